@@ -0,0 +1,303 @@
+use crate::error::{Error, Problem, Result};
+use crate::ConfigExt;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use rocket::http::{HeaderMap, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{
+    Object, OpenApi, SecurityRequirement, SecurityScheme, SecuritySchemeData,
+};
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+const SECURITY_SCHEME: &str = "api_key";
+const BEARER_SECURITY_SCHEME: &str = "bearer";
+
+/// Claims of bearer tokens on the admin api.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Option<String>,
+    pub exp: usize,
+}
+
+/// Request guard of the admin api.
+///
+/// Accepts the `admin_api_key` in the `X-API-Key` header or a bearer jwt
+/// signed with `admin_jwt_secret`. Without both configured the admin api
+/// is open.
+pub struct AdminAuth;
+
+/// Hex encoded SHA-256 digest of a secret, stored instead of the secret.
+pub fn key_digest(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Compares the digest of the key to the stored digest without an early
+/// return, so the time taken does not tell how much of the key matched.
+pub fn digest_matches(key: &str, digest: &str) -> bool {
+    let key = key_digest(key);
+    key.len() == digest.len()
+        && key
+            .bytes()
+            .zip(digest.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn api_key_matches(header: &str, api_key: &str) -> bool {
+    digest_matches(header, &key_digest(api_key))
+}
+
+pub fn authorize(config: &ConfigExt, headers: &HeaderMap<'_>) -> Result<()> {
+    if config.admin_api_key.is_none() && config.admin_jwt_secret.is_none() {
+        return Ok(());
+    }
+    if let (Some(api_key), Some(header)) = (&config.admin_api_key, headers.get_one(API_KEY_HEADER))
+    {
+        return match api_key_matches(header, api_key) {
+            true => Ok(()),
+            false => Err(Error::Unauthorized("invalid api key".to_string())),
+        };
+    }
+    let bearer = headers
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "));
+    if let (Some(secret), Some(token)) = (&config.admin_jwt_secret, bearer) {
+        decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )
+        .map_err(|err| Error::Unauthorized(format!("invalid bearer token: {}", err)))?;
+        return Ok(());
+    }
+    Err(Error::Unauthorized("missing admin credentials".to_string()))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAuth {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match request.rocket().state::<ConfigExt>() {
+            Some(config) => config,
+            None => {
+                let err = Error::Internal("config ext not managed".to_string());
+                return Outcome::Failure((err.status(), err));
+            }
+        };
+        match authorize(config, request.headers()) {
+            Ok(()) => Outcome::Success(AdminAuth),
+            Err(err) => {
+                warn!("{}", err);
+                Outcome::Failure((err.status(), err))
+            }
+        }
+    }
+}
+
+/// Adds the bearer jwt as alternative to the api key on the operations
+/// requiring it, a request guard can only declare one requirement.
+pub fn add_bearer_requirement(spec: &mut OpenApi) {
+    for path in spec.paths.values_mut() {
+        let operations = [
+            &mut path.get,
+            &mut path.put,
+            &mut path.post,
+            &mut path.delete,
+            &mut path.options,
+            &mut path.head,
+            &mut path.patch,
+            &mut path.trace,
+        ];
+        for operation in operations.into_iter().flatten() {
+            if let Some(security) = &mut operation.security {
+                if security
+                    .iter()
+                    .any(|requirement| requirement.contains_key(SECURITY_SCHEME))
+                {
+                    let mut requirement = SecurityRequirement::new();
+                    requirement.insert(BEARER_SECURITY_SCHEME.to_string(), Vec::new());
+                    security.push(requirement);
+                }
+            }
+        }
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for AdminAuth {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        let scheme = SecurityScheme {
+            description: Some(
+                "Admin api key, alternatively a bearer jwt in the `Authorization` header. \
+                 An `X-Tenant-Token` header selects the wallet of a tenant."
+                    .to_string(),
+            ),
+            data: SecuritySchemeData::ApiKey {
+                name: API_KEY_HEADER.to_string(),
+                location: "header".to_string(),
+            },
+            extensions: Object::default(),
+        };
+        gen.add_security_scheme(
+            BEARER_SECURITY_SCHEME.to_string(),
+            SecurityScheme {
+                description: Some("Jwt signed with the `admin_jwt_secret`.".to_string()),
+                data: SecuritySchemeData::Http {
+                    scheme: "bearer".to_string(),
+                    bearer_format: Some("JWT".to_string()),
+                },
+                extensions: Object::default(),
+            },
+        );
+        let mut requirement = SecurityRequirement::new();
+        requirement.insert(SECURITY_SCHEME.to_string(), Vec::new());
+        Ok(RequestHeaderInput::Security(
+            SECURITY_SCHEME.to_string(),
+            scheme,
+            requirement,
+        ))
+    }
+}
+
+/// Answers rejected admin requests with a problem body.
+#[catch(401)]
+pub fn unauthorized() -> Json<Problem> {
+    Json(Error::Unauthorized("missing or invalid admin credentials".to_string()).problem())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::didcomm;
+    use crate::webhook::WebhookPool;
+    use crate::Config;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use rocket::http::Header as HttpHeader;
+    use rocket::local::asynchronous::Client;
+
+    async fn client(api_key: &str, jwt_secret: &str) -> Client {
+        let figment = rocket::Config::figment()
+            .merge(("admin_api_key", api_key))
+            .merge(("admin_jwt_secret", jwt_secret));
+        let rocket = rocket::custom(figment);
+        let config: Config = rocket.figment().extract().expect("config");
+        let didcomm =
+            Box::new(didcomm::test_client::TestClient::new()) as Box<dyn didcomm::DidComm>;
+        let rocket = crate::rocket(rocket, config, WebhookPool::default(), didcomm).await;
+        Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance")
+    }
+
+    #[tokio::test]
+    async fn test_api_key() {
+        let client = client("secret", "jwt-secret").await;
+
+        let response = client.get("/connections").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let problem: Problem = response.into_json().await.unwrap();
+        assert_eq!(problem.status, 401);
+
+        let response = client
+            .get("/connections")
+            .header(HttpHeader::new(API_KEY_HEADER, "wrong"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .get("/connections")
+            .header(HttpHeader::new(API_KEY_HEADER, "secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/server/live").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token() {
+        let client = client("secret", "jwt-secret").await;
+
+        let claims = Claims {
+            sub: Some("admin".to_string()),
+            exp: 10000000000,
+        };
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret("jwt-secret".as_bytes()),
+        )
+        .unwrap();
+        let response = client
+            .get("/connections")
+            .header(HttpHeader::new(
+                "Authorization",
+                format!("Bearer {}", token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret("other".as_bytes()),
+        )
+        .unwrap();
+        let response = client
+            .get("/connections")
+            .header(HttpHeader::new(
+                "Authorization",
+                format!("Bearer {}", token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn test_security_schemes() {
+        let client = client("secret", "jwt-secret").await;
+
+        let response = client.get("/openapi.json").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let spec: serde_json::Value = response.into_json().await.unwrap();
+        let schemes = &spec["components"]["securitySchemes"];
+        assert_eq!(schemes[SECURITY_SCHEME]["in"], "header");
+        assert_eq!(schemes[BEARER_SECURITY_SCHEME]["scheme"], "bearer");
+        let security = &spec["paths"]["/connections"]["get"]["security"];
+        assert_eq!(
+            security,
+            &serde_json::json!([{ SECURITY_SCHEME: [] }, { BEARER_SECURITY_SCHEME: [] }])
+        );
+        let security = &spec["paths"]["/topic/message"]["post"]["security"];
+        assert_eq!(security.as_array().unwrap().len(), 2);
+        assert!(spec["paths"]["/"]["post"]["security"].is_null());
+    }
+
+    #[test]
+    fn test_api_key_matches() {
+        assert!(api_key_matches("secret", "secret"));
+        assert!(!api_key_matches("secret", "secreT"));
+        assert!(!api_key_matches("secret", "secret2"));
+        assert!(!api_key_matches("", "secret"));
+        assert!(digest_matches("secret", &key_digest("secret")));
+        assert!(!digest_matches("secret", "secret"));
+    }
+
+    #[test]
+    fn test_open_without_config() {
+        let headers = HeaderMap::new();
+        assert!(authorize(&ConfigExt::default(), &headers).is_ok());
+    }
+}
@@ -0,0 +1,79 @@
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+/// Steps of the issuance and presentation flows the agent answers on its own.
+///
+/// Unset policies fall back to the connection's, then to the global ones in
+/// `Rocket.toml`. Received credentials are stored unless `auto_store` is
+/// disabled, all other steps wait for the controller by default.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AutoRespond {
+    /// Offer the proposed credential.
+    pub auto_offer: Option<bool>,
+    /// Request an offered credential.
+    pub auto_request: Option<bool>,
+    /// Issue a requested credential.
+    pub auto_issue: Option<bool>,
+    /// Store and acknowledge a received credential.
+    pub auto_store: Option<bool>,
+    /// Present credentials on a presentation request.
+    pub auto_present: Option<bool>,
+}
+
+impl AutoRespond {
+    /// The policies of `other` where set, these otherwise.
+    pub fn merge(&self, other: &AutoRespond) -> AutoRespond {
+        AutoRespond {
+            auto_offer: other.auto_offer.or(self.auto_offer),
+            auto_request: other.auto_request.or(self.auto_request),
+            auto_issue: other.auto_issue.or(self.auto_issue),
+            auto_store: other.auto_store.or(self.auto_store),
+            auto_present: other.auto_present.or(self.auto_present),
+        }
+    }
+
+    pub fn offer(&self) -> bool {
+        self.auto_offer.unwrap_or(false)
+    }
+
+    pub fn request(&self) -> bool {
+        self.auto_request.unwrap_or(false)
+    }
+
+    pub fn issue(&self) -> bool {
+        self.auto_issue.unwrap_or(false)
+    }
+
+    pub fn store(&self) -> bool {
+        self.auto_store.unwrap_or(true)
+    }
+
+    pub fn present(&self) -> bool {
+        self.auto_present.unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let global = AutoRespond {
+            auto_offer: Some(true),
+            auto_store: Some(false),
+            ..Default::default()
+        };
+        let connection = AutoRespond {
+            auto_offer: Some(false),
+            auto_issue: Some(true),
+            ..Default::default()
+        };
+        let policy = global.merge(&connection);
+        assert!(!policy.offer());
+        assert!(policy.issue());
+        assert!(!policy.store());
+        assert!(!policy.request());
+        assert!(AutoRespond::default().store());
+    }
+}
@@ -39,7 +39,7 @@ async fn rocket() -> _ {
     };
 
     let webhook_pool = WebhookPool::default();
-    webhook_pool.webhooks.try_lock().unwrap().insert(
+    webhook_pool.webhooks.lock().await.insert(
         webhook_endpoint.id.as_ref().unwrap().to_string(),
         (webhook_endpoint, Arc::new(Mutex::new(webhook_client))),
     );
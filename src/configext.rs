@@ -1,5 +1,18 @@
+use crate::autorespond::AutoRespond;
+use crate::storage::StorageConfig;
 use serde::Deserialize;
 #[derive(Default, PartialEq, Deserialize)]
 pub struct ConfigExt {
     pub webhook_url: Option<String>,
+    /// Api key expected in the `X-API-Key` header of admin requests.
+    pub admin_api_key: Option<String>,
+    /// Secret of HS256 bearer tokens accepted on admin requests.
+    pub admin_jwt_secret: Option<String>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Template of issued credential ids, `{uuid}` is replaced by a random uuid.
+    pub credential_id_template: Option<String>,
+    /// Global auto-respond policies, see `AutoRespond`.
+    #[serde(default)]
+    pub auto_respond: AutoRespond,
 }
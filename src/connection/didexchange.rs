@@ -0,0 +1,306 @@
+use super::invitation::Invitations;
+use super::{
+    Connection, ConnectionEvent, ConnectionEvents, ConnectionRole, ConnectionState, Connections,
+};
+use crate::didcomm::{
+    is_returned_message, parent_thread_id, receive, thread_id, with_thread, DidComm,
+};
+use crate::error::Error;
+use crate::wallet::{did_endpoint, wallet_did, Wallet};
+use did_key::KeyMaterial;
+use didcomm_mediator::message::sign_and_encrypt;
+use didcomm_protocols::DidExchangeResponseBuilder;
+use didcomm_rs::Message;
+use identity_iota::client::ExplorerUrl;
+use identity_iota::core::Timestamp;
+use identity_iota::iota_core::IotaDID;
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const REQUEST: &str = "https://didcomm.org/didexchange/1.0/request";
+pub const RESPONSE: &str = "https://didcomm.org/didexchange/1.0/response";
+pub const COMPLETE: &str = "https://didcomm.org/didexchange/1.0/complete";
+pub const PROBLEM_REPORT: &str = "https://didcomm.org/didexchange/1.0/problem_report";
+
+pub fn did_doc(did: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    let did: IotaDID = IotaDID::from_str(did)?;
+    let explorer: &ExplorerUrl = ExplorerUrl::mainnet();
+    let did_doc = explorer.resolver_url(&did)?;
+    Ok(serde_json::to_value(&did_doc)?)
+}
+
+/// Builds a request answering the invitation, the invitation is the parent thread.
+pub fn build_request(
+    did: &str,
+    invitation_id: &str,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    let request = DidExchangeResponseBuilder::new()
+        .did_doc(did_doc(did)?)
+        .did(did.to_string())
+        .build_request()?;
+    let thid = thread_id(&request);
+    Ok(with_thread(request, &thid, Some(invitation_id))?)
+}
+
+pub fn build_response(did: &str, request: &Message) -> Result<Message, Box<dyn std::error::Error>> {
+    let response = DidExchangeResponseBuilder::new()
+        .message(request.clone())
+        .did_doc(did_doc(did)?)
+        .did(did.to_string())
+        .build_response()?;
+    Ok(with_thread(response, &thread_id(request), None)?)
+}
+
+pub fn build_complete(
+    response: &Message,
+    invitation_id: &str,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    let complete = DidExchangeResponseBuilder::new()
+        .message(response.clone())
+        .build_complete()?;
+    Ok(with_thread(
+        complete,
+        &thread_id(response),
+        Some(invitation_id),
+    )?)
+}
+
+/// Stores the connection in its new state and notifies the observers.
+pub async fn set_state(
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    connection: &mut Connection,
+    state: ConnectionState,
+) -> Result<(), Error> {
+    connection.state = state;
+    connection.updated_at = Timestamp::now_utc().to_string();
+    connections
+        .connections
+        .insert(&connection.id, connection.clone())?;
+    connection_events
+        .lock()
+        .await
+        .send(ConnectionEvent::transition(
+            &connection.id,
+            &connection.state,
+        ))
+        .await;
+    Ok(())
+}
+
+/// The connection exchanging dids on the thread, unknown threads are an
+/// `Error::NotFound`.
+pub fn find_by_thread(connections: &Connections, thid: &str) -> Result<Connection, Error> {
+    connections
+        .connections
+        .values()?
+        .into_iter()
+        .find(|connection| connection.thread_id.as_deref() == Some(thid))
+        .ok_or_else(|| Error::NotFound(format!("connection of thread {}", thid)))
+}
+
+/// Invitee side: sends the request to the inviter and completes the
+/// exchange if the response is returned on the return route. Failing to
+/// reach the inviter is an `Error::PeerUnreachable`.
+pub async fn send_request(
+    wallet: &Arc<Mutex<Wallet>>,
+    didcomm: &dyn DidComm,
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    connection: &mut Connection,
+) -> Result<(), Error> {
+    let (my_did, keypair) = {
+        let wallet = wallet.lock().await;
+        (wallet_did(&wallet)?.to_string(), wallet.keypair())
+    };
+    let invitation_id = connection.invitation_msg_id.clone().unwrap_or_default();
+    let their_did = connection.did.to_string();
+    let unreachable = |err| Error::PeerUnreachable(format!("{}: {:?}", their_did, err));
+
+    let request = build_request(&my_did, &invitation_id)?;
+    connection.thread_id = Some(thread_id(&request));
+    let message = sign_and_encrypt(&request, &my_did, &connection.did, &keypair)
+        .await
+        .map_err(unreachable)?;
+    set_state(
+        connections,
+        connection_events,
+        connection,
+        ConnectionState::RequestSent,
+    )
+    .await?;
+
+    let response = didcomm.post(&connection.endpoint, &message).await?;
+    let body = response.text().await?;
+    if !is_returned_message(&body) {
+        return Ok(());
+    }
+    let response = receive(&body, &keypair.private_key_bytes(), None).await?;
+    match response.get_didcomm_header().m_type.as_str() {
+        RESPONSE => {
+            set_state(
+                connections,
+                connection_events,
+                connection,
+                ConnectionState::ResponseReceived,
+            )
+            .await?;
+            let complete = build_complete(&response, &invitation_id)?;
+            let message = sign_and_encrypt(&complete, &my_did, &connection.did, &keypair)
+                .await
+                .map_err(unreachable)?;
+            didcomm.post(&connection.endpoint, &message).await?;
+            set_state(
+                connections,
+                connection_events,
+                connection,
+                ConnectionState::Completed,
+            )
+            .await?;
+        }
+        PROBLEM_REPORT => {
+            set_state(
+                connections,
+                connection_events,
+                connection,
+                ConnectionState::Abandoned,
+            )
+            .await?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Inviter side: accepts a request to an invitation we created and answers
+/// with the signed and encrypted response.
+///
+/// Multi-use invitations keep their connection in invitation state and
+/// start a new connection for every request.
+pub async fn receive_request(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    invitations: &Invitations,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    request: &Message,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let invitation_id = parent_thread_id(request).ok_or("request without invitation")?;
+    let invitation = invitations.accept(&invitation_id)?;
+
+    let template = connections
+        .connections
+        .get(&invitation.connection_id)?
+        .ok_or("unknown invitation connection")?;
+    let mut connection = match invitation.multi_use {
+        true => {
+            let connection = Connection {
+                alias: template.alias,
+                invitation_msg_id: template.invitation_msg_id,
+                auto_respond: template.auto_respond,
+                ..Connection::new(ConnectionRole::Inviter, ConnectionState::InvitationSent)
+            };
+            connection_events
+                .lock()
+                .await
+                .send(ConnectionEvent::Created(connection.id.to_string()))
+                .await;
+            connection
+        }
+        false => template,
+    };
+
+    let their_did = request
+        .get_didcomm_header()
+        .from
+        .clone()
+        .ok_or("request without sender")?;
+    connection.did = their_did.to_string();
+    connection.endpoint = did_endpoint(&their_did).await?;
+    connection.thread_id = Some(thread_id(request));
+    set_state(
+        connections,
+        connection_events,
+        &mut connection,
+        ConnectionState::RequestReceived,
+    )
+    .await?;
+
+    let (my_did, keypair) = {
+        let wallet = wallet.lock().await;
+        (
+            wallet.did_iota().ok_or("wallet has no did")?,
+            wallet.keypair(),
+        )
+    };
+    let response = build_response(&my_did, request)?;
+    let message = sign_and_encrypt(&response, &my_did, &their_did, &keypair).await?;
+    set_state(
+        connections,
+        connection_events,
+        &mut connection,
+        ConnectionState::ResponseSent,
+    )
+    .await?;
+    Ok(message)
+}
+
+/// Inviter side: the invitee confirmed the response.
+pub async fn receive_complete(
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    complete: &Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connection = find_by_thread(connections, &thread_id(complete))?;
+    if connection.state != ConnectionState::ResponseSent {
+        return Err(format!(
+            "connection {} is {:?}, expected {:?}",
+            connection.id,
+            connection.state,
+            ConnectionState::ResponseSent
+        )
+        .into());
+    }
+    set_state(
+        connections,
+        connection_events,
+        &mut connection,
+        ConnectionState::Completed,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn receive_problem_report(
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    problem_report: &Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connection = find_by_thread(connections, &thread_id(problem_report))?;
+    set_state(
+        connections,
+        connection_events,
+        &mut connection,
+        ConnectionState::Abandoned,
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let did = "did:iota:9eKMNdLSQhpgGrJNjcbX4qWZiXRkzNFy8uCoMF2Va8ig";
+        let request = build_request(did, "invitation").unwrap();
+        assert_eq!(request.get_didcomm_header().m_type, REQUEST);
+        assert_eq!(parent_thread_id(&request), Some("invitation".to_string()));
+        assert_eq!(
+            thread_id(&request),
+            request.get_didcomm_header().id.to_string()
+        );
+    }
+}
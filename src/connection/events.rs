@@ -1,9 +1,37 @@
+use super::ConnectionState;
 use serde::{Deserialize, Serialize};
 use {futures::SinkExt, pharos::*};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ConnectionEvent {
     Created(String),
+    InvitationSent(String),
+    InvitationReceived(String),
+    RequestSent(String),
+    RequestReceived(String),
+    ResponseSent(String),
+    ResponseReceived(String),
+    Completed(String),
+    Abandoned(String),
+    Reused(String),
+    Terminated(String),
+}
+
+impl ConnectionEvent {
+    /// The event for a connection entering the given state.
+    pub fn transition(id: &str, state: &ConnectionState) -> Self {
+        let id = id.to_string();
+        match state {
+            ConnectionState::InvitationSent => ConnectionEvent::InvitationSent(id),
+            ConnectionState::InvitationReceived => ConnectionEvent::InvitationReceived(id),
+            ConnectionState::RequestSent => ConnectionEvent::RequestSent(id),
+            ConnectionState::RequestReceived => ConnectionEvent::RequestReceived(id),
+            ConnectionState::ResponseSent => ConnectionEvent::ResponseSent(id),
+            ConnectionState::ResponseReceived => ConnectionEvent::ResponseReceived(id),
+            ConnectionState::Completed => ConnectionEvent::Completed(id),
+            ConnectionState::Abandoned => ConnectionEvent::Abandoned(id),
+        }
+    }
 }
 
 pub struct ConnectionEvents {
@@ -58,4 +86,12 @@ mod tests {
         assert_eq!(ConnectionEvent::Created(String::default()), evt);
         assert_eq!(None, events.next().await);
     }
+
+    #[test]
+    fn test_transition() {
+        assert_eq!(
+            ConnectionEvent::transition("foo", &ConnectionState::Completed),
+            ConnectionEvent::Completed("foo".to_string())
+        );
+    }
 }
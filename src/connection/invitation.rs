@@ -1,7 +1,11 @@
+use crate::storage::{MemoryStorage, Record, Storage, Store};
+use identity_iota::core::Timestamp;
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use url::Url;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -23,6 +27,112 @@ pub struct Invitation {
     pub attachments: Vec<Attachment>,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema, FromFormField)]
+#[serde(rename_all = "kebab-case")]
+pub enum InvitationState {
+    #[field(value = "active")]
+    Active,
+    #[field(value = "used")]
+    Used,
+    #[field(value = "expired")]
+    Expired,
+    #[field(value = "revoked")]
+    Revoked,
+}
+
+/// An out-of-band invitation created by this agent.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct InvitationRecord {
+    pub id: String,
+    pub connection_id: String,
+    pub invitation: Value,
+    pub invitation_url: String,
+    pub alias: Option<String>,
+    pub multi_use: bool,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub expires_at: Option<String>,
+    pub state: InvitationState,
+    pub created_at: String,
+}
+
+impl Record for InvitationRecord {}
+
+impl InvitationRecord {
+    pub fn is_expired(&self) -> bool {
+        match &self.expires_at {
+            Some(expires_at) => match Timestamp::parse(expires_at) {
+                Ok(expires_at) => expires_at.to_unix() <= Timestamp::now_utc().to_unix(),
+                Err(_) => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Marks an active invitation as expired once its expiry time passed.
+    pub fn refresh_state(&mut self) {
+        if self.state == InvitationState::Active && self.is_expired() {
+            self.state = InvitationState::Expired;
+        }
+    }
+
+    /// Counts a use of the invitation, fails if it cannot be used anymore.
+    pub fn accept(&mut self) -> Result<(), String> {
+        self.refresh_state();
+        if self.state == InvitationState::Active
+            && self
+                .max_uses
+                .map_or(false, |max_uses| self.uses >= max_uses)
+        {
+            self.state = InvitationState::Used;
+        }
+        if self.state != InvitationState::Active {
+            return Err(format!("invitation {} is {:?}", self.id, self.state));
+        }
+        self.uses += 1;
+        let exhausted = match self.max_uses {
+            Some(max_uses) => self.uses >= max_uses,
+            None => !self.multi_use,
+        };
+        if exhausted {
+            self.state = InvitationState::Used;
+        }
+        Ok(())
+    }
+}
+
+pub struct Invitations {
+    pub invitations: Store<InvitationRecord>,
+    accepting: Mutex<()>,
+}
+
+impl Invitations {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Invitations {
+            invitations: Store::new(storage, "invitations"),
+            accepting: Mutex::new(()),
+        }
+    }
+
+    /// Counts a use of the stored invitation. Concurrent requests on the same
+    /// invitation are counted one after the other, so `max_uses` holds.
+    pub fn accept(&self, id: &str) -> Result<InvitationRecord, Box<dyn std::error::Error>> {
+        let _accepting = self.accepting.lock().unwrap_or_else(|err| err.into_inner());
+        let mut invitation = self.invitations.get(id)?.ok_or("unknown invitation")?;
+        let accepted = invitation.accept();
+        self.invitations
+            .insert(&invitation.id, invitation.clone())?;
+        accepted?;
+        Ok(invitation)
+    }
+}
+
+impl Default for Invitations {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
+}
+
 pub fn build_issue_vc_invitation(endpoint: String) -> Invitation {
     let body: Value = json!({
         "goal_code": "issue-vc",
@@ -59,6 +169,25 @@ pub fn build_issue_vc_invitation(endpoint: String) -> Invitation {
     invitation
 }
 
+/// Encodes the invitation as `_oob` query parameter of the base url.
+pub fn invitation_url(base_url: &str, invitation: &Value) -> Result<String, url::ParseError> {
+    let encoded = base64::encode_config(invitation.to_string(), base64::URL_SAFE_NO_PAD);
+    let mut url = Url::parse(base_url)?;
+    url.query_pairs_mut().append_pair("_oob", &encoded);
+    Ok(url.to_string())
+}
+
+/// Decodes the invitation of an url created by `invitation_url`.
+pub fn parse_invitation_url(invitation_url: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    let url = Url::parse(invitation_url)?;
+    let (_, encoded) = url
+        .query_pairs()
+        .find(|(key, _)| key == "_oob")
+        .ok_or("missing _oob parameter")?;
+    let decoded = base64::decode_config(encoded.as_bytes(), base64::URL_SAFE_NO_PAD)?;
+    Ok(serde_json::from_slice(&decoded)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,4 +204,58 @@ mod tests {
             endpoint
         )
     }
+
+    fn record(multi_use: bool, max_uses: Option<u32>) -> InvitationRecord {
+        InvitationRecord {
+            id: "foo".to_string(),
+            connection_id: "bar".to_string(),
+            invitation: Value::Null,
+            invitation_url: "http://localhost:8000/?_oob=e30".to_string(),
+            alias: None,
+            multi_use,
+            max_uses,
+            uses: 0,
+            expires_at: None,
+            state: InvitationState::Active,
+            created_at: Timestamp::now_utc().to_string(),
+        }
+    }
+
+    #[test]
+    fn test_accept_invitation() {
+        let mut single_use = record(false, None);
+        assert!(single_use.accept().is_ok());
+        assert_eq!(single_use.state, InvitationState::Used);
+        assert!(single_use.accept().is_err());
+
+        let mut multi_use = record(true, Some(2));
+        assert!(multi_use.accept().is_ok());
+        assert_eq!(multi_use.state, InvitationState::Active);
+        assert!(multi_use.accept().is_ok());
+        assert_eq!(multi_use.state, InvitationState::Used);
+
+        let mut unlimited = record(true, None);
+        for _ in 0..10 {
+            assert!(unlimited.accept().is_ok());
+        }
+
+        let mut exhausted = record(true, Some(0));
+        assert!(exhausted.accept().is_err());
+        assert_eq!(exhausted.state, InvitationState::Used);
+
+        let mut expired = record(true, None);
+        expired.expires_at = Some(Timestamp::from_unix(0).unwrap().to_string());
+        assert!(expired.accept().is_err());
+        assert_eq!(expired.state, InvitationState::Expired);
+    }
+
+    #[test]
+    fn test_invitation_url() {
+        let invitation =
+            json!({"id": "foo", "type": "https://didcomm.org/out-of-band/2.0/invitation"});
+        let url = invitation_url("http://localhost:8000", &invitation).unwrap();
+        assert!(url.starts_with("http://localhost:8000/?_oob="));
+        assert_eq!(parse_invitation_url(&url).unwrap(), invitation);
+        assert!(parse_invitation_url("http://localhost:8000/").is_err());
+    }
 }
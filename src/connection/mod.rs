@@ -1,5 +1,10 @@
+use crate::auth::AdminAuth;
+use crate::autorespond::AutoRespond;
 use crate::didcomm::DidComm;
-use crate::wallet::get_did_endpoint;
+use crate::error::{Error, Result};
+use crate::storage::{self, MemoryStorage, Record, Storage, Store};
+use crate::tenant::TenantState;
+use crate::wallet::{did_endpoint, parse_did, wallet_did};
 use crate::Config;
 use crate::Wallet;
 use base58::ToBase58;
@@ -8,8 +13,10 @@ use didcomm_protocols::DidExchangeResponseBuilder;
 use didcomm_protocols::InvitationBuilder;
 use didcomm_protocols::Service;
 use didcomm_rs::Message;
-use identity_iota::client::ExplorerUrl;
+use identity_iota::core::Timestamp;
 use identity_iota::iota_core::IotaDID;
+use invitation::{InvitationRecord, InvitationState, Invitations};
+use qr::{QrFormat, QrImage};
 use rocket::http::Status;
 use rocket::State;
 use rocket::{delete, get, post, serde::json::Json};
@@ -19,211 +26,642 @@ use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use uuid::Uuid;
 
+pub mod didexchange;
 pub mod events;
 pub mod invitation;
+pub mod qr;
+pub mod reuse;
+pub mod termination;
 
 pub use events::ConnectionEvent;
 pub use events::ConnectionEvents;
 
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, JsonSchema, FromFormField)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionState {
+    #[default]
+    #[field(value = "invitation-sent")]
+    InvitationSent,
+    #[field(value = "invitation-received")]
+    InvitationReceived,
+    #[field(value = "request-sent")]
+    RequestSent,
+    #[field(value = "request-received")]
+    RequestReceived,
+    #[field(value = "response-sent")]
+    ResponseSent,
+    #[field(value = "response-received")]
+    ResponseReceived,
+    #[field(value = "completed")]
+    Completed,
+    #[field(value = "abandoned")]
+    Abandoned,
+}
+
+/// Our role in the connection.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionRole {
+    #[default]
+    Inviter,
+    Invitee,
+}
+
 #[derive(Default, Debug, PartialEq, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Connection {
     pub id: String,
     pub did: String,
     pub endpoint: String,
+    pub state: ConnectionState,
+    pub role: ConnectionRole,
+    pub alias: Option<String>,
+    pub their_label: Option<String>,
+    pub invitation_msg_id: Option<String>,
+    pub thread_id: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    pub metadata: HashMap<String, Value>,
+    #[serde(default)]
+    pub auto_respond: Option<AutoRespond>,
+}
+
+impl Connection {
+    pub fn new(role: ConnectionRole, state: ConnectionState) -> Self {
+        let now = Timestamp::now_utc().to_string();
+        Connection {
+            id: Uuid::new_v4().to_string(),
+            role,
+            state,
+            created_at: now.to_string(),
+            updated_at: now,
+            ..Default::default()
+        }
+    }
+}
+
+impl Record for Connection {
+    const VERSION: u32 = 3;
+
+    fn migrate(version: u32, mut value: Value) -> std::result::Result<Value, storage::Error> {
+        match version {
+            1 => {
+                // connections were recorded without handshake
+                value["state"] = json!(ConnectionState::Completed);
+                value["invitation_msg_id"] = value["id"].clone();
+                value["thread_id"] = Value::Null;
+                Ok(value)
+            }
+            2 => {
+                let role = match serde_json::from_value::<ConnectionState>(value["state"].clone())?
+                {
+                    ConnectionState::InvitationSent
+                    | ConnectionState::RequestReceived
+                    | ConnectionState::ResponseSent => ConnectionRole::Inviter,
+                    _ => ConnectionRole::Invitee,
+                };
+                let now = Timestamp::now_utc().to_string();
+                value["role"] = json!(role);
+                value["alias"] = Value::Null;
+                value["their_label"] = Value::Null;
+                value["created_at"] = json!(now);
+                value["updated_at"] = json!(now);
+                value["metadata"] = json!({});
+                Ok(value)
+            }
+            _ => Ok(value),
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct Connections {
-    pub connections: Arc<Mutex<HashMap<String, Connection>>>,
+    pub connections: Store<Connection>,
+}
+
+impl Connections {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Connections {
+            connections: Store::new(storage, "connections"),
+        }
+    }
+
+    /// Fetches a connection, unknown ids are an `Error::ConnectionNotFound`.
+    pub fn get(&self, conn_id: &str) -> Result<Connection> {
+        self.connections
+            .get(conn_id)?
+            .ok_or_else(|| Error::ConnectionNotFound(conn_id.to_string()))
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
-pub struct ConnectionEndpoints {
-    pub my_endpoint: String,
-    pub their_endpoint: String,
+pub struct InvitationResponse {
+    pub connection_id: String,
+    pub invitation_msg_id: String,
+    pub invitation: Value,
+    pub invitation_url: String,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
-pub struct Termination {
-    pub typ: String,
-    #[serde(rename = "type")]
-    pub type_: String,
-    pub id: String,
-    pub body: Value,
+pub struct ConnectionMetadata {
+    pub metadata: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
-pub struct TerminationResponse {
-    pub typ: String,
-    #[serde(rename = "type")]
-    pub type_: String,
-    pub id: String,
-    pub body: Value,
+pub struct ConnectionEndpoints {
+    pub my_endpoint: String,
+    pub their_endpoint: String,
 }
 
 /// # Create a new connection invitation.
+///
+/// Invitations are single use unless `multi_use` is set, `max_uses` limits
+/// and `expires_in` seconds end the validity of an invitation.
 #[openapi(tag = "out-of-band")]
-#[post("/out-of-band/create-invitation")]
-pub async fn post_create_invitation(wallet: &State<Arc<Mutex<Wallet>>>) -> Json<Value> {
-    let wallet = wallet.try_lock().unwrap();
-    let did: IotaDID = IotaDID::from_str(&wallet.did_iota().unwrap()).unwrap();
-    let endpoint = get_did_endpoint(did.to_string()).await.as_str().to_string();
+#[post("/out-of-band/create-invitation?<alias>&<multi_use>&<max_uses>&<expires_in>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn post_create_invitation(
+    _auth: AdminAuth,
+    config: &State<Config>,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    invitations: &TenantState<Invitations>,
+    connection_events: &TenantState<Arc<Mutex<ConnectionEvents>>>,
+    alias: Option<String>,
+    multi_use: Option<bool>,
+    max_uses: Option<u32>,
+    expires_in: Option<i64>,
+) -> Result<Json<InvitationResponse>> {
+    if max_uses == Some(0) {
+        return Err(Error::BadRequest("max_uses must be at least 1".to_string()));
+    }
+    let now = Timestamp::now_utc();
+    let expires_at = match expires_in {
+        Some(expires_in) if expires_in <= 0 => {
+            return Err(Error::BadRequest("expires_in must be positive".to_string()))
+        }
+        Some(expires_in) => Some(
+            now.to_unix()
+                .checked_add(expires_in)
+                .and_then(|expires_at| Timestamp::from_unix(expires_at).ok())
+                .ok_or_else(|| Error::BadRequest(format!("expires_in: {} too large", expires_in)))?
+                .to_string(),
+        ),
+        None => None,
+    };
 
-    let explorer: &ExplorerUrl = ExplorerUrl::mainnet();
-    let did_doc = explorer.resolver_url(&did).unwrap();
+    let did: IotaDID = {
+        let wallet = wallet.lock().await;
+        wallet_did(&wallet)?
+    };
+    let endpoint = did_endpoint(did.as_str()).await?;
 
     let did_exchange = DidExchangeResponseBuilder::new()
-        .did_doc(serde_json::to_value(&did_doc).unwrap())
+        .did_doc(didexchange::did_doc(did.as_str())?)
         .did(did.to_string())
         .build_request()
-        .unwrap();
+        .map_err(Error::internal)?;
 
     let recipient_key = resolver::resolve(&did.to_string())
         .await
-        .unwrap()
+        .map_err(|err| Error::PeerUnreachable(format!("resolve {}: {:?}", did, err)))?
         .to_base58();
     let services: Vec<Service> =
-        vec![Service::new(did.to_string(), endpoint, vec![recipient_key]).unwrap()];
+        vec![Service::new(did.to_string(), endpoint, vec![recipient_key])
+            .map_err(Error::internal)?];
     let invitation = InvitationBuilder::new()
         .goal("to create a relationship".to_string())
         .goal_code("aries.rel.build".to_string())
         .services(services)
         .attachments(vec![did_exchange])
         .build()
-        .unwrap();
+        .map_err(Error::internal)?;
+    let invitation_msg_id = invitation.get_didcomm_header().id.to_string();
 
-    let response = serde_json::from_str(&invitation.as_raw_json().unwrap()).unwrap();
-    Json(response)
+    let connection = Connection {
+        alias: alias.clone(),
+        invitation_msg_id: Some(invitation_msg_id.to_string()),
+        ..Connection::new(ConnectionRole::Inviter, ConnectionState::InvitationSent)
+    };
+    let connection_id = connection.id.to_string();
+    connections.connections.insert(&connection_id, connection)?;
+    connection_events
+        .lock()
+        .await
+        .send(ConnectionEvent::Created(connection_id.to_string()))
+        .await;
+
+    let invitation: Value = serde_json::to_value(&invitation).map_err(Error::internal)?;
+    let invitation_url =
+        invitation::invitation_url(&config.ext_hostname, &invitation).map_err(Error::internal)?;
+
+    let record = InvitationRecord {
+        id: invitation_msg_id.to_string(),
+        connection_id: connection_id.to_string(),
+        invitation: invitation.clone(),
+        invitation_url: invitation_url.to_string(),
+        alias,
+        multi_use: multi_use.unwrap_or(false) || max_uses.map_or(false, |max| max > 1),
+        max_uses,
+        uses: 0,
+        expires_at,
+        state: InvitationState::Active,
+        created_at: now.to_string(),
+    };
+    invitations.invitations.insert(&record.id, record)?;
+
+    Ok(Json(InvitationResponse {
+        connection_id,
+        invitation_msg_id,
+        invitation,
+        invitation_url,
+    }))
+}
+
+/// # Render an invitation url as qr code
+#[openapi(tag = "out-of-band")]
+#[get("/out-of-band/qr-code?<invitation_url>&<format>")]
+pub async fn get_invitation_qr_code(
+    _auth: AdminAuth,
+    invitation_url: String,
+    format: Option<QrFormat>,
+) -> Result<QrImage> {
+    qr::render(&invitation_url, &format.unwrap_or_default())
+        .map_err(|err| Error::BadRequest(err.to_string()))
+}
+
+/// # Query created out-of-band invitations
+#[openapi(tag = "out-of-band")]
+#[get("/out-of-band/invitations?<state>")]
+pub async fn get_all_invitations(
+    _auth: AdminAuth,
+    invitations: &TenantState<Invitations>,
+    state: Option<InvitationState>,
+) -> Result<Json<Vec<InvitationRecord>>> {
+    let mut records: Vec<InvitationRecord> = invitations
+        .invitations
+        .values()?
+        .into_iter()
+        .map(|mut record| {
+            record.refresh_state();
+            record
+        })
+        .filter(|record| state.is_none() || state.as_ref() == Some(&record.state))
+        .collect();
+    records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(Json(records))
+}
+
+fn invitation_record(invitations: &Invitations, invi_msg_id: &str) -> Result<InvitationRecord> {
+    invitations
+        .invitations
+        .get(invi_msg_id)?
+        .ok_or_else(|| Error::NotFound(format!("invitation {}", invi_msg_id)))
+}
+
+/// # Fetch a single out-of-band invitation
+#[openapi(tag = "out-of-band")]
+#[get("/out-of-band/invitations/<invi_msg_id>")]
+pub async fn get_invitation(
+    _auth: AdminAuth,
+    invitations: &TenantState<Invitations>,
+    invi_msg_id: String,
+) -> Result<Json<InvitationRecord>> {
+    let mut record = invitation_record(invitations, &invi_msg_id)?;
+    record.refresh_state();
+    Ok(Json(record))
+}
+
+/// # Revoke an outstanding out-of-band invitation
+///
+/// The connection waiting for the invitation is abandoned.
+#[openapi(tag = "out-of-band")]
+#[delete("/out-of-band/invitations/<invi_msg_id>")]
+pub async fn delete_invitation(
+    _auth: AdminAuth,
+    connections: &TenantState<Connections>,
+    invitations: &TenantState<Invitations>,
+    connection_events: &TenantState<Arc<Mutex<ConnectionEvents>>>,
+    invi_msg_id: String,
+) -> Result<Status> {
+    let mut record = invitation_record(invitations, &invi_msg_id)?;
+    record.state = InvitationState::Revoked;
+    invitations
+        .invitations
+        .insert(&invi_msg_id, record.clone())?;
+    if let Some(mut connection) = connections.connections.get(&record.connection_id)? {
+        if connection.state == ConnectionState::InvitationSent {
+            didexchange::set_state(
+                connections,
+                connection_events,
+                &mut connection,
+                ConnectionState::Abandoned,
+            )
+            .await?;
+        }
+    }
+    Ok(Status::Ok)
+}
+
+/// # Render a created invitation as qr code
+#[openapi(tag = "out-of-band")]
+#[get("/out-of-band/invitations/<invi_msg_id>/qr-code?<format>")]
+pub async fn get_invitation_record_qr_code(
+    _auth: AdminAuth,
+    invitations: &TenantState<Invitations>,
+    invi_msg_id: String,
+    format: Option<QrFormat>,
+) -> Result<QrImage> {
+    let record = invitation_record(invitations, &invi_msg_id)?;
+    qr::render(&record.invitation_url, &format.unwrap_or_default())
+        .map_err(|err| Error::BadRequest(err.to_string()))
 }
 
 /// # Receive a new connection invitation.
+///
+/// Accepts the invitation message or the invitation url as json string.
+/// Answers the invitation with a did exchange request, or with a handshake
+/// reuse when `use_existing_connection` is set and a completed connection
+/// to the inviter exists.
+///
+/// The connection is abandoned if the request can not be sent.
 #[openapi(tag = "out-of-band")]
 #[post(
-    "/out-of-band/receive-invitation",
+    "/out-of-band/receive-invitation?<alias>&<use_existing_connection>",
     format = "application/json",
     data = "<invitation>"
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn post_receive_invitation(
-    connections: &State<Connections>,
-    connection_events: &State<Arc<Mutex<ConnectionEvents>>>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    didcomm: &State<Box<dyn DidComm>>,
+    connections: &TenantState<Connections>,
+    connection_events: &TenantState<Arc<Mutex<ConnectionEvents>>>,
+    alias: Option<String>,
+    use_existing_connection: Option<bool>,
     invitation: Json<Value>,
-) -> Json<Value> {
-    let invitation = invitation.into_inner();
-    let message: Message = serde_json::from_value(invitation.clone()).unwrap();
-    let id = message.get_didcomm_header().id.to_string();
+) -> Result<Json<Value>> {
+    let invitation = match invitation.into_inner() {
+        Value::String(invitation_url) => invitation::parse_invitation_url(&invitation_url)
+            .map_err(|err| Error::BadRequest(format!("invitation url: {}", err)))?,
+        invitation => invitation,
+    };
+    let message: Message = serde_json::from_value(invitation.clone())
+        .map_err(|err| Error::BadRequest(format!("invitation: {}", err)))?;
+    let invitation_id = message.get_didcomm_header().id.to_string();
     let (_, services) = message
         .get_application_params()
         .find(|(key, _)| *key == "services")
-        .unwrap();
-    let services: Vec<Service> = serde_json::from_str(services).unwrap();
-    let services = services
-        .iter()
-        .filter(|service| service.id.starts_with("did:iota"))
-        .cloned()
-        .collect::<Vec<Service>>();
-
-    let endpoint: String = services.first().unwrap().service_endpoint.to_string();
-    let did: String = services.first().unwrap().id.replace("#didcomm", "");
-
-    let client = reqwest::Client::new();
-    match client
-        .post(endpoint.to_string())
-        .json(&invitation)
-        .send()
-        .await
-    {
-        Ok(_) => (),
-        Err(err) => error!("{:?}", err),
+        .ok_or_else(|| Error::BadRequest("invitation without services".to_string()))?;
+    let services: Vec<Service> = serde_json::from_str(services)
+        .map_err(|err| Error::BadRequest(format!("invitation services: {}", err)))?;
+    let service = services
+        .into_iter()
+        .find(|service| service.id.starts_with("did:iota"))
+        .ok_or_else(|| Error::BadRequest("invitation without did:iota service".to_string()))?;
+
+    let endpoint: String = service.service_endpoint.to_string();
+    let did: String = service.id.replace("#didcomm", "");
+    parse_did(&did)?;
+    if use_existing_connection.unwrap_or(false) {
+        if let Some(connection) = reuse::find_existing(connections, &did)? {
+            match reuse::send_reuse(
+                wallet,
+                didcomm.inner().as_ref(),
+                connection_events,
+                &connection,
+                &invitation_id,
+            )
+            .await
+            {
+                Ok(()) => return Ok(Json(invitation)),
+                Err(err) => warn!(
+                    "reuse of {} failed, exchanging dids: {:?}",
+                    connection.id, err
+                ),
+            }
+        }
+    }
+
+    let their_label = message
+        .get_application_params()
+        .find(|(key, _)| *key == "label")
+        .map(|(_, label)| label.to_string());
+
+    let mut connection = Connection {
+        did,
+        endpoint,
+        alias,
+        their_label,
+        invitation_msg_id: Some(invitation_id),
+        ..Connection::new(ConnectionRole::Invitee, ConnectionState::InvitationReceived)
     };
-    let connection = Connection { id, endpoint, did };
     let connection_id = connection.id.to_string();
-    let mut lock = connections.connections.lock().await;
-    lock.insert(connection_id.to_string(), connection);
+    connections
+        .connections
+        .insert(&connection_id, connection.clone())?;
     connection_events
-        .try_lock()
-        .unwrap()
+        .lock()
+        .await
         .send(ConnectionEvent::Created(connection_id))
         .await;
-    Json(invitation)
+
+    if let Err(err) = didexchange::send_request(
+        wallet,
+        didcomm.inner().as_ref(),
+        connections,
+        connection_events,
+        &mut connection,
+    )
+    .await
+    {
+        didexchange::set_state(
+            connections,
+            connection_events,
+            &mut connection,
+            ConnectionState::Abandoned,
+        )
+        .await?;
+        return Err(err);
+    }
+    Ok(Json(invitation))
 }
 
 /// # Query agent-to-agent connections
+///
+/// Connections are ordered by creation time.
 #[openapi(tag = "connection")]
-#[get("/connections")]
-pub async fn get_all_connections(connections: &State<Connections>) -> Json<Vec<Connection>> {
-    let lock = connections.connections.lock().await;
-    let connections = lock.values().cloned().collect();
-    Json(connections)
+#[get("/connections?<state>&<alias>&<their_did>&<limit>&<offset>")]
+pub async fn get_all_connections(
+    _auth: AdminAuth,
+    connections: &TenantState<Connections>,
+    state: Option<ConnectionState>,
+    alias: Option<String>,
+    their_did: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+) -> Result<Json<Vec<Connection>>> {
+    let mut connections: Vec<Connection> = connections
+        .connections
+        .values()?
+        .into_iter()
+        .filter(|connection| state.is_none() || state.as_ref() == Some(&connection.state))
+        .filter(|connection| alias.is_none() || alias == connection.alias)
+        .filter(|connection| their_did.is_none() || their_did.as_ref() == Some(&connection.did))
+        .collect();
+    connections.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    let connections = connections
+        .into_iter()
+        .skip(offset.unwrap_or(0))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    Ok(Json(connections))
 }
 
 /// # Fetch a single connection record
 #[openapi(tag = "connection")]
 #[get("/connections/<conn_id>")]
-pub async fn get_connection(connections: &State<Connections>, conn_id: String) -> Json<Connection> {
-    let lock = connections.connections.lock().await;
-    let connection = lock.get(&conn_id).unwrap().clone();
-    Json(connection)
+pub async fn get_connection(
+    _auth: AdminAuth,
+    connections: &TenantState<Connections>,
+    conn_id: String,
+) -> Result<Json<Connection>> {
+    let connection = connections.get(&conn_id)?;
+    Ok(Json(connection))
+}
+
+/// # Fetch connection metadata
+#[openapi(tag = "connection")]
+#[get("/connections/<conn_id>/metadata")]
+pub async fn get_connection_metadata(
+    _auth: AdminAuth,
+    connections: &TenantState<Connections>,
+    conn_id: String,
+) -> Result<Json<ConnectionMetadata>> {
+    let connection = connections.get(&conn_id)?;
+    Ok(Json(ConnectionMetadata {
+        metadata: connection.metadata,
+    }))
+}
+
+/// # Set connection metadata
+///
+/// Given keys are added or replaced, a null value removes the key.
+#[openapi(tag = "connection")]
+#[post("/connections/<conn_id>/metadata", data = "<request>")]
+pub async fn post_connection_metadata(
+    _auth: AdminAuth,
+    connections: &TenantState<Connections>,
+    conn_id: String,
+    request: Json<ConnectionMetadata>,
+) -> Result<Json<ConnectionMetadata>> {
+    let mut connection = connections.get(&conn_id)?;
+    for (key, value) in request.into_inner().metadata {
+        match value {
+            Value::Null => connection.metadata.remove(&key),
+            value => connection.metadata.insert(key, value),
+        };
+    }
+    connection.updated_at = Timestamp::now_utc().to_string();
+    connections
+        .connections
+        .insert(&conn_id, connection.clone())?;
+    Ok(Json(ConnectionMetadata {
+        metadata: connection.metadata,
+    }))
+}
+
+/// # Set auto-respond policies of the connection
+///
+/// Policies apply to exchanges on the connection unless an exchange sets its own.
+#[openapi(tag = "connection")]
+#[post("/connections/<conn_id>/auto-respond", data = "<request>")]
+pub async fn post_connection_auto_respond(
+    _auth: AdminAuth,
+    connections: &TenantState<Connections>,
+    conn_id: String,
+    request: Json<AutoRespond>,
+) -> Result<Json<Connection>> {
+    let mut connection = connections.get(&conn_id)?;
+    connection.auto_respond = Some(request.into_inner());
+    connection.updated_at = Timestamp::now_utc().to_string();
+    connections
+        .connections
+        .insert(&conn_id, connection.clone())?;
+    Ok(Json(connection))
 }
 
 /// # Fetch connection remote endpoint
 #[openapi(tag = "connection")]
 #[get("/connections/<conn_id>/endpoints")]
 pub async fn get_connection_endpoints(
+    _auth: AdminAuth,
     config: &State<Config>,
-    connections: &State<Connections>,
+    connections: &TenantState<Connections>,
     conn_id: String,
-) -> Json<ConnectionEndpoints> {
-    let lock = connections.connections.lock().await;
+) -> Result<Json<ConnectionEndpoints>> {
     let endpoint = config.ext_service.to_string();
-    let connection = lock.get(&conn_id).unwrap().clone();
+    let connection = connections.get(&conn_id)?;
     let their_endpoint = connection.endpoint;
-    Json(ConnectionEndpoints {
+    Ok(Json(ConnectionEndpoints {
         my_endpoint: endpoint,
         their_endpoint,
-    })
+    }))
 }
 
 /// # Remove an existing connection record
+///
+/// Sends a termination message to the peer and removes the connection. The
+/// connection is removed as well if the peer can not be reached.
 #[openapi(tag = "connection")]
 #[delete("/connections/<conn_id>")]
 pub async fn delete_connection(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
     didcomm: &State<Box<dyn DidComm>>,
-    connections: &State<Connections>,
+    connections: &TenantState<Connections>,
+    connection_events: &TenantState<Arc<Mutex<ConnectionEvents>>>,
     conn_id: String,
-) -> Status {
-    let lock = connections.connections.lock().await;
-    let connection = lock.get(&conn_id).unwrap().clone();
-    std::mem::drop(lock);
-    let endpoint = connection.endpoint.to_string();
-    let termination: Termination = Termination {
-        typ: "application/didcomm-plain+json".to_string(),
-        type_: "iota/termination/0.1/termination".to_string(),
-        id: connection.id.clone(),
-        body: Value::default(),
-    };
-    match didcomm.post(&endpoint, &json!(termination)).await {
-        Ok(_) => (),
-        Err(err) => error!("{:?}", err),
-    };
-    let mut lock = connections.connections.lock().await;
-    lock.remove(&conn_id).unwrap();
-    Status::Ok
+) -> Result<Status> {
+    let connection = connections.get(&conn_id)?;
+    termination::terminate(
+        wallet,
+        didcomm.as_ref(),
+        connections,
+        connection_events,
+        &connection,
+    )
+    .await?;
+    Ok(Status::Ok)
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::webhook;
+    use crate::didcomm::build_message;
+    use crate::tenant::Tenants;
+    use crate::webhook::{self, WebhookPool};
     use crate::{test_rocket, test_rocket_with_webhook_client};
+    use didcomm_mediator::message::sign_and_encrypt;
+    use futures::{FutureExt, StreamExt};
+    use identity_iota::prelude::{KeyPair, KeyType};
+    use pharos::{Channel, Observable};
     use rocket::http::{ContentType, Status};
     use rocket::local::asynchronous::Client;
     use serde_json::{from_value, Value};
 
-    pub async fn connect(client: &Client) -> Result<Connection, Box<dyn std::error::Error>> {
+    pub async fn connect(
+        client: &Client,
+    ) -> std::result::Result<Connection, Box<dyn std::error::Error>> {
         let response = client.get("/connections").dispatch().await;
         assert_eq!(response.status(), Status::Ok);
         let response = response.into_json::<Value>().await.unwrap();
@@ -235,8 +673,8 @@ pub mod tests {
             .dispatch()
             .await;
         assert_eq!(response.status(), Status::Ok);
-        let invitation: Value = response.into_json::<Value>().await.unwrap();
-        let invitation: String = serde_json::to_string(&invitation).unwrap();
+        let invitation: InvitationResponse = response.into_json().await.unwrap();
+        let invitation: String = serde_json::to_string(&invitation.invitation_url).unwrap();
 
         let response = client
             .post("/out-of-band/receive-invitation")
@@ -251,7 +689,10 @@ pub mod tests {
         let response = response.into_json::<Value>().await.unwrap();
         let connections: Vec<Connection> = from_value(response).unwrap();
 
-        let connection = connections.last().unwrap().clone();
+        let connection = connections
+            .into_iter()
+            .find(|connection| connection.state != ConnectionState::InvitationSent)
+            .unwrap();
         Ok(connection)
     }
 
@@ -274,8 +715,8 @@ pub mod tests {
             .dispatch()
             .await;
         assert_eq!(response.status(), Status::Ok);
-        let invitation: Value = response.into_json::<Value>().await.unwrap();
-        let invitation: String = serde_json::to_string(&invitation).unwrap();
+        let invitation: InvitationResponse = response.into_json().await.unwrap();
+        let invitation: String = serde_json::to_string(&invitation.invitation).unwrap();
 
         let response = client
             .post("/out-of-band/receive-invitation")
@@ -289,41 +730,380 @@ pub mod tests {
         assert_eq!(response.status(), Status::Ok);
         let response = response.into_json::<Value>().await.unwrap();
         let connections = response.as_array().unwrap();
-        assert_eq!(connections.len(), 1);
+        assert_eq!(connections.len(), 2);
         let connections: Vec<Connection> = serde_json::from_value(response).unwrap();
 
-        let connection_id = connections[0].id.to_string();
+        let inviter = connections
+            .iter()
+            .find(|connection| connection.state == ConnectionState::InvitationSent)
+            .unwrap();
+        assert!(inviter.invitation_msg_id.is_some());
+
+        let invitee = connections
+            .iter()
+            .find(|connection| connection.state == ConnectionState::RequestSent)
+            .unwrap();
+        assert_eq!(invitee.invitation_msg_id, inviter.invitation_msg_id);
+        assert!(invitee.thread_id.is_some());
+
         assert_eq!(
             webhook::test_client::last_response(&webhook_client).unwrap(),
-            serde_json::to_value(ConnectionEvent::Created(connection_id)).unwrap()
+            serde_json::to_value(ConnectionEvent::RequestSent(invitee.id.to_string())).unwrap()
         );
     }
 
     #[tokio::test]
     async fn test_delete_connection() {
-        let client = Client::tracked(test_rocket().await)
+        let webhook_client = Box::new(webhook::test_client::TestClient::new(Default::default()))
+            as Box<dyn webhook::Webhook>;
+        let webhook_client = Arc::new(Mutex::new(webhook_client));
+        let client = Client::tracked(test_rocket_with_webhook_client(webhook_client.clone()).await)
             .await
             .expect("valid rocket instance");
 
-        connect(&client).await.unwrap();
+        let connection = connect(&client).await.unwrap();
 
         let response = client.get("/connections").dispatch().await;
         assert_eq!(response.status(), Status::Ok);
         let response = response.into_json::<Value>().await.unwrap();
         let connections = response.as_array().unwrap();
-        assert_eq!(connections.len(), 1);
+        assert_eq!(connections.len(), 2);
 
-        let connection: Connection = serde_json::from_value(connections[0].clone()).unwrap();
         let response = client
             .delete(format!("/connections/{}", connection.id))
             .dispatch()
             .await;
         assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            webhook::test_client::last_response(&webhook_client).unwrap(),
+            serde_json::to_value(ConnectionEvent::Terminated(connection.id.to_string())).unwrap()
+        );
 
         let response = client.get("/connections").dispatch().await;
         assert_eq!(response.status(), Status::Ok);
         let response = response.into_json::<Value>().await.unwrap();
         let connections = response.as_array().unwrap();
+        assert_eq!(connections.len(), 1);
+
+        let response = client
+            .delete(format!("/connections/{}", connection.id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peer() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+        let did = crate::wallet::tests::get_did(&client).await.unwrap();
+
+        let response = client
+            .post("/out-of-band/create-invitation")
+            .dispatch()
+            .await;
+        let invitation: InvitationResponse = response.into_json().await.unwrap();
+        // a did not published on the ledger can not be encrypted for
+        let keypair = KeyPair::new(KeyType::Ed25519).unwrap();
+        let unpublished = IotaDID::new(keypair.public().as_ref()).unwrap().to_string();
+        let invitation = invitation
+            .invitation
+            .to_string()
+            .replace(&did, &unpublished);
+
+        let response = client
+            .post("/out-of-band/receive-invitation")
+            .header(ContentType::JSON)
+            .body(invitation)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadGateway);
+        let response = client
+            .get(format!("/connections?their_did={}", unpublished))
+            .dispatch()
+            .await;
+        let connections: Vec<Connection> = response.into_json().await.unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].state, ConnectionState::Abandoned);
+
+        // the connection is removed although the termination is not sent
+        let response = client
+            .delete(format!("/connections/{}", connections[0].id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadGateway);
+        let response = client
+            .delete(format!("/connections/{}", connections[0].id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_invitation_qr_code() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .post("/out-of-band/create-invitation")
+            .dispatch()
+            .await;
+        let invitation: InvitationResponse = response.into_json().await.unwrap();
+
+        let response = client
+            .get(format!(
+                "/out-of-band/qr-code?invitation_url={}&format=svg",
+                url::form_urlencoded::byte_serialize(invitation.invitation_url.as_bytes())
+                    .collect::<String>()
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+    }
+
+    #[tokio::test]
+    async fn test_invitations() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .post("/out-of-band/create-invitation?multi_use=true&max_uses=5&expires_in=3600")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let invitation: InvitationResponse = response.into_json().await.unwrap();
+
+        let response = client
+            .post("/out-of-band/create-invitation")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        for query in [
+            "max_uses=0",
+            "expires_in=0",
+            "expires_in=9223372036854775807",
+        ] {
+            let response = client
+                .post(format!("/out-of-band/create-invitation?{}", query))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+
+        let response = client.get("/out-of-band/invitations").dispatch().await;
+        let records: Vec<InvitationRecord> = response.into_json().await.unwrap();
+        assert_eq!(records.len(), 2);
+
+        let response = client
+            .get(format!(
+                "/out-of-band/invitations/{}",
+                invitation.invitation_msg_id
+            ))
+            .dispatch()
+            .await;
+        let record: InvitationRecord = response.into_json().await.unwrap();
+        assert!(record.multi_use);
+        assert_eq!(record.max_uses, Some(5));
+        assert!(record.expires_at.is_some());
+
+        let response = client
+            .get(format!(
+                "/out-of-band/invitations/{}/qr-code",
+                invitation.invitation_msg_id
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+
+        let response = client
+            .delete(format!(
+                "/out-of-band/invitations/{}",
+                invitation.invitation_msg_id
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/out-of-band/invitations?state=revoked")
+            .dispatch()
+            .await;
+        let records: Vec<InvitationRecord> = response.into_json().await.unwrap();
+        assert_eq!(records.len(), 1);
+
+        let response = client
+            .get(format!("/connections/{}", invitation.connection_id))
+            .dispatch()
+            .await;
+        let connection: Connection = response.into_json().await.unwrap();
+        assert_eq!(connection.state, ConnectionState::Abandoned);
+    }
+
+    /// Receives an invitation of ourselves with `use_existing_connection`
+    /// while a completed connection to our did exists and returns it with the
+    /// connections afterwards and whether it was reused.
+    async fn receive_with_existing(client: &Client) -> (Connection, Vec<Connection>, bool) {
+        let did = crate::wallet::tests::get_did(client).await.unwrap();
+
+        let existing = Connection {
+            did,
+            endpoint: "http://localhost:8000".to_string(),
+            ..Connection::new(ConnectionRole::Invitee, ConnectionState::Completed)
+        };
+        let agent = client.rocket().state::<Tenants>().unwrap().base();
+        agent
+            .connections
+            .connections
+            .insert(&existing.id, existing.clone())
+            .unwrap();
+
+        let response = client
+            .post("/out-of-band/create-invitation")
+            .dispatch()
+            .await;
+        let invitation: InvitationResponse = response.into_json().await.unwrap();
+
+        let mut events = agent
+            .connection_events
+            .lock()
+            .await
+            .observe(Channel::Unbounded.into())
+            .await
+            .unwrap();
+        let response = client
+            .post("/out-of-band/receive-invitation?use_existing_connection=true")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&invitation.invitation).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/connections").dispatch().await;
+        let connections: Vec<Connection> = response.into_json().await.unwrap();
+        let mut reused = false;
+        while let Some(Some(event)) = events.next().now_or_never() {
+            reused |= event == ConnectionEvent::Reused(existing.id.to_string());
+        }
+        (existing, connections, reused)
+    }
+
+    #[tokio::test]
+    async fn test_use_existing_connection() {
+        let rocket = rocket::build();
+        let config: Config = rocket.figment().extract().expect("config");
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        let did = wallet.did_iota().unwrap();
+        let accepted = build_message(reuse::HANDSHAKE_REUSE_ACCEPTED, json!({})).unwrap();
+        let accepted = sign_and_encrypt(&accepted, &did, &did, &wallet.keypair())
+            .await
+            .unwrap();
+        let mut didcomm = crate::didcomm::test_client::TestClient::new();
+        didcomm.response(accepted);
+        let rocket = crate::rocket(rocket, config, WebhookPool::default(), Box::new(didcomm)).await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let (existing, connections, reused) = receive_with_existing(&client).await;
+        assert_eq!(connections.len(), 2);
+        assert!(connections.contains(&existing));
+        assert!(reused);
+    }
+
+    #[tokio::test]
+    async fn test_use_existing_connection_fallback() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        // the test didcomm client answers the reuse with nothing
+        let (existing, connections, reused) = receive_with_existing(&client).await;
+        assert!(!reused);
+        assert_eq!(connections.len(), 3);
+        assert!(connections
+            .iter()
+            .any(|connection| connection.id != existing.id
+                && connection.role == ConnectionRole::Invitee));
+    }
+
+    #[tokio::test]
+    async fn test_filter_connections() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .post("/out-of-band/create-invitation?alias=kiosk")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post("/out-of-band/create-invitation")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/connections?state=invitation-sent")
+            .dispatch()
+            .await;
+        let connections: Vec<Connection> = response.into_json().await.unwrap();
+        assert_eq!(connections.len(), 2);
+        assert!(connections
+            .iter()
+            .all(|connection| connection.role == ConnectionRole::Inviter));
+
+        let response = client.get("/connections?alias=kiosk").dispatch().await;
+        let connections: Vec<Connection> = response.into_json().await.unwrap();
+        assert_eq!(connections.len(), 1);
+
+        let response = client.get("/connections?limit=1&offset=1").dispatch().await;
+        let connections: Vec<Connection> = response.into_json().await.unwrap();
+        assert_eq!(connections.len(), 1);
+
+        let response = client.get("/connections?state=completed").dispatch().await;
+        let connections: Vec<Connection> = response.into_json().await.unwrap();
         assert_eq!(connections.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_connection_metadata() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+        let connection = connect(&client).await.unwrap();
+
+        let response = client
+            .post(format!("/connections/{}/metadata", connection.id))
+            .json(&json!({"metadata": {"customer": "42"}}))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get(format!("/connections/{}/metadata", connection.id))
+            .dispatch()
+            .await;
+        let metadata: ConnectionMetadata = response.into_json().await.unwrap();
+        assert_eq!(metadata.metadata.get("customer"), Some(&json!("42")));
+    }
+
+    #[test]
+    fn test_migrate_connection() {
+        let value = json!({
+            "id": "foo",
+            "did": "did:iota:foo",
+            "endpoint": "http://localhost:8000"
+        });
+        let value = Connection::migrate(1, value).unwrap();
+        let value = Connection::migrate(2, value).unwrap();
+        let connection: Connection = serde_json::from_value(value).unwrap();
+        assert_eq!(connection.state, ConnectionState::Completed);
+        assert_eq!(connection.role, ConnectionRole::Invitee);
+    }
 }
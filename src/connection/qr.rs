@@ -0,0 +1,91 @@
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rocket::http::ContentType;
+use rocket::response::{self, Responder};
+use rocket::Request;
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{MediaType, RefOr, Response, Responses};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::response::OpenApiResponderInner;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    #[default]
+    #[field(value = "png")]
+    Png,
+    #[field(value = "svg")]
+    Svg,
+}
+
+/// Rendered qr code image.
+pub struct QrImage {
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+}
+
+pub fn render(data: &str, format: &QrFormat) -> Result<QrImage, Box<dyn std::error::Error>> {
+    let code = QrCode::new(data.as_bytes())?;
+    match format {
+        QrFormat::Png => {
+            let image = code.render::<Luma<u8>>().min_dimensions(200, 200).build();
+            let mut data = Vec::new();
+            DynamicImage::ImageLuma8(image).write_to(&mut data, ImageOutputFormat::Png)?;
+            Ok(QrImage {
+                content_type: ContentType::PNG,
+                data,
+            })
+        }
+        QrFormat::Svg => {
+            let image = code.render::<svg::Color>().min_dimensions(200, 200).build();
+            Ok(QrImage {
+                content_type: ContentType::SVG,
+                data: image.into_bytes(),
+            })
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for QrImage {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        (self.content_type, self.data).respond_to(request)
+    }
+}
+
+impl OpenApiResponderInner for QrImage {
+    fn responses(_gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut response = Response {
+            description: "QR code image".to_string(),
+            ..Default::default()
+        };
+        response
+            .content
+            .insert("image/png".to_string(), MediaType::default());
+        response
+            .content
+            .insert("image/svg+xml".to_string(), MediaType::default());
+        let mut responses = Responses::default();
+        responses
+            .responses
+            .insert("200".to_string(), RefOr::Object(response));
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let png = render("https://example.com/?_oob=e30", &QrFormat::Png).unwrap();
+        assert_eq!(png.content_type, ContentType::PNG);
+        assert_eq!(&png.data[1..4], b"PNG");
+
+        let svg = render("https://example.com/?_oob=e30", &QrFormat::Svg).unwrap();
+        assert_eq!(svg.content_type, ContentType::SVG);
+        assert!(String::from_utf8(svg.data).unwrap().contains("<svg"));
+    }
+}
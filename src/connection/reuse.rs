@@ -0,0 +1,122 @@
+use super::invitation::{InvitationState, Invitations};
+use super::{Connection, ConnectionEvent, ConnectionEvents, ConnectionState, Connections};
+use crate::didcomm::{build_message, parent_thread_id, receive, thread_id, with_thread, DidComm};
+use crate::error::Error;
+use crate::wallet::Wallet;
+use did_key::KeyMaterial;
+use didcomm_mediator::message::sign_and_encrypt;
+use didcomm_rs::Message;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const HANDSHAKE_REUSE: &str = "https://didcomm.org/out-of-band/2.0/handshake-reuse";
+pub const HANDSHAKE_REUSE_ACCEPTED: &str =
+    "https://didcomm.org/out-of-band/2.0/handshake-reuse-accepted";
+
+/// A completed connection with the peer did.
+pub fn find_existing(connections: &Connections, did: &str) -> Result<Option<Connection>, Error> {
+    Ok(connections
+        .connections
+        .values()?
+        .into_iter()
+        .find(|connection| connection.did == did && connection.state == ConnectionState::Completed))
+}
+
+async fn reused(connection_events: &Arc<Mutex<ConnectionEvents>>, connection: &Connection) {
+    connection_events
+        .lock()
+        .await
+        .send(ConnectionEvent::Reused(connection.id.to_string()))
+        .await;
+}
+
+/// Invitee side: asks the inviter to reuse the existing connection instead
+/// of a new handshake. Fails unless the inviter accepts the reuse.
+pub async fn send_reuse(
+    wallet: &Arc<Mutex<Wallet>>,
+    didcomm: &dyn DidComm,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    connection: &Connection,
+    invitation_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (my_did, keypair) = {
+        let wallet = wallet.lock().await;
+        (
+            wallet.did_iota().ok_or("wallet has no did")?,
+            wallet.keypair(),
+        )
+    };
+    let reuse = build_message(HANDSHAKE_REUSE, json!({}))?;
+    let thid = thread_id(&reuse);
+    let reuse = with_thread(reuse, &thid, Some(invitation_id))?;
+    let message = sign_and_encrypt(&reuse, &my_did, &connection.did, &keypair).await?;
+
+    let response = didcomm.post(&connection.endpoint, &message).await?;
+    let body = response.text().await?;
+    let response = receive(&body, &keypair.private_key_bytes(), None).await?;
+    let m_type = &response.get_didcomm_header().m_type;
+    if m_type != HANDSHAKE_REUSE_ACCEPTED {
+        return Err(format!("reuse answered with {}", m_type).into());
+    }
+    reused(connection_events, connection).await;
+    Ok(())
+}
+
+/// Inviter side: accepts the reuse of a completed connection for an active invitation.
+pub async fn receive_reuse(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    invitations: &Invitations,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    reuse: &Message,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let invitation_id = parent_thread_id(reuse).ok_or("reuse without invitation")?;
+    let mut invitation = invitations
+        .invitations
+        .get(&invitation_id)?
+        .ok_or("unknown invitation")?;
+    invitation.refresh_state();
+    if invitation.state == InvitationState::Revoked || invitation.state == InvitationState::Expired
+    {
+        return Err(format!("invitation {} is {:?}", invitation.id, invitation.state).into());
+    }
+
+    let their_did = reuse
+        .get_didcomm_header()
+        .from
+        .clone()
+        .ok_or("reuse without sender")?;
+    let connection = find_existing(connections, &their_did)?
+        .ok_or_else(|| Error::ConnectionNotFound(their_did.to_string()))?;
+
+    let (my_did, keypair) = {
+        let wallet = wallet.lock().await;
+        (
+            wallet.did_iota().ok_or("wallet has no did")?,
+            wallet.keypair(),
+        )
+    };
+    let accepted = build_message(HANDSHAKE_REUSE_ACCEPTED, json!({}))?;
+    let accepted = with_thread(accepted, &thread_id(reuse), Some(&invitation_id))?;
+    let message = sign_and_encrypt(&accepted, &my_did, &their_did, &keypair).await?;
+    reused(connection_events, &connection).await;
+    Ok(message)
+}
+
+/// Invitee side: the reuse was accepted asynchronously.
+pub async fn receive_reuse_accepted(
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    accepted: &Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let their_did = accepted
+        .get_didcomm_header()
+        .from
+        .clone()
+        .ok_or("reuse accepted without sender")?;
+    let connection = find_existing(connections, &their_did)?
+        .ok_or_else(|| Error::ConnectionNotFound(their_did.to_string()))?;
+    reused(connection_events, &connection).await;
+    Ok(())
+}
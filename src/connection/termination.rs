@@ -0,0 +1,148 @@
+use super::{Connection, ConnectionEvent, ConnectionEvents, Connections};
+use crate::didcomm::{
+    build_message, build_problem_report, is_returned_message, receive, thread_id, with_thread,
+    DidComm,
+};
+use crate::error::Error;
+use crate::wallet::{wallet_did, Wallet};
+use did_key::KeyMaterial;
+use didcomm_mediator::message::sign_and_encrypt;
+use didcomm_rs::Message;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const TERMINATION: &str = "iota/termination/0.1/termination";
+pub const TERMINATION_RESPONSE: &str = "iota/termination/0.1/termination-response";
+
+async fn terminated(
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    connection: &Connection,
+) -> Result<(), Error> {
+    connections
+        .connections
+        .remove(&connection.id)?
+        .ok_or_else(|| Error::ConnectionNotFound(connection.id.to_string()))?;
+    connection_events
+        .lock()
+        .await
+        .send(ConnectionEvent::Terminated(connection.id.to_string()))
+        .await;
+    Ok(())
+}
+
+fn find_by_did(connections: &Connections, did: &str) -> Result<Vec<Connection>, Error> {
+    Ok(connections
+        .connections
+        .values()?
+        .into_iter()
+        .filter(|connection| connection.did == did)
+        .collect())
+}
+
+/// Initiating side: informs the peer and removes the connection.
+///
+/// The connection is removed even if the peer can not be reached, which is
+/// reported as `Error::PeerUnreachable`.
+pub async fn terminate(
+    wallet: &Arc<Mutex<Wallet>>,
+    didcomm: &dyn DidComm,
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    connection: &Connection,
+) -> Result<(), Error> {
+    let sent = send_termination(wallet, didcomm, connection).await;
+    terminated(connections, connection_events, connection).await?;
+    sent
+}
+
+/// Posts the termination, a response the peer does not return on the return
+/// route arrives asynchronously.
+async fn send_termination(
+    wallet: &Arc<Mutex<Wallet>>,
+    didcomm: &dyn DidComm,
+    connection: &Connection,
+) -> Result<(), Error> {
+    let (my_did, keypair) = {
+        let wallet = wallet.lock().await;
+        (wallet_did(&wallet)?.to_string(), wallet.keypair())
+    };
+    let termination = build_message(TERMINATION, json!({}))?;
+    let message = sign_and_encrypt(&termination, &my_did, &connection.did, &keypair)
+        .await
+        .map_err(|err| Error::PeerUnreachable(format!("{}: {:?}", connection.did, err)))?;
+    let response = didcomm.post(&connection.endpoint, &message).await?;
+    let body = response.text().await?;
+    if !is_returned_message(&body) {
+        return Ok(());
+    }
+    let response = receive(&body, &keypair.private_key_bytes(), None).await?;
+    match response.get_didcomm_header().m_type.as_str() {
+        TERMINATION_RESPONSE => Ok(()),
+        m_type => Err(Error::PeerUnreachable(format!(
+            "termination answered with {}: {:?}",
+            m_type,
+            response.get_body()
+        ))),
+    }
+}
+
+/// Receiving side: removes all connections with the sender and answers with
+/// a termination response, or a problem report if there is no connection.
+pub async fn receive_termination(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    termination: &Message,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let their_did = termination
+        .get_didcomm_header()
+        .from
+        .clone()
+        .ok_or("termination without sender")?;
+    let (my_did, keypair) = {
+        let wallet = wallet.lock().await;
+        (
+            wallet.did_iota().ok_or("wallet has no did")?,
+            wallet.keypair(),
+        )
+    };
+
+    let terminate = find_by_did(connections, &their_did)?;
+    let response = match terminate.is_empty() {
+        true => build_problem_report(
+            "e.p.msg.connection-not-found",
+            &format!("no connection with {}", their_did),
+            &thread_id(termination),
+        )?,
+        false => {
+            for connection in terminate {
+                terminated(connections, connection_events, &connection).await?;
+            }
+            with_thread(
+                build_message(TERMINATION_RESPONSE, json!({}))?,
+                &thread_id(termination),
+                None,
+            )?
+        }
+    };
+    Ok(sign_and_encrypt(&response, &my_did, &their_did, &keypair).await?)
+}
+
+/// Initiating side: the termination response arrived asynchronously.
+pub async fn receive_termination_response(
+    connections: &Connections,
+    connection_events: &Arc<Mutex<ConnectionEvents>>,
+    response: &Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let their_did = response
+        .get_didcomm_header()
+        .from
+        .clone()
+        .ok_or("termination response without sender")?;
+    for connection in find_by_did(connections, &their_did)? {
+        terminated(connections, connection_events, &connection).await?;
+    }
+    Ok(())
+}
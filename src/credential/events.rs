@@ -1,10 +1,24 @@
+use super::exchange::CredentialExchangeState;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use {futures::SinkExt, pharos::*};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum IssueCredentialEvent {
-    IssueCredentialReceived { from: String, value: Value },
+    IssueCredentialReceived {
+        from: String,
+        value: Value,
+    },
+    StateChanged {
+        thread_id: String,
+        state: CredentialExchangeState,
+    },
+    /// A received credential failed verification and was rejected.
+    VerificationFailed {
+        from: String,
+        thread_id: String,
+        errors: Vec<String>,
+    },
 }
 
 pub struct IssueCredentialEvents {
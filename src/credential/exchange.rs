@@ -0,0 +1,499 @@
+use super::{IssueCredentialEvent, IssueCredentialEvents};
+use crate::auth::AdminAuth;
+use crate::autorespond::AutoRespond;
+use crate::connection::{reuse, Connection, Connections};
+use crate::didcomm::{build_message, sender, thread_id, with_thread};
+use crate::error::{Error, Result};
+use crate::exchange::{peer_record, ExchangeRecord};
+use crate::jwt::CredentialFormat;
+use crate::storage::{MemoryStorage, Record, Storage, Store};
+use crate::tenant::TenantState;
+use didcomm_rs::Message;
+use identity_iota::core::Timestamp;
+use rocket::http::Status;
+use rocket::{get, post, serde::json::Json};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const PROPOSE_CREDENTIAL: &str = "https://didcomm.org/issue-credential/2.1/propose-credential";
+pub const OFFER_CREDENTIAL: &str = "https://didcomm.org/issue-credential/2.1/offer-credential";
+pub const REQUEST_CREDENTIAL: &str = "https://didcomm.org/issue-credential/2.1/request-credential";
+pub const ISSUE_CREDENTIAL: &str = "https://didcomm.org/issue-credential/2.1/issue-credential";
+pub const ACK: &str = "https://didcomm.org/issue-credential/2.1/ack";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema, FromFormField)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialExchangeState {
+    #[field(value = "proposal-sent")]
+    ProposalSent,
+    #[field(value = "proposal-received")]
+    ProposalReceived,
+    #[field(value = "offer-sent")]
+    OfferSent,
+    #[field(value = "offer-received")]
+    OfferReceived,
+    #[field(value = "request-sent")]
+    RequestSent,
+    #[field(value = "request-received")]
+    RequestReceived,
+    #[field(value = "credential-issued")]
+    CredentialIssued,
+    #[field(value = "credential-received")]
+    CredentialReceived,
+    #[field(value = "done")]
+    Done,
+    #[field(value = "abandoned")]
+    Abandoned,
+}
+
+/// Our role in the credential exchange.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialExchangeRole {
+    Issuer,
+    Holder,
+}
+
+/// An issue-credential exchange, keyed by its thread id.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CredentialExchangeRecord {
+    pub thread_id: String,
+    pub connection_id: Option<String>,
+    pub their_did: String,
+    pub role: CredentialExchangeRole,
+    pub state: CredentialExchangeState,
+    pub comment: Option<String>,
+    pub credential_preview: Option<Value>,
+    pub credential: Option<Value>,
+    /// Schema the credential is issued from.
+    #[serde(default)]
+    pub schema_id: Option<String>,
+    /// Format the credential is issued in.
+    #[serde(default)]
+    pub format: CredentialFormat,
+    #[serde(default)]
+    pub auto_respond: Option<AutoRespond>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Record for CredentialExchangeRecord {}
+
+impl ExchangeRecord for CredentialExchangeRecord {
+    const NAME: &'static str = "credential exchange";
+
+    fn thread_id(&self) -> &str {
+        &self.thread_id
+    }
+
+    fn their_did(&self) -> &str {
+        &self.their_did
+    }
+}
+
+impl CredentialExchangeRecord {
+    pub fn new(
+        thread_id: &str,
+        their_did: &str,
+        role: CredentialExchangeRole,
+        state: CredentialExchangeState,
+    ) -> Self {
+        let now = Timestamp::now_utc().to_string();
+        CredentialExchangeRecord {
+            thread_id: thread_id.to_string(),
+            connection_id: None,
+            their_did: their_did.to_string(),
+            role,
+            state,
+            comment: None,
+            credential_preview: None,
+            credential: None,
+            schema_id: None,
+            format: CredentialFormat::default(),
+            auto_respond: None,
+            created_at: now.to_string(),
+            updated_at: now,
+        }
+    }
+}
+
+pub struct CredentialExchanges {
+    pub exchanges: Store<CredentialExchangeRecord>,
+}
+
+impl CredentialExchanges {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        CredentialExchanges {
+            exchanges: Store::new(storage, "credential_exchanges"),
+        }
+    }
+
+    /// Fetches the exchange of a thread, unknown threads are an `Error::NotFound`.
+    pub fn get(&self, thread_id: &str) -> Result<CredentialExchangeRecord> {
+        self.exchanges
+            .get(thread_id)?
+            .ok_or_else(|| Error::NotFound(format!("credential exchange {}", thread_id)))
+    }
+}
+
+impl Default for CredentialExchanges {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
+}
+
+/// A json application parameter of a message, plain strings are kept as is.
+pub fn application_param(message: &Message, key: &str) -> Option<Value> {
+    message
+        .get_application_params()
+        .find(|(param, _)| *param == key)
+        .map(|(_, value)| {
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+        })
+}
+
+/// The connection the exchange runs on, looked up by did for inbound exchanges.
+pub fn connection(
+    connections: &Connections,
+    record: &CredentialExchangeRecord,
+) -> Result<Connection> {
+    match &record.connection_id {
+        Some(connection_id) => connections.get(connection_id),
+        None => reuse::find_existing(connections, &record.their_did)?
+            .ok_or_else(|| Error::ConnectionNotFound(record.their_did.to_string())),
+    }
+}
+
+/// The auto-respond policies of an exchange, layered over the connection's
+/// and the global ones.
+pub fn policy(
+    global: &AutoRespond,
+    connections: &Connections,
+    record: &CredentialExchangeRecord,
+) -> AutoRespond {
+    let mut policy = global.clone();
+    if let Some(auto_respond) = connection(connections, record)
+        .ok()
+        .and_then(|connection| connection.auto_respond)
+    {
+        policy = policy.merge(&auto_respond);
+    }
+    if let Some(auto_respond) = &record.auto_respond {
+        policy = policy.merge(auto_respond);
+    }
+    policy
+}
+
+/// Stores the exchange in its new state and notifies the observers.
+pub async fn set_state(
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    record: &mut CredentialExchangeRecord,
+    state: CredentialExchangeState,
+) -> Result<()> {
+    record.state = state;
+    record.updated_at = Timestamp::now_utc().to_string();
+    exchanges
+        .exchanges
+        .insert(&record.thread_id, record.clone())?;
+    issue_credential_events
+        .lock()
+        .await
+        .send(IssueCredentialEvent::StateChanged {
+            thread_id: record.thread_id.to_string(),
+            state: record.state.clone(),
+        })
+        .await;
+    Ok(())
+}
+
+/// Records a message we sent on the connection.
+pub async fn sent(
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    connection: &Connection,
+    message: &Message,
+    role: CredentialExchangeRole,
+    state: CredentialExchangeState,
+) -> Result<CredentialExchangeRecord> {
+    let thread_id = thread_id(message);
+    let mut record = match exchanges.exchanges.get(&thread_id)? {
+        Some(record) => record,
+        None => CredentialExchangeRecord::new(&thread_id, &connection.did, role, state.clone()),
+    };
+    record.connection_id = Some(connection.id.to_string());
+    update(&mut record, message);
+    set_state(exchanges, issue_credential_events, &mut record, state).await?;
+    Ok(record)
+}
+
+/// Records an inbound message, exchanges started by the peer are created.
+pub async fn received(
+    exchanges: &CredentialExchanges,
+    connections: &Connections,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    message: &Message,
+    role: CredentialExchangeRole,
+    state: CredentialExchangeState,
+) -> Result<CredentialExchangeRecord> {
+    let their_did = sender(message)?;
+    let mut record = match peer_record(&exchanges.exchanges, message)? {
+        Some(record) => record,
+        None => {
+            let mut record =
+                CredentialExchangeRecord::new(&thread_id(message), &their_did, role, state.clone());
+            record.connection_id =
+                reuse::find_existing(connections, &their_did)?.map(|connection| connection.id);
+            record
+        }
+    };
+    update(&mut record, message);
+    set_state(exchanges, issue_credential_events, &mut record, state).await?;
+    Ok(record)
+}
+
+/// Builds the ack of a received credential.
+pub fn build_ack(thread_id: &str) -> Result<Message> {
+    with_thread(
+        build_message(ACK, json!({"status": "OK"}))?,
+        thread_id,
+        None,
+    )
+}
+
+/// Issuer side: the holder acknowledged the credential.
+///
+/// Only an issued credential is acknowledged, acks of done exchanges are
+/// ignored.
+pub async fn receive_ack(
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    ack: &Message,
+) -> Result<()> {
+    let thread_id = thread_id(ack);
+    let mut record = peer_record(&exchanges.exchanges, ack)?
+        .ok_or_else(|| Error::NotFound(format!("credential exchange {}", thread_id)))?;
+    match (&record.role, &record.state) {
+        (_, CredentialExchangeState::Done) => return Ok(()),
+        (CredentialExchangeRole::Issuer, CredentialExchangeState::CredentialIssued) => (),
+        (role, state) => {
+            return Err(Error::BadRequest(format!(
+                "no ack expected as {:?} in credential exchange {} in state {:?}",
+                role, thread_id, state
+            )))
+        }
+    }
+    set_state(
+        exchanges,
+        issue_credential_events,
+        &mut record,
+        CredentialExchangeState::Done,
+    )
+    .await
+}
+
+/// The peer reported a problem on the thread of an exchange, which is
+/// abandoned unless it is already over. Returns false if the thread is no
+/// credential exchange.
+pub async fn receive_problem_report(
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    problem_report: &Message,
+) -> Result<bool> {
+    let mut record = match peer_record(&exchanges.exchanges, problem_report)? {
+        Some(record) => record,
+        None => return Ok(false),
+    };
+    if matches!(
+        record.state,
+        CredentialExchangeState::Done | CredentialExchangeState::Abandoned
+    ) {
+        return Ok(true);
+    }
+    set_state(
+        exchanges,
+        issue_credential_events,
+        &mut record,
+        CredentialExchangeState::Abandoned,
+    )
+    .await?;
+    Ok(true)
+}
+
+fn update(record: &mut CredentialExchangeRecord, message: &Message) {
+    if let Some(comment) = application_param(message, "comment") {
+        record.comment = comment.as_str().map(|comment| comment.to_string());
+    }
+    if let Some(credential_preview) = application_param(message, "credential_preview") {
+        record.credential_preview = Some(credential_preview);
+    }
+}
+
+/// # Query credential exchange records
+#[openapi(tag = "issue-credential v2.1")]
+#[get("/issue-credential-2.1/records?<state>&<connection_id>")]
+pub async fn get_all_records(
+    _auth: AdminAuth,
+    exchanges: &TenantState<CredentialExchanges>,
+    state: Option<CredentialExchangeState>,
+    connection_id: Option<String>,
+) -> Result<Json<Vec<CredentialExchangeRecord>>> {
+    let mut records: Vec<CredentialExchangeRecord> = exchanges
+        .exchanges
+        .values()?
+        .into_iter()
+        .filter(|record| state.is_none() || state.as_ref() == Some(&record.state))
+        .filter(|record| connection_id.is_none() || connection_id == record.connection_id)
+        .collect();
+    records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(Json(records))
+}
+
+/// # Fetch a single credential exchange record
+#[openapi(tag = "issue-credential v2.1")]
+#[get("/issue-credential-2.1/records/<thread_id>")]
+pub async fn get_record(
+    _auth: AdminAuth,
+    exchanges: &TenantState<CredentialExchanges>,
+    thread_id: String,
+) -> Result<Json<CredentialExchangeRecord>> {
+    Ok(Json(exchanges.get(&thread_id)?))
+}
+
+/// # Set auto-respond policies of a credential exchange
+#[openapi(tag = "issue-credential v2.1")]
+#[post(
+    "/issue-credential-2.1/records/<thread_id>/auto-respond",
+    data = "<request>"
+)]
+pub async fn post_record_auto_respond(
+    _auth: AdminAuth,
+    exchanges: &TenantState<CredentialExchanges>,
+    thread_id: String,
+    request: Json<AutoRespond>,
+) -> Result<Json<CredentialExchangeRecord>> {
+    let mut record = exchanges.get(&thread_id)?;
+    record.auto_respond = Some(request.into_inner());
+    record.updated_at = Timestamp::now_utc().to_string();
+    exchanges.exchanges.insert(&thread_id, record.clone())?;
+    Ok(Json(record))
+}
+
+/// # Remove a credential exchange record
+#[openapi(tag = "issue-credential v2.1")]
+#[delete("/issue-credential-2.1/records/<thread_id>")]
+pub async fn delete_record(
+    _auth: AdminAuth,
+    exchanges: &TenantState<CredentialExchanges>,
+    thread_id: String,
+) -> Result<Status> {
+    exchanges
+        .exchanges
+        .remove(&thread_id)?
+        .ok_or_else(|| Error::NotFound(format!("credential exchange {}", thread_id)))?;
+    Ok(Status::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::issue::example_credential_preview;
+    use crate::didcomm::build_problem_report;
+    use crate::test_rocket;
+    use crate::wallet::tests::get_did;
+    use didcomm_protocols::IssueCredentialResponseBuilder;
+    use rocket::local::asynchronous::Client;
+
+    #[tokio::test]
+    async fn test_records() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let did = get_did(&client).await.unwrap();
+
+        let proposal = IssueCredentialResponseBuilder::new()
+            .goal_code("issue-vc".to_string())
+            .comment("proposal".to_string())
+            .credential_preview(example_credential_preview())
+            .build_propose_credential()
+            .unwrap()
+            .from(&did)
+            .to(&[&did]);
+        let response = client.post("/").json(&proposal).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/issue-credential-2.1/records?state=proposal-received")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let records: Vec<CredentialExchangeRecord> = response.into_json().await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].role, CredentialExchangeRole::Issuer);
+        assert!(records[0].credential_preview.is_some());
+        let thread_id = records[0].thread_id.to_string();
+        assert_eq!(thread_id, proposal.get_didcomm_header().id);
+
+        let response = client
+            .get(format!("/issue-credential-2.1/records/{}", thread_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // acks are only taken from the peer of an issued credential
+        let other = "did:iota:H3C2AVvLMv6gmMNam3uVAjZpfkcJCwDwnZn6z3wXmqPV";
+        let ack = build_ack(&thread_id).unwrap().from(other).to(&[&did]);
+        let response = client.post("/").json(&ack).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let ack = build_ack(&thread_id).unwrap().from(&did).to(&[&did]);
+        let response = client.post("/").json(&ack).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // problem reports are only taken from the peer
+        let problem_report = build_problem_report("e.p.test", "test", &thread_id)
+            .unwrap()
+            .from(other)
+            .to(&[&did]);
+        let response = client.post("/").json(&problem_report).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let record: CredentialExchangeRecord = client
+            .get(format!("/issue-credential-2.1/records/{}", thread_id))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(record.state, CredentialExchangeState::ProposalReceived);
+        let problem_report = build_problem_report("e.p.test", "test", &thread_id)
+            .unwrap()
+            .from(&did)
+            .to(&[&did]);
+        let response = client.post("/").json(&problem_report).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let record: CredentialExchangeRecord = client
+            .get(format!("/issue-credential-2.1/records/{}", thread_id))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(record.state, CredentialExchangeState::Abandoned);
+
+        let response = client
+            .delete(format!("/issue-credential-2.1/records/{}", thread_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get(format!("/issue-credential-2.1/records/{}", thread_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -1,5 +1,21 @@
-use crate::connection::Connections;
-use crate::wallet::Wallet;
+use super::exchange::{
+    self, CredentialExchangeRecord, CredentialExchangeRole, CredentialExchangeState,
+    CredentialExchanges,
+};
+use super::verification::verify_attachment;
+use super::{Credentials, IssueCredentialEvent, IssueCredentialEvents};
+use crate::auth::AdminAuth;
+use crate::autorespond::AutoRespond;
+use crate::connection::{Connection, Connections};
+use crate::didcomm::{build_problem_report, thread_id, with_thread};
+use crate::error::Error;
+use crate::jwt::{self, CredentialFormat};
+use crate::revocation::{self, Revocations};
+use crate::schema::Schemas;
+use crate::sdjwt;
+use crate::tenant::TenantState;
+use crate::wallet::{wallet_did, Wallet};
+use crate::ConfigExt;
 use didcomm_mediator::message::{add_return_route_all_header, sign_and_encrypt};
 use didcomm_protocols::{CredentialAttribute, CredentialPreview, IssueCredentialResponseBuilder};
 use didcomm_rs::Message;
@@ -7,21 +23,18 @@ use identity_iota::core::FromJson;
 use identity_iota::core::Url;
 use identity_iota::credential::Credential;
 use identity_iota::credential::CredentialBuilder;
+use identity_iota::credential::Schema;
+use identity_iota::credential::Status;
 use identity_iota::credential::Subject;
 use identity_iota::crypto::ProofOptions;
-use identity_iota::did::DID;
-use identity_iota::iota_core::IotaDID;
-use identity_iota::prelude::KeyPair;
-use identity_iota::prelude::*;
-use rocket::http::Status;
-use rocket::State;
-use rocket::{post, serde::json::Json};
+use rocket::{post, serde::json::Json, State};
 use rocket_okapi::okapi::schemars::{self, JsonSchema};
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use uuid::Uuid;
 
 pub fn example_type() -> &'static str {
     "UniversityDegreeCredential"
@@ -57,6 +70,8 @@ pub struct CreateProposalRequest {
     pub comment: String,
     #[schemars(example = "example_credential_preview")]
     pub credential_preview: CredentialPreview,
+    #[serde(default)]
+    pub auto_respond: Option<AutoRespond>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -66,6 +81,14 @@ pub struct CreateOfferRequest {
     pub comment: String,
     #[schemars(example = "example_credential_preview")]
     pub credential_preview: CredentialPreview,
+    /// Id of a registered schema the attributes are validated against.
+    #[serde(default)]
+    pub schema_id: Option<String>,
+    /// Format the credential is issued in once requested.
+    #[serde(default)]
+    pub format: CredentialFormat,
+    #[serde(default)]
+    pub auto_respond: Option<AutoRespond>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -75,6 +98,77 @@ pub struct SendRequest {
     pub comment: String,
     #[schemars(example = "example_credential_preview")]
     pub credential_preview: CredentialPreview,
+    /// DID of the credential subject, the DID of the connection if unset.
+    #[serde(default)]
+    pub subject_id: Option<String>,
+    /// Id of a registered schema the attributes are validated against.
+    #[serde(default)]
+    pub schema_id: Option<String>,
+    /// `ldp_vc` for a JSON-LD credential, `jwt_vc` for a VC-JWT or `vc+sd-jwt`
+    /// for an SD-JWT.
+    #[serde(default)]
+    pub format: CredentialFormat,
+}
+
+/// The attributes of the preview as JSON object, values holding JSON objects
+/// or arrays are parsed.
+///
+/// Numbers and booleans are kept as the strings of the preview, as the
+/// credential subject holds them, so a schema has to type them as `string`.
+pub fn preview_attributes(preview: &CredentialPreview) -> Value {
+    let attributes = preview
+        .attributes
+        .iter()
+        .map(|attribute| {
+            let value = match attribute.value.trim_start().starts_with(['{', '[']) {
+                true => serde_json::from_str(&attribute.value)
+                    .unwrap_or_else(|_| json!(attribute.value)),
+                false => json!(attribute.value),
+            };
+            (attribute.name.to_string(), value)
+        })
+        .collect::<serde_json::Map<String, Value>>();
+    Value::Object(attributes)
+}
+
+/// Records the message as sent and posts it to the peer.
+///
+/// `update` sets the request's settings on the record. Returns the record and
+/// the message the peer returned.
+#[allow(clippy::too_many_arguments)]
+async fn send_message(
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    connection: &Connection,
+    message: &Message,
+    encrypted: &Value,
+    role: CredentialExchangeRole,
+    state: CredentialExchangeState,
+    update: impl FnOnce(&mut CredentialExchangeRecord),
+) -> Result<(CredentialExchangeRecord, Value), Error> {
+    let previous = exchanges.exchanges.get(&thread_id(message))?;
+    let mut record = exchange::sent(
+        exchanges,
+        issue_credential_events,
+        connection,
+        message,
+        role,
+        state,
+    )
+    .await?;
+    update(&mut record);
+    exchanges
+        .exchanges
+        .insert(&record.thread_id, record.clone())?;
+    let response = crate::exchange::post(
+        &exchanges.exchanges,
+        previous,
+        &record,
+        connection,
+        encrypted,
+    )
+    .await?;
+    Ok((record, response))
 }
 
 pub async fn prepare_proposal_request(
@@ -86,14 +180,11 @@ pub async fn prepare_proposal_request(
         .goal_code("issue-vc".to_string())
         .comment(request.comment)
         .credential_preview(request.credential_preview)
-        .build_propose_credential()
-        .unwrap();
+        .build_propose_credential()?;
     proposal = add_return_route_all_header(proposal);
-    let did_from = wallet.did_iota().unwrap();
+    let did_from = wallet.did_iota().ok_or("wallet has no did")?;
     let keypair = wallet.keypair();
-    let message = sign_and_encrypt(&proposal, &did_from, &did_to, &keypair)
-        .await
-        .unwrap();
+    let message = sign_and_encrypt(&proposal, &did_from, &did_to, &keypair).await?;
     Ok((proposal, message))
 }
 
@@ -101,51 +192,53 @@ pub async fn prepare_proposal_request(
 #[openapi(tag = "issue-credential v2.1")]
 #[post("/issue-credential-2.1/send-proposal", data = "<request>")]
 pub async fn post_send_proposal_2(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    connections: &State<Connections>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
     request: Json<CreateProposalRequest>,
-) -> Result<Json<Value>, Status> {
-    let (did_to, endpoint) = {
-        let connections = connections.connections.lock().await;
-        let connection = connections.get(&request.connection_id).unwrap().clone();
-        (connection.did.to_string(), connection.endpoint)
-    };
+) -> Result<Json<Value>, Error> {
+    let connection = connections.get(&request.connection_id)?;
+    let did_to = connection.did.to_string();
     let request = request.into_inner();
+    let auto_respond = request.auto_respond.clone();
 
-    let (offer, message) = prepare_proposal_request(&wallet.try_lock().unwrap(), did_to, request)
-        .await
-        .unwrap();
+    let (proposal, message) =
+        prepare_proposal_request(&wallet.lock().await, did_to, request).await?;
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post(endpoint.to_string())
-        .json(&message)
-        .send()
-        .await
-        .unwrap();
-    match res.json::<Value>().await.is_ok() {
-        true => Ok(Json(json!(offer))),
-        false => Err(Status::InternalServerError),
-    }
+    send_message(
+        exchanges,
+        issue_credential_events,
+        &connection,
+        &proposal,
+        &message,
+        CredentialExchangeRole::Holder,
+        CredentialExchangeState::ProposalSent,
+        |record| record.auto_respond = auto_respond,
+    )
+    .await?;
+    Ok(Json(json!(proposal)))
 }
 
 pub async fn prepare_offer_request(
     wallet: &Wallet,
     did_to: String,
     request: CreateOfferRequest,
+    thid: Option<&str>,
 ) -> Result<(Message, Value), Box<dyn std::error::Error>> {
     let mut offer = IssueCredentialResponseBuilder::new()
         .goal_code("issue-vc".to_string())
         .comment(request.comment)
         .credential_preview(request.credential_preview)
-        .build_offer_credential()
-        .unwrap();
+        .build_offer_credential()?;
+    if let Some(thid) = thid {
+        offer = with_thread(offer, thid, None)?;
+    }
     offer = add_return_route_all_header(offer);
-    let did_from = wallet.did_iota().unwrap();
+    let did_from = wallet.did_iota().ok_or("wallet has no did")?;
     let keypair = wallet.keypair();
-    let message = sign_and_encrypt(&offer, &did_from, &did_to, &keypair)
-        .await
-        .unwrap();
+    let message = sign_and_encrypt(&offer, &did_from, &did_to, &keypair).await?;
     Ok((offer, message))
 }
 
@@ -153,119 +246,724 @@ pub async fn prepare_offer_request(
 #[openapi(tag = "issue-credential v2.1")]
 #[post("/issue-credential-2.1/send-offer", data = "<request>")]
 pub async fn post_send_offer_2(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    connections: &State<Connections>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
+    schemas: &State<Schemas>,
     request: Json<CreateOfferRequest>,
-) -> Result<Json<Value>, Status> {
-    let (did_to, endpoint) = {
-        let connections = connections.connections.lock().await;
-        let connection = connections.get(&request.connection_id).unwrap().clone();
-        (connection.did.to_string(), connection.endpoint)
-    };
+) -> Result<Json<Value>, Error> {
+    let connection = connections.get(&request.connection_id)?;
+    let did_to = connection.did.to_string();
 
     let request = request.into_inner();
+    let auto_respond = request.auto_respond.clone();
+    let schema_id = request.schema_id.clone();
+    let format = request.format;
+    if let Some(schema_id) = &schema_id {
+        schemas.validate(schema_id, &preview_attributes(&request.credential_preview))?;
+    }
 
-    let (offer, message) = prepare_offer_request(&wallet.try_lock().unwrap(), did_to, request)
-        .await
-        .unwrap();
+    let (offer, message) =
+        prepare_offer_request(&wallet.lock().await, did_to, request, None).await?;
 
-    let client = reqwest::Client::new();
-    match client
-        .post(endpoint.to_string())
-        .json(&message)
-        .send()
-        .await
-    {
-        Ok(_) => Ok(Json(json!(offer))),
-        Err(err) => {
-            println!("{:?}", err);
-            Err(Status::InternalServerError)
-        }
+    send_message(
+        exchanges,
+        issue_credential_events,
+        &connection,
+        &offer,
+        &message,
+        CredentialExchangeRole::Issuer,
+        CredentialExchangeState::OfferSent,
+        |record| {
+            record.auto_respond = auto_respond;
+            record.schema_id = schema_id;
+            record.format = format;
+        },
+    )
+    .await?;
+    Ok(Json(json!(offer)))
+}
+
+/// Template of credential ids if none is configured.
+pub const DEFAULT_CREDENTIAL_ID_TEMPLATE: &str = "urn:uuid:{uuid}";
+
+/// A new credential id from the template, `{uuid}` is replaced by a random uuid.
+pub fn credential_id(template: Option<&str>) -> Result<Url, Error> {
+    let template = template.unwrap_or(DEFAULT_CREDENTIAL_ID_TEMPLATE);
+    let id = template.replace("{uuid}", &Uuid::new_v4().to_string());
+    Url::parse(&id).map_err(|err| Error::Internal(format!("credential id {}: {}", id, err)))
+}
+
+/// The unsigned credential of the request, issued by `did_from` to the
+/// subject of the request or else `did_to`.
+fn build_credential(
+    did_from: &str,
+    did_to: &str,
+    request: &SendRequest,
+    credential_id: Url,
+    status: Option<Status>,
+) -> Result<Credential, Box<dyn std::error::Error>> {
+    let subject_id = request.subject_id.as_deref().unwrap_or(did_to);
+
+    let subject: Subject = Subject::from_json_value(
+        json!({"id": Url::parse(subject_id)?, "attributes": request.credential_preview.attributes}),
+    )?;
+
+    let mut builder = CredentialBuilder::default()
+        .id(credential_id)
+        .issuer(Url::parse(did_from)?)
+        .subject(subject);
+    if let Some(schema_id) = &request.schema_id {
+        builder = builder.credential_schema(Schema::from_json_value(
+            json!({"id": Url::parse(schema_id)?, "type": "JsonSchemaValidator2018"}),
+        )?);
+    }
+    if let Some(status) = status {
+        builder = builder.status(status);
     }
+    Ok(builder.build()?)
 }
 
 pub async fn prepare_issue_credential_request(
     wallet: &Wallet,
     did_to: String,
     request: SendRequest,
+    thid: Option<&str>,
+    credential_id: Url,
+    status: Option<Status>,
 ) -> Result<(Message, Value), Box<dyn std::error::Error>> {
-    let subject_key: KeyPair = KeyPair::new(KeyType::Ed25519).unwrap();
-    let subject_did: IotaDID = IotaDID::new(subject_key.public().as_ref()).unwrap();
-
-    let subject: Subject =
-        Subject::from_json_value(json!({"id": Url::parse(subject_did.as_str()).unwrap(), "attributes": request.credential_preview.attributes}))
-            .unwrap();
+    let did_from = wallet.did_iota().ok_or("wallet has no did")?;
+    let mut credential = build_credential(&did_from, &did_to, &request, credential_id, status)?;
 
-    let mut credential: Credential = CredentialBuilder::default()
-        .id(Url::parse("https://example.edu/credentials/3732").unwrap())
-        .issuer(Url::parse(&did_to).unwrap())
-        .subject(subject)
-        .build()
-        .unwrap();
-
-    wallet
-        .account
-        .as_ref()
-        .unwrap()
-        .sign("sign-0", &mut credential, ProofOptions::default())
-        .await
-        .unwrap();
-    let did_from = wallet.did_iota().unwrap();
+    let account = wallet.account.as_ref().ok_or("wallet has no account")?;
+    let attachment = match request.format {
+        CredentialFormat::LdpVc => {
+            account
+                .sign(
+                    jwt::SIGNING_METHOD,
+                    &mut credential,
+                    ProofOptions::default(),
+                )
+                .await?;
+            serde_json::to_value(&credential)?
+        }
+        CredentialFormat::JwtVc => {
+            json!(jwt::sign(account, &jwt::credential_claims(&credential)?).await?)
+        }
+        CredentialFormat::SdJwtVc => json!(sdjwt::issue(account, &credential).await?),
+    };
     let keypair = wallet.keypair();
 
-    let attachment = serde_json::to_value(&credential).unwrap();
     let mut issue = IssueCredentialResponseBuilder::new()
         .goal_code("issue-vc".to_string())
         .comment(request.comment)
         .credential_preview(request.credential_preview)
         .attachment(attachment)
         .build_issue_credential()?;
+    if let Some(thid) = thid {
+        issue = with_thread(issue, thid, None)?;
+    }
     issue = add_return_route_all_header(issue);
     let request = sign_and_encrypt(&issue, &did_from, &did_to, &keypair).await?;
     Ok((issue, request))
 }
 
+/// Issues the credential with a revocation index and sends it to the
+/// connection, the index is released if the credential is not sent.
+///
+/// Returns the issue message and the message the holder returned.
+#[allow(clippy::too_many_arguments)]
+async fn send_credential(
+    wallet: &Arc<Mutex<Wallet>>,
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    revocations: &Revocations,
+    connection: &Connection,
+    request: SendRequest,
+    thid: Option<&str>,
+    credential_id: Url,
+) -> Result<(Message, Value), Error> {
+    let status = revocation::allocate(revocations, wallet, credential_id.as_str()).await?;
+    let sent = async {
+        let (issue, message) = {
+            let wallet = wallet.lock().await;
+            prepare_issue_credential_request(
+                &wallet,
+                connection.did.to_string(),
+                request,
+                thid,
+                credential_id.clone(),
+                Some(status),
+            )
+            .await?
+        };
+        let (_, ack) = send_message(
+            exchanges,
+            issue_credential_events,
+            connection,
+            &issue,
+            &message,
+            CredentialExchangeRole::Issuer,
+            CredentialExchangeState::CredentialIssued,
+            |_| (),
+        )
+        .await?;
+        Ok::<_, Error>((issue, ack))
+    }
+    .await;
+    if sent.is_err() {
+        revocations.release(credential_id.as_str())?;
+    }
+    sent
+}
+
 /// # Send holder a credential
 #[openapi(tag = "issue-credential v2.1")]
 #[post("/issue-credential-2.1/send", data = "<request>")]
 pub async fn post_send_2(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    connections: &State<Connections>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
+    revocations: &TenantState<Revocations>,
+    config_ext: &State<ConfigExt>,
+    schemas: &State<Schemas>,
     request: Json<SendRequest>,
-) -> Result<Json<Value>, Status> {
-    let (did_to, endpoint) = {
-        let connections = connections.connections.lock().await;
-        let connection = connections.get(&request.connection_id).unwrap().clone();
-        (connection.did.to_string(), connection.endpoint)
-    };
+) -> Result<Json<Value>, Error> {
+    let connection = connections.get(&request.connection_id)?;
     let request = request.into_inner();
+    if let Some(subject_id) = &request.subject_id {
+        parse_subject_id(subject_id)?;
+    }
+    if let Some(schema_id) = &request.schema_id {
+        schemas.validate(schema_id, &preview_attributes(&request.credential_preview))?;
+    }
+    let credential_id = credential_id(config_ext.credential_id_template.as_deref())?;
 
-    let (issue, request) = {
-        let wallet = wallet.try_lock().unwrap();
-        prepare_issue_credential_request(&wallet, did_to, request)
-            .await
-            .unwrap()
+    let (issue, ack) = send_credential(
+        wallet,
+        exchanges,
+        issue_credential_events,
+        revocations,
+        &connection,
+        request,
+        None,
+        credential_id,
+    )
+    .await?;
+    receive_returned_ack(wallet, exchanges, issue_credential_events, &ack).await;
+    Ok(Json(json!(issue)))
+}
+
+fn parse_subject_id(subject_id: &str) -> Result<(), Error> {
+    match subject_id.starts_with("did:") && Url::parse(subject_id).is_ok() {
+        true => Ok(()),
+        false => Err(Error::InvalidDid(subject_id.to_string())),
+    }
+}
+
+/// Completes the exchange with an ack the holder returned on the return route,
+/// or abandons it on a returned problem report.
+async fn receive_returned_ack(
+    wallet: &Arc<Mutex<Wallet>>,
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    response: &Value,
+) {
+    crate::exchange::receive_returned(
+        wallet,
+        response,
+        exchange::ACK,
+        |ack| async move { exchange::receive_ack(exchanges, issue_credential_events, &ack).await },
+        |problem_report| async move {
+            exchange::receive_problem_report(exchanges, issue_credential_events, &problem_report)
+                .await
+        },
+    )
+    .await
+}
+
+/// The credential preview of an exchange, for answering the peer.
+fn record_preview(record: &CredentialExchangeRecord) -> Result<CredentialPreview, Error> {
+    let preview = record.credential_preview.clone().ok_or_else(|| {
+        Error::BadRequest(format!(
+            "credential exchange {} without credential preview",
+            record.thread_id
+        ))
+    })?;
+    serde_json::from_value(preview).map_err(|err| Error::BadRequest(err.to_string()))
+}
+
+fn expect_state(
+    record: &CredentialExchangeRecord,
+    state: CredentialExchangeState,
+) -> Result<(), Error> {
+    match record.state == state {
+        true => Ok(()),
+        false => Err(Error::BadRequest(format!(
+            "credential exchange {} is {:?}, expected {:?}",
+            record.thread_id, record.state, state
+        ))),
+    }
+}
+
+/// Issuer side: answers a received proposal with an offer on its thread.
+pub async fn send_offer(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    record: &CredentialExchangeRecord,
+) -> Result<CredentialExchangeRecord, Error> {
+    expect_state(record, CredentialExchangeState::ProposalReceived)?;
+    let connection = exchange::connection(connections, record)?;
+    let request = CreateOfferRequest {
+        connection_id: connection.id.to_string(),
+        comment: record.comment.clone().unwrap_or_default(),
+        credential_preview: record_preview(record)?,
+        schema_id: record.schema_id.clone(),
+        format: record.format,
+        auto_respond: None,
+    };
+    let (offer, message) = {
+        let wallet = wallet.lock().await;
+        prepare_offer_request(
+            &wallet,
+            connection.did.to_string(),
+            request,
+            Some(&record.thread_id),
+        )
+        .await?
+    };
+    let (record, _) = send_message(
+        exchanges,
+        issue_credential_events,
+        &connection,
+        &offer,
+        &message,
+        CredentialExchangeRole::Issuer,
+        CredentialExchangeState::OfferSent,
+        |_| (),
+    )
+    .await?;
+    Ok(record)
+}
+
+/// Issuer side: answers a received request with the credential on its thread.
+#[allow(clippy::too_many_arguments)]
+pub async fn issue_credential(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    revocations: &Revocations,
+    config_ext: &ConfigExt,
+    schemas: &Schemas,
+    record: &CredentialExchangeRecord,
+) -> Result<CredentialExchangeRecord, Error> {
+    expect_state(record, CredentialExchangeState::RequestReceived)?;
+    let connection = exchange::connection(connections, record)?;
+    let request = SendRequest {
+        connection_id: connection.id.to_string(),
+        comment: record.comment.clone().unwrap_or_default(),
+        credential_preview: record_preview(record)?,
+        subject_id: None,
+        schema_id: record.schema_id.clone(),
+        format: record.format,
+    };
+    if let Some(schema_id) = &request.schema_id {
+        schemas.validate(schema_id, &preview_attributes(&request.credential_preview))?;
+    }
+    let credential_id = credential_id(config_ext.credential_id_template.as_deref())?;
+    let (issue, ack) = send_credential(
+        wallet,
+        exchanges,
+        issue_credential_events,
+        revocations,
+        &connection,
+        request,
+        Some(&record.thread_id),
+        credential_id,
+    )
+    .await?;
+    let mut record = exchanges.get(&record.thread_id)?;
+    if let Some(attachment) = issue.get_attachments().next() {
+        record.credential = attachment
+            .data
+            .base64
+            .as_ref()
+            .and_then(|data| base64::decode(data).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok());
+        exchanges
+            .exchanges
+            .insert(&record.thread_id, record.clone())?;
+    }
+    receive_returned_ack(wallet, exchanges, issue_credential_events, &ack).await;
+    exchanges.get(&record.thread_id)
+}
+
+pub async fn prepare_request_credential(
+    wallet: &Wallet,
+    did_to: String,
+    record: &CredentialExchangeRecord,
+) -> Result<(Message, Value), Box<dyn std::error::Error>> {
+    let mut request = IssueCredentialResponseBuilder::new()
+        .goal_code("issue-vc".to_string())
+        .comment(record.comment.clone().unwrap_or_default())
+        .credential_preview(record_preview(record)?)
+        .build_request_credential()?;
+    request = with_thread(request, &record.thread_id, None)?;
+    request = add_return_route_all_header(request);
+    let did_from = wallet.did_iota().ok_or("wallet has no did")?;
+    let keypair = wallet.keypair();
+    let message = sign_and_encrypt(&request, &did_from, &did_to, &keypair).await?;
+    Ok((request, message))
+}
+
+/// Holder side: answers a received offer with a request for the credential.
+pub async fn send_request(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    record: &CredentialExchangeRecord,
+) -> Result<CredentialExchangeRecord, Error> {
+    expect_state(record, CredentialExchangeState::OfferReceived)?;
+    let connection = exchange::connection(connections, record)?;
+    let (request, message) = {
+        let wallet = wallet.lock().await;
+        prepare_request_credential(&wallet, connection.did.to_string(), record).await?
     };
+    send_message(
+        exchanges,
+        issue_credential_events,
+        &connection,
+        &request,
+        &message,
+        CredentialExchangeRole::Holder,
+        CredentialExchangeState::RequestSent,
+        |_| (),
+    )
+    .await?;
+    exchanges.get(&record.thread_id)
+}
+
+/// # Request the credential of a received offer
+#[openapi(tag = "issue-credential v2.1")]
+#[post("/issue-credential-2.1/records/<thread_id>/send-request")]
+pub async fn post_record_send_request(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
+    thread_id: String,
+) -> Result<Json<CredentialExchangeRecord>, Error> {
+    let record = exchanges.get(&thread_id)?;
+    let record = send_request(
+        wallet,
+        connections,
+        exchanges,
+        issue_credential_events,
+        &record,
+    )
+    .await?;
+    Ok(Json(record))
+}
+
+/// # Send an offer answering a received proposal
+#[openapi(tag = "issue-credential v2.1")]
+#[post("/issue-credential-2.1/records/<thread_id>/send-offer")]
+pub async fn post_record_send_offer(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
+    thread_id: String,
+) -> Result<Json<CredentialExchangeRecord>, Error> {
+    let record = exchanges.get(&thread_id)?;
+    let record = send_offer(
+        wallet,
+        connections,
+        exchanges,
+        issue_credential_events,
+        &record,
+    )
+    .await?;
+    Ok(Json(record))
+}
+
+/// # Issue the credential answering a received request
+#[openapi(tag = "issue-credential v2.1")]
+#[post("/issue-credential-2.1/records/<thread_id>/issue")]
+pub async fn post_record_issue(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
+    revocations: &TenantState<Revocations>,
+    config_ext: &State<ConfigExt>,
+    schemas: &State<Schemas>,
+    thread_id: String,
+) -> Result<Json<CredentialExchangeRecord>, Error> {
+    let record = exchanges.get(&thread_id)?;
+    let record = issue_credential(
+        wallet,
+        connections,
+        exchanges,
+        issue_credential_events,
+        revocations,
+        config_ext,
+        schemas,
+        &record,
+    )
+    .await?;
+    Ok(Json(record))
+}
 
+/// Holder side: stores the credential of an exchange left for the controller
+/// and acknowledges it to the issuer. A credential failing verification is
+/// not stored, the exchange is abandoned and the issuer gets a problem report.
+pub async fn store_credential(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    credentials: &Credentials,
+    exchanges: &CredentialExchanges,
+    issue_credential_events: &Arc<Mutex<IssueCredentialEvents>>,
+    record: &CredentialExchangeRecord,
+) -> Result<CredentialExchangeRecord, Error> {
+    expect_state(record, CredentialExchangeState::CredentialReceived)?;
+    let attachment = record.credential.as_ref().ok_or_else(|| {
+        Error::BadRequest(format!(
+            "credential exchange {} without credential",
+            record.thread_id
+        ))
+    })?;
+    let holder = wallet_did(&*wallet.lock().await)?.to_string();
+    let (credential, jwt, verification) = verify_attachment(attachment, &holder).await?;
+    let mut record = record.clone();
+    if !verification.verified {
+        exchange::set_state(
+            exchanges,
+            issue_credential_events,
+            &mut record,
+            CredentialExchangeState::Abandoned,
+        )
+        .await?;
+        issue_credential_events
+            .lock()
+            .await
+            .send(IssueCredentialEvent::VerificationFailed {
+                from: record.their_did.to_string(),
+                thread_id: record.thread_id.to_string(),
+                errors: verification.errors.clone(),
+            })
+            .await;
+        let problem_report = build_problem_report(
+            "e.p.credential.invalid",
+            &verification.errors.join(", "),
+            &record.thread_id,
+        )?;
+        let sent = match exchange::connection(connections, &record) {
+            Ok(connection) => post_message(wallet, &connection, &problem_report).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = sent {
+            warn!("problem report to {}: {}", record.their_did, err);
+        }
+        return Err(Error::BadRequest(format!(
+            "credential of exchange {} is invalid: {}",
+            record.thread_id,
+            verification.errors.join(", ")
+        )));
+    }
+    credentials.insert(credential, Some(verification), jwt)?;
+
+    let connection = exchange::connection(connections, &record)?;
+    let ack = exchange::build_ack(&record.thread_id)?;
+    post_message(wallet, &connection, &ack).await?;
+    exchange::set_state(
+        exchanges,
+        issue_credential_events,
+        &mut record,
+        CredentialExchangeState::Done,
+    )
+    .await?;
+    Ok(record)
+}
+
+/// Encrypts the message for the connection and posts it, the response is
+/// ignored.
+async fn post_message(
+    wallet: &Arc<Mutex<Wallet>>,
+    connection: &Connection,
+    message: &Message,
+) -> Result<(), Error> {
+    let encrypted = {
+        let wallet = wallet.lock().await;
+        let did_from = wallet_did(&wallet)?.to_string();
+        sign_and_encrypt(message, &did_from, &connection.did, &wallet.keypair()).await?
+    };
     let client = reqwest::Client::new();
-    let res = client
-        .post(endpoint.to_string())
-        .json(&request)
+    client
+        .post(connection.endpoint.to_string())
+        .json(&encrypted)
         .send()
-        .await
-        .unwrap();
-    match res.json::<Value>().await.is_ok() {
-        true => Ok(Json(json!(issue))),
-        false => Err(Status::InternalServerError),
-    }
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// # Store the credential of a received issuance
+#[openapi(tag = "issue-credential v2.1")]
+#[post("/issue-credential-2.1/records/<thread_id>/store")]
+pub async fn post_record_store(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    credentials: &TenantState<Credentials>,
+    exchanges: &TenantState<CredentialExchanges>,
+    issue_credential_events: &TenantState<Arc<Mutex<IssueCredentialEvents>>>,
+    thread_id: String,
+) -> Result<Json<CredentialExchangeRecord>, Error> {
+    let record = exchanges.get(&thread_id)?;
+    let record = store_credential(
+        wallet,
+        connections,
+        credentials,
+        exchanges,
+        issue_credential_events,
+        &record,
+    )
+    .await?;
+    Ok(Json(record))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connection::tests::connect;
+    use crate::error::Problem;
     use crate::test_rocket;
+    use crate::wallet::parse_did;
+    use crate::wallet::tests::get_did;
     use crate::Config;
     use crate::Wallet;
+    use futures::StreamExt;
+    use identity_iota::credential::RevocationBitmapStatus;
+    use identity_iota::did::DID;
+    use identity_iota::iota_core::IotaDIDUrl;
+    use pharos::{Channel, Observable};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client;
+
+    #[test]
+    fn test_preview_attributes() {
+        let preview = CredentialPreview {
+            type_: "https://didcomm.org/issue-credential/2.1/credential-preview".to_string(),
+            attributes: [
+                ("name", "Alice"),
+                ("GPA", "4.0"),
+                ("member", "true"),
+                ("degree", r#"{"type": "BachelorDegree"}"#),
+                ("broken", "{"),
+            ]
+            .iter()
+            .map(|(name, value)| CredentialAttribute::new(name.to_string(), value.to_string()))
+            .collect(),
+        };
+        assert_eq!(
+            preview_attributes(&preview),
+            json!({
+                "name": "Alice",
+                "GPA": "4.0",
+                "member": "true",
+                "degree": {"type": "BachelorDegree"},
+                "broken": "{",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_request() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let did = get_did(&client).await.unwrap();
+
+        let offer = IssueCredentialResponseBuilder::new()
+            .goal_code("issue-vc".to_string())
+            .comment("offer".to_string())
+            .credential_preview(example_credential_preview())
+            .build_offer_credential()
+            .unwrap()
+            .from(&did)
+            .to(&[&did]);
+        let response = client.post("/").json(&offer).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let thread_id = offer.get_didcomm_header().id.to_string();
+
+        let response = client
+            .post(format!("/issue-credential-2.1/records/{}/issue", thread_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // there is no connection to the issuer
+        let response = client
+            .post(format!(
+                "/issue-credential-2.1/records/{}/send-request",
+                thread_id
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .post("/issue-credential-2.1/records/unknown/send-request")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_send_invalid_attributes() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+        let connection = connect(&client).await.unwrap();
+
+        let response = client
+            .post("/schemas")
+            .header(ContentType::JSON)
+            .body(include_str!("../assets/degree_schema.json"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let request = SendRequest {
+            connection_id: connection.id.to_string(),
+            comment: "".to_string(),
+            credential_preview: example_credential_preview(),
+            subject_id: None,
+            schema_id: Some("https://example.org/examples/degree.json".to_string()),
+            format: Default::default(),
+        };
+        let response = client
+            .post("/issue-credential-2.1/send")
+            .json(&request)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let problem: Problem = response.into_json().await.unwrap();
+        assert!(!problem.errors.is_empty());
+    }
 
     #[tokio::test]
     async fn test_prepare_issue_credential_request() {
@@ -277,13 +975,88 @@ mod tests {
             connection_id: "".to_string(),
             comment: "".to_string(),
             credential_preview: example_credential_preview(),
+            subject_id: None,
+            schema_id: None,
+            format: Default::default(),
+        };
+        let did_from = wallet.did_iota().unwrap();
+        let did_to = "did:iota:H3C2AVvLMv6gmMNam3uVAjZpfkcJCwDwnZn6z3wXmqPV";
+        let credential_id = credential_id(Some("https://example.edu/credentials/{uuid}")).unwrap();
+        let credential =
+            build_credential(&did_from, did_to, &request, credential_id.clone(), None).unwrap();
+        assert_eq!(credential.id, Some(credential_id.clone()));
+        assert_eq!(credential.issuer.url().as_str(), did_from.as_str());
+        assert_eq!(
+            credential.credential_subject.first().unwrap().id,
+            Some(Url::parse(did_to).unwrap())
+        );
+
+        // the message is encrypted for a did on the ledger, our own
+        let service = parse_did(&did_from)
+            .unwrap()
+            .to_url()
+            .join(format!("#{}", revocation::REVOCATION_SERVICE))
+            .unwrap();
+        let status: Status = RevocationBitmapStatus::new(service.clone(), 3).into();
+        let (message, _value) = prepare_issue_credential_request(
+            &wallet,
+            did_from.to_string(),
+            request,
+            None,
+            credential_id.clone(),
+            Some(status),
+        )
+        .await
+        .unwrap();
+        let attachment = message.get_attachments().next().unwrap();
+        let credential: Credential = serde_json::from_slice(
+            &base64::decode(attachment.data.base64.as_ref().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(credential.id, Some(credential_id));
+        assert_eq!(credential.issuer.url().as_str(), did_from.as_str());
+        let status =
+            RevocationBitmapStatus::try_from(credential.credential_status.unwrap()).unwrap();
+        assert_eq!(status.index().unwrap(), 3);
+        let id: IotaDIDUrl = status.id().unwrap();
+        assert_eq!(id, service);
+    }
+
+    #[tokio::test]
+    async fn test_prepare_jwt_credential() {
+        let rocket = test_rocket().await;
+        let figment = rocket.figment();
+        let config: Config = figment.extract().expect("config");
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        let request = SendRequest {
+            connection_id: "".to_string(),
+            comment: "".to_string(),
+            credential_preview: example_credential_preview(),
+            subject_id: None,
+            schema_id: None,
+            format: CredentialFormat::JwtVc,
         };
         let did_to = wallet.did_iota().unwrap();
-        let (message, _value) =
-            prepare_issue_credential_request(&wallet, did_to.to_string(), request)
-                .await
-                .unwrap();
-        assert!(message.get_attachments().next().is_some());
+        let credential_id = credential_id(None).unwrap();
+        let (message, _value) = prepare_issue_credential_request(
+            &wallet,
+            did_to.to_string(),
+            request,
+            None,
+            credential_id.clone(),
+            None,
+        )
+        .await
+        .unwrap();
+        let attachment = message.get_attachments().next().unwrap();
+        let vc: String = serde_json::from_slice(
+            &base64::decode(attachment.data.base64.as_ref().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(jwt::is_jwt(&vc));
+        let credential = jwt::decode_credential(&vc).unwrap();
+        assert_eq!(credential.id, Some(credential_id));
+        assert!(credential.proof.is_none());
     }
 
     #[tokio::test]
@@ -296,9 +1069,12 @@ mod tests {
             connection_id: "".to_string(),
             comment: "".to_string(),
             credential_preview: example_credential_preview(),
+            schema_id: None,
+            format: Default::default(),
+            auto_respond: None,
         };
         let did_to = wallet.did_iota().unwrap();
-        let (message, _value) = prepare_offer_request(&wallet, did_to.to_string(), request)
+        let (message, _value) = prepare_offer_request(&wallet, did_to.to_string(), request, None)
             .await
             .unwrap();
         assert!(message
@@ -318,6 +1094,7 @@ mod tests {
             connection_id: "".to_string(),
             comment: "".to_string(),
             credential_preview: example_credential_preview(),
+            auto_respond: None,
         };
         let did_to = wallet.did_iota().unwrap();
         let (message, _value) = prepare_proposal_request(&wallet, did_to.to_string(), request)
@@ -329,4 +1106,76 @@ mod tests {
             .next()
             .is_some());
     }
+
+    #[tokio::test]
+    async fn test_store_tampered_credential() {
+        let rocket = test_rocket().await;
+        let config: Config = rocket.figment().extract().expect("config");
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        let did = wallet_did(&wallet).unwrap().to_string();
+        let mut credential: Credential = CredentialBuilder::default()
+            .id(Url::parse("urn:uuid:1").unwrap())
+            .issuer(Url::parse(&did).unwrap())
+            .subject(Subject::from_json_value(json!({"id": did, "GPA": "2.0"})).unwrap())
+            .build()
+            .unwrap();
+        wallet
+            .account
+            .as_ref()
+            .unwrap()
+            .sign(
+                jwt::SIGNING_METHOD,
+                &mut credential,
+                ProofOptions::default(),
+            )
+            .await
+            .unwrap();
+        let mut attachment = serde_json::to_value(&credential).unwrap();
+        attachment["credentialSubject"]["GPA"] = json!("4.0");
+
+        let wallet = Arc::new(Mutex::new(wallet));
+        let credentials = Credentials::default();
+        let exchanges = CredentialExchanges::default();
+        let mut events = IssueCredentialEvents::new();
+        let mut observed = events
+            .observe(Channel::Bounded(3).into())
+            .await
+            .expect("observe");
+        let issue_credential_events = Arc::new(Mutex::new(events));
+        let mut record = CredentialExchangeRecord::new(
+            "thread",
+            &did,
+            CredentialExchangeRole::Holder,
+            CredentialExchangeState::CredentialReceived,
+        );
+        record.credential = Some(attachment);
+        exchanges
+            .exchanges
+            .insert("thread", record.clone())
+            .unwrap();
+
+        let stored = store_credential(
+            &wallet,
+            &Connections::default(),
+            &credentials,
+            &exchanges,
+            &issue_credential_events,
+            &record,
+        )
+        .await;
+        assert!(matches!(stored, Err(Error::BadRequest(_))));
+        assert!(credentials.get_record("urn:uuid:1").is_err());
+        assert_eq!(
+            exchanges.get("thread").unwrap().state,
+            CredentialExchangeState::Abandoned
+        );
+        observed.next().await.unwrap();
+        match observed.next().await.unwrap() {
+            IssueCredentialEvent::VerificationFailed { from, errors, .. } => {
+                assert_eq!(from, did);
+                assert!(!errors.is_empty());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
 }
@@ -1,4 +1,9 @@
+use crate::auth::AdminAuth;
+use crate::error::{Error, Result};
+use crate::storage::{MemoryStorage, Record, Storage, Store};
+use crate::tenant::TenantState;
 use identity_iota::core::FromJson;
+use identity_iota::core::Timestamp;
 use identity_iota::core::Url;
 use identity_iota::credential::Credential;
 use identity_iota::credential::Subject;
@@ -6,31 +11,182 @@ use identity_iota::did::DID;
 use identity_iota::iota_core::IotaDID;
 use identity_iota::prelude::KeyPair;
 use identity_iota::prelude::*;
-use rocket::State;
-use rocket::{get, serde::json::Json};
+use rocket::http::Status;
+use rocket::{delete, get, put, serde::json::Json};
 use rocket_okapi::okapi::schemars::{self, JsonSchema};
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 pub mod events;
+pub mod exchange;
 pub mod issue;
+pub mod verification;
 
 pub use events::IssueCredentialEvent;
 pub use events::IssueCredentialEvents;
+pub use exchange::{CredentialExchangeRecord, CredentialExchanges};
+pub use verification::{CredentialRecord, CredentialVerification};
+
+impl Record for Credential {}
 
-#[derive(Default)]
 pub struct Credentials {
-    pub credentials: Arc<Mutex<HashMap<String, Credential>>>,
+    pub credentials: Store<CredentialRecord>,
+}
+
+impl Credentials {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Credentials {
+            credentials: Store::new(storage, "credentials"),
+        }
+    }
+
+    /// Fetches a credential, unknown ids are an `Error::CredentialNotFound`.
+    pub fn get(&self, credential_id: &str) -> Result<Credential> {
+        Ok(self.get_record(credential_id)?.credential)
+    }
+
+    pub fn get_record(&self, credential_id: &str) -> Result<CredentialRecord> {
+        self.credentials
+            .get(credential_id)?
+            .ok_or_else(|| Error::CredentialNotFound(credential_id.to_string()))
+    }
+
+    /// Stores the credential with its verification and the VC-JWT or SD-JWT
+    /// it was received as under its id.
+    pub fn insert(
+        &self,
+        credential: Credential,
+        verification: Option<CredentialVerification>,
+        jwt: Option<String>,
+    ) -> Result<()> {
+        let credential_id = credential
+            .id
+            .as_ref()
+            .ok_or_else(|| Error::BadRequest("credential without id".to_string()))?
+            .to_string();
+        self.credentials.insert(
+            &credential_id,
+            CredentialRecord {
+                credential,
+                verification,
+                tags: Vec::new(),
+                jwt,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct CredentialsResponse {
     #[schemars(example = "example_credentials")]
     result: Vec<Value>,
+    /// Number of matching credentials before pagination.
+    total: usize,
+}
+
+/// Filters of the credential query, unset filters match all credentials.
+#[derive(Default)]
+pub struct CredentialQuery {
+    pub credential_type: Option<String>,
+    pub issuer: Option<String>,
+    pub schema_id: Option<String>,
+    /// Name and expected value of a subject attribute.
+    pub attribute: Option<(String, Option<String>)>,
+    pub valid: Option<bool>,
+    pub tag: Option<String>,
+}
+
+impl CredentialQuery {
+    pub fn matches(&self, record: &CredentialRecord) -> bool {
+        let credential = &record.credential;
+        if let Some(credential_type) = &self.credential_type {
+            if !credential
+                .types
+                .iter()
+                .any(|type_| type_ == credential_type)
+            {
+                return false;
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if credential.issuer.url().as_str() != issuer {
+                return false;
+            }
+        }
+        if let Some(schema_id) = &self.schema_id {
+            if !credential
+                .credential_schema
+                .iter()
+                .any(|schema| schema.id.as_str() == schema_id)
+            {
+                return false;
+            }
+        }
+        if let Some((name, value)) = &self.attribute {
+            let found = credential
+                .credential_subject
+                .iter()
+                .filter_map(|subject| subject_attribute(subject, name))
+                .any(|found| match (value, found) {
+                    (None, _) => true,
+                    (Some(value), Value::String(found)) => &found == value,
+                    (Some(value), found) => &found.to_string() == value,
+                });
+            if !found {
+                return false;
+            }
+        }
+        if let Some(valid) = self.valid {
+            if is_valid(record) != valid {
+                return false;
+            }
+        }
+        if let Some(tag) = &self.tag {
+            if !record.tags.contains(tag) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// An attribute of the subject, either a property or an entry of the
+/// `attributes` list of issued credential previews.
+fn subject_attribute(subject: &Subject, name: &str) -> Option<Value> {
+    if let Some(value) = subject.properties.get(name) {
+        return Some(value.clone());
+    }
+    subject
+        .properties
+        .get("attributes")?
+        .as_array()?
+        .iter()
+        .find(|attribute| attribute["name"].as_str() == Some(name))
+        .map(|attribute| attribute["value"].clone())
+}
+
+/// Verified on receipt and not expired since.
+fn is_valid(record: &CredentialRecord) -> bool {
+    let verified = record
+        .verification
+        .as_ref()
+        .map(|verification| verification.verified)
+        .unwrap_or(false);
+    let expired = record
+        .credential
+        .expiration_date
+        .map(|expiration_date| expiration_date < Timestamp::now_utc())
+        .unwrap_or(false);
+    verified && !expired
 }
 
 pub fn example_credential() -> Value {
@@ -56,27 +212,124 @@ pub fn example_credential() -> Value {
 }
 
 pub fn example_credentials() -> Vec<Value> {
-    vec![example_credential()]
+    vec![json!({ "credential": example_credential(), "verification": null })]
 }
 
+/// # Query stored credentials
+///
+/// Filters by type, issuer, schema, subject attribute, validity and tag.
+/// Without `value` credentials having the `attribute` match.
 #[openapi(tag = "credentials")]
-#[get("/credentials")]
-pub async fn get_all_credentials(credentials: &State<Credentials>) -> Json<CredentialsResponse> {
-    let lock = credentials.credentials.lock().await;
-    let result: Vec<Value> = lock
-        .values()
-        .cloned()
-        .map(|c| serde_json::to_value(&c).unwrap())
+#[get(
+    "/credentials?<credential_type>&<issuer>&<schema_id>&<attribute>&<value>&<valid>&<tag>&<offset>&<limit>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_all_credentials(
+    _auth: AdminAuth,
+    credentials: &TenantState<Credentials>,
+    credential_type: Option<String>,
+    issuer: Option<String>,
+    schema_id: Option<String>,
+    attribute: Option<String>,
+    value: Option<String>,
+    valid: Option<bool>,
+    tag: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Json<CredentialsResponse>> {
+    let query = CredentialQuery {
+        credential_type,
+        issuer,
+        schema_id,
+        attribute: attribute.map(|attribute| (attribute, value)),
+        valid,
+        tag,
+    };
+    let mut records: Vec<CredentialRecord> = credentials
+        .credentials
+        .values()?
+        .into_iter()
+        .filter(|record| query.matches(record))
         .collect();
-    Json(CredentialsResponse { result })
+    records.sort_by(|a, b| a.credential.issuance_date.cmp(&b.credential.issuance_date));
+    let total = records.len();
+    let result: Vec<Value> = records
+        .iter()
+        .skip(offset.unwrap_or(0))
+        .take(limit.unwrap_or(usize::MAX))
+        .map(serde_json::to_value)
+        .collect::<std::result::Result<_, _>>()
+        .map_err(Error::internal)?;
+    Ok(Json(CredentialsResponse { result, total }))
+}
+
+/// # Fetch a stored credential
+#[openapi(tag = "credentials")]
+#[get("/credentials/<credential_id>")]
+pub async fn get_credential(
+    _auth: AdminAuth,
+    credentials: &TenantState<Credentials>,
+    credential_id: String,
+) -> Result<Json<CredentialRecord>> {
+    Ok(Json(credentials.get_record(&credential_id)?))
+}
+
+/// # Remove a stored credential
+#[openapi(tag = "credentials")]
+#[delete("/credentials/<credential_id>")]
+pub async fn delete_credential(
+    _auth: AdminAuth,
+    credentials: &TenantState<Credentials>,
+    credential_id: String,
+) -> Result<Status> {
+    credentials
+        .credentials
+        .remove(&credential_id)?
+        .ok_or(Error::CredentialNotFound(credential_id))?;
+    Ok(Status::Ok)
+}
+
+/// # Replace the tags of a stored credential
+#[openapi(tag = "credentials")]
+#[put("/credentials/<credential_id>/tags", data = "<tags>")]
+pub async fn put_credential_tags(
+    _auth: AdminAuth,
+    credentials: &TenantState<Credentials>,
+    credential_id: String,
+    tags: Json<Vec<String>>,
+) -> Result<Json<CredentialRecord>> {
+    let mut record = credentials.get_record(&credential_id)?;
+    record.tags = tags.into_inner();
+    credentials
+        .credentials
+        .insert(&credential_id, record.clone())?;
+    Ok(Json(record))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::tenant::Tenants;
     use crate::test_rocket;
+    use identity_iota::credential::CredentialBuilder;
     use rocket::http::Status;
     use rocket::local::asynchronous::Client;
-    use serde_json::Value;
+
+    fn credential(id: &str, degree: &str) -> Credential {
+        CredentialBuilder::default()
+            .id(Url::parse(id).unwrap())
+            .type_("UniversityDegreeCredential")
+            .issuer(Url::parse("did:iota:issuer").unwrap())
+            .subject(
+                Subject::from_json_value(json!({
+                    "id": "did:iota:holder",
+                    "attributes": [{"name": "degree", "value": degree}]
+                }))
+                .unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
 
     #[tokio::test]
     async fn test_credentials() {
@@ -89,4 +342,65 @@ mod tests {
         let credentials = response.get("result").unwrap().as_array().unwrap();
         assert_eq!(credentials.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_query_credentials() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+        let credentials = &client
+            .rocket()
+            .state::<Tenants>()
+            .unwrap()
+            .base()
+            .credentials;
+        credentials
+            .insert(credential("urn:uuid:1", "BachelorDegree"), None, None)
+            .unwrap();
+        credentials
+            .insert(credential("urn:uuid:2", "MasterDegree"), None, None)
+            .unwrap();
+
+        let response = client
+            .get("/credentials?attribute=degree&value=MasterDegree")
+            .dispatch()
+            .await;
+        let response: CredentialsResponse = response.into_json().await.unwrap();
+        assert_eq!(response.total, 1);
+        assert_eq!(response.result[0]["credential"]["id"], "urn:uuid:2");
+
+        let response = client
+            .get("/credentials?credential_type=UniversityDegreeCredential&offset=1&limit=1")
+            .dispatch()
+            .await;
+        let response: CredentialsResponse = response.into_json().await.unwrap();
+        assert_eq!(response.total, 2);
+        assert_eq!(response.result.len(), 1);
+
+        let response = client.get("/credentials?valid=true").dispatch().await;
+        let response: CredentialsResponse = response.into_json().await.unwrap();
+        assert_eq!(response.total, 0);
+
+        let response = client
+            .put("/credentials/urn:uuid:1/tags")
+            .json(&vec!["diploma"])
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/credentials?tag=diploma").dispatch().await;
+        let response: CredentialsResponse = response.into_json().await.unwrap();
+        assert_eq!(response.total, 1);
+
+        let response = client.get("/credentials/urn:uuid:1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let record: CredentialRecord = response.into_json().await.unwrap();
+        assert_eq!(record.tags, vec!["diploma".to_string()]);
+
+        let response = client.delete("/credentials/urn:uuid:1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/credentials/urn:uuid:1").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.delete("/credentials/urn:uuid:1").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
 }
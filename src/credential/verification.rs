@@ -0,0 +1,178 @@
+use crate::error::{Error, Result};
+use crate::jwt;
+use crate::sdjwt::{self, SdJwt};
+use crate::storage::{self, Record};
+use identity_iota::client::{
+    CredentialValidationOptions, CredentialValidator, FailFast, Resolver, StatusCheck,
+};
+use identity_iota::core::Timestamp;
+use identity_iota::credential::Credential;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Result of validating a received credential.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CredentialVerification {
+    pub verified: bool,
+    /// Failed checks, empty if verified.
+    pub errors: Vec<String>,
+    pub verified_at: String,
+}
+
+/// A stored credential with the result of its verification on receipt.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CredentialRecord {
+    #[schemars(with = "Value")]
+    pub credential: Credential,
+    /// Unset for credentials stored before they were verified.
+    pub verification: Option<CredentialVerification>,
+    /// Tags set by the controller.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The VC-JWT or SD-JWT the credential was received as.
+    #[serde(default)]
+    pub jwt: Option<String>,
+}
+
+impl Record for CredentialRecord {
+    const VERSION: u32 = 2;
+
+    fn migrate(version: u32, value: Value) -> Result<Value, storage::Error> {
+        match version {
+            1 => Ok(json!({ "credential": value, "verification": null, "tags": [] })),
+            _ => Ok(value),
+        }
+    }
+}
+
+/// Validates issuer, signature, expiry and revocation status of the
+/// credential and that it is issued to the holder.
+pub async fn verify_credential(credential: &Credential, holder: &str) -> CredentialVerification {
+    let mut errors = Vec::new();
+    match Resolver::new().await {
+        Ok(resolver) => match resolver.resolve_credential_issuer(credential).await {
+            Ok(issuer) => {
+                if let Err(err) = CredentialValidator::validate(
+                    credential,
+                    &issuer,
+                    &CredentialValidationOptions::default(),
+                    FailFast::AllErrors,
+                ) {
+                    errors.extend(err.validation_errors.iter().map(ToString::to_string));
+                }
+            }
+            Err(err) => errors.push(format!("issuer: {}", err)),
+        },
+        Err(err) => errors.push(format!("resolver: {}", err)),
+    }
+    errors.extend(check_holder(credential, holder));
+    verification(errors)
+}
+
+/// Validates the signature of the VC-JWT, expiry and revocation status of
+/// its credential and that it is issued to the holder.
+pub async fn verify_jwt_credential(
+    jwt: &str,
+    credential: &Credential,
+    holder: &str,
+) -> CredentialVerification {
+    let mut errors = Vec::new();
+    if let Err(err) = jwt::verify(jwt).await {
+        errors.push(err.to_string());
+    }
+    if let Err(err) =
+        CredentialValidator::check_expires_on_or_after(credential, Timestamp::now_utc())
+    {
+        errors.push(err.to_string());
+    }
+    match Resolver::new().await {
+        Ok(resolver) => match resolver.resolve_credential_issuer(credential).await {
+            Ok(issuer) => {
+                if let Err(err) =
+                    CredentialValidator::check_status(credential, &[issuer], StatusCheck::Strict)
+                {
+                    errors.push(err.to_string());
+                }
+            }
+            Err(err) => errors.push(format!("issuer: {}", err)),
+        },
+        Err(err) => errors.push(format!("resolver: {}", err)),
+    }
+    errors.extend(check_holder(credential, holder));
+    verification(errors)
+}
+
+/// Decodes a received credential attachment, a JSON-LD credential, a VC-JWT
+/// or an SD-JWT, and verifies it. Returns the credential, the jwt and the result.
+pub async fn verify_attachment(
+    attachment: &Value,
+    holder: &str,
+) -> Result<(Credential, Option<String>, CredentialVerification)> {
+    match attachment.as_str() {
+        Some(sd_jwt) if sdjwt::is_sd_jwt(sd_jwt) => {
+            let credential = sdjwt::decode(sd_jwt)?;
+            let issuer_jwt = SdJwt::parse(sd_jwt)?.jwt;
+            let verification = verify_jwt_credential(&issuer_jwt, &credential, holder).await;
+            Ok((credential, Some(sd_jwt.to_string()), verification))
+        }
+        Some(jwt) if jwt::is_jwt(jwt) => {
+            let credential = jwt::decode_credential(jwt)?;
+            let verification = verify_jwt_credential(jwt, &credential, holder).await;
+            Ok((credential, Some(jwt.to_string()), verification))
+        }
+        _ => {
+            let credential: Credential = serde_json::from_value(attachment.clone())
+                .map_err(|err| Error::BadRequest(err.to_string()))?;
+            let verification = verify_credential(&credential, holder).await;
+            Ok((credential, None, verification))
+        }
+    }
+}
+
+fn check_holder(credential: &Credential, holder: &str) -> Option<String> {
+    let issued_to_holder = credential
+        .credential_subject
+        .iter()
+        .any(|subject| subject.id.as_ref().map(|id| id.as_str()) == Some(holder));
+    match issued_to_holder {
+        true => None,
+        false => Some(format!("credential subject is not {}", holder)),
+    }
+}
+
+fn verification(errors: Vec<String>) -> CredentialVerification {
+    CredentialVerification {
+        verified: errors.is_empty(),
+        errors,
+        verified_at: Timestamp::now_utc().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, Storage, Store};
+    use identity_iota::core::{FromJson, Url};
+    use identity_iota::credential::{CredentialBuilder, Subject};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_migrate() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let credential: Credential = CredentialBuilder::default()
+            .id(Url::parse("https://example.edu/credentials/3732").unwrap())
+            .issuer(Url::parse("did:iota:issuer").unwrap())
+            .subject(Subject::from_json_value(json!({"id": "did:iota:holder"})).unwrap())
+            .build()
+            .unwrap();
+        let old: Store<Credential> = Store::new(storage.clone(), "credentials");
+        old.insert("1", credential.clone()).unwrap();
+
+        let store: Store<CredentialRecord> = Store::new(storage, "credentials");
+        assert_eq!(store.migrate().unwrap(), 1);
+        let record = store.get("1").unwrap().unwrap();
+        assert_eq!(record.credential, credential);
+        assert!(record.verification.is_none());
+    }
+}
@@ -28,7 +28,10 @@ impl DidComm for Client {
         endpoint: &str,
         body: &Value,
     ) -> Result<reqwest::Response, reqwest::Error> {
-        self.request(endpoint, body).send().await
+        self.request(endpoint, body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
     }
 }
 
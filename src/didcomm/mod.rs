@@ -1,9 +1,21 @@
-use crate::connection::{invitation::Invitation, Connections, Termination, TerminationResponse};
-use crate::credential::{Credentials, IssueCredentialEvent, IssueCredentialEvents};
-use crate::message::{MessageEvent, MessageEvents};
-use crate::ping::{PingEvent, PingEvents};
-use crate::presentation::{PresentProofEvent, PresentProofEvents};
-use crate::wallet::Wallet;
+use crate::connection::{didexchange, invitation::Invitation, reuse, termination};
+use crate::credential::exchange::{
+    self, CredentialExchangeRecord, CredentialExchangeRole, CredentialExchangeState,
+};
+use crate::credential::verification::verify_attachment;
+use crate::credential::{issue, IssueCredentialEvent};
+use crate::error::Error;
+use crate::message::MessageEvent;
+use crate::ping::PingEvent;
+use crate::presentation::request::PresentationRequest;
+use crate::presentation::verification::verify_presentation;
+use crate::presentation::{
+    self, PresentProofEvent, PresentationExchangeRole, PresentationExchangeState,
+};
+use crate::schema::Schemas;
+use crate::tenant::{Agent, Tenants};
+use crate::wallet::wallet_did;
+use crate::ConfigExt;
 use async_trait::async_trait;
 use base64::decode;
 use did_key::KeyMaterial;
@@ -14,8 +26,6 @@ use didcomm_rs::{
     Message,
 };
 use identity_iota::client::{ResolvedIotaDocument, Resolver};
-use identity_iota::credential::Credential;
-use identity_iota::credential::Presentation;
 use identity_iota::did::MethodScope;
 use identity_iota::iota_core::{IotaDID, IotaVerificationMethod};
 use identity_iota::prelude::{KeyPair, KeyType};
@@ -24,10 +34,11 @@ use rocket::http::Status;
 use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::openapi;
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use uuid::Uuid;
 
 pub mod client;
 #[cfg(test)]
@@ -50,144 +61,653 @@ pub fn didcomm_options() -> Status {
     Status::Ok
 }
 
-#[allow(clippy::too_many_arguments)]
 #[openapi(tag = "didcomm")]
 #[post("/", format = "any", data = "<body>")]
 pub async fn post_endpoint(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    connections: &State<Connections>,
-    credentials: &State<Credentials>,
-    ping_events: &State<Arc<Mutex<PingEvents>>>,
-    issue_credential_events: &State<Arc<Mutex<IssueCredentialEvents>>>,
-    message_events: &State<Arc<Mutex<MessageEvents>>>,
-    present_proof_events: &State<Arc<Mutex<PresentProofEvents>>>,
+    tenants: &State<Tenants>,
+    config_ext: &State<ConfigExt>,
+    schemas: &State<Schemas>,
     body: Json<Value>,
-) -> Result<Json<Value>, Status> {
-    let body_str = serde_json::to_string(&body.into_inner()).unwrap();
+) -> Result<Json<Value>, Error> {
+    let body = body.into_inner();
+    let body_str = serde_json::to_string(&body).map_err(Error::internal)?;
+    let (agent, received) = route(tenants, &body, &body_str).await?;
     let (my_did, private_key) = {
-        let wallet = wallet.try_lock().unwrap();
-        let my_did = wallet.did_iota().unwrap();
+        let wallet = agent.wallet.lock().await;
+        let my_did = wallet_did(&wallet)?.to_string();
         let keypair = wallet.keypair().private_key_bytes();
         (my_did, keypair)
     };
-    let received: Message = match receive(&body_str, &private_key, None).await {
-        Ok(received) => received,
-        Err(_) => return Err(Status::BadRequest),
-    };
+    let wallet = &agent.wallet;
+    let connections = &agent.connections;
+    let invitations = &agent.invitations;
+    let credentials = &agent.credentials;
+    let exchanges = &agent.exchanges;
+    let presentation_exchanges = &agent.presentation_exchanges;
+    let connection_events = &agent.connection_events;
+    let ping_events = &agent.ping_events;
+    let issue_credential_events = &agent.issue_credential_events;
+    let message_events = &agent.message_events;
+    let present_proof_events = &agent.present_proof_events;
     match received.get_didcomm_header().m_type.as_str() {
         "https://didcomm.org/out-of-band/2.0/invitation" => {
+            let body = received
+                .get_body()
+                .map_err(|err| Error::BadRequest(format!("{:?}", err)))?;
             let invitation: Invitation =
-                serde_json::from_str(&received.get_body().unwrap()).unwrap();
+                serde_json::from_str(&body).map_err(|err| Error::BadRequest(err.to_string()))?;
             info!("invitation = {:?}", invitation.id);
             Ok(Json(json!({})))
         }
+        didexchange::REQUEST => {
+            match didexchange::receive_request(
+                wallet,
+                connections,
+                invitations,
+                connection_events,
+                &received,
+            )
+            .await
+            {
+                Ok(response) => Ok(Json(response)),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
+        reuse::HANDSHAKE_REUSE => {
+            match reuse::receive_reuse(
+                wallet,
+                connections,
+                invitations,
+                connection_events,
+                &received,
+            )
+            .await
+            {
+                Ok(response) => Ok(Json(response)),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
+        reuse::HANDSHAKE_REUSE_ACCEPTED => {
+            match reuse::receive_reuse_accepted(connections, connection_events, &received).await {
+                Ok(_) => Ok(Json(json!({}))),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
+        didexchange::COMPLETE => {
+            match didexchange::receive_complete(connections, connection_events, &received).await {
+                Ok(_) => Ok(Json(json!({}))),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
+        didexchange::PROBLEM_REPORT => {
+            match didexchange::receive_problem_report(connections, connection_events, &received)
+                .await
+            {
+                Ok(_) => Ok(Json(json!({}))),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
         "https://didcomm.org/trust-ping/2.0/ping" => {
-            let did_to = received.get_didcomm_header().from.clone().unwrap();
+            let did_to = sender(&received)?;
             let response = TrustPingResponseBuilder::new()
                 .message(received.clone())
                 .build()
-                .unwrap();
+                .map_err(Error::internal)?;
             ping_events
-                .try_lock()
-                .unwrap()
-                .send(PingEvent::Received(
-                    received
-                        .get_didcomm_header()
-                        .from
-                        .as_ref()
-                        .unwrap()
-                        .to_string(),
-                ))
+                .lock()
+                .await
+                .send(PingEvent::Received(did_to.to_string()))
                 .await;
-            let keypair =
-                KeyPair::try_from_private_key_bytes(KeyType::X25519, &private_key).unwrap();
+            let keypair = KeyPair::try_from_private_key_bytes(KeyType::X25519, &private_key)
+                .map_err(Error::internal)?;
             let ping_response = sign_and_encrypt(&response, &my_did, &did_to, &keypair)
                 .await
-                .unwrap();
+                .map_err(|err| Error::PeerUnreachable(format!("{}: {}", did_to, err)))?;
             Ok(Json(json!(ping_response)))
         }
-        "https://didcomm.org/present-proof/2.1/presentation" => {
-            for attachment in received.get_attachments() {
-                let presentation = decode(attachment.data.base64.as_ref().unwrap()).unwrap();
-                let presentation = std::str::from_utf8(&presentation).unwrap();
-                let presentation: Presentation = serde_json::from_str(presentation).unwrap();
-                present_proof_events
-                    .try_lock()
-                    .unwrap()
-                    .send(PresentProofEvent::ProofReceived(
-                        received
-                            .get_didcomm_header()
-                            .from
-                            .as_ref()
-                            .unwrap()
-                            .to_string(),
-                        serde_json::to_value(&presentation).unwrap(),
-                    ))
-                    .await;
+        presentation::exchange::PRESENTATION => {
+            let from = sender(&received)?;
+            let requested = match presentation_exchanges
+                .exchanges
+                .get(&thread_id(&received))?
+            {
+                Some(record)
+                    if record.role == PresentationExchangeRole::Verifier
+                        && record.state == PresentationExchangeState::RequestSent =>
+                {
+                    true
+                }
+                Some(record) => {
+                    return Err(Error::BadRequest(format!(
+                        "presentation exchange {} is {:?}, expected {:?}",
+                        record.thread_id,
+                        record.state,
+                        PresentationExchangeState::RequestSent
+                    )))
+                }
+                None => false,
+            };
+            let mut record = presentation::exchange::received(
+                presentation_exchanges,
+                connections,
+                present_proof_events,
+                &received,
+                PresentationExchangeRole::Verifier,
+                PresentationExchangeState::PresentationReceived,
+            )
+            .await?;
+            let attachment = record
+                .presentation
+                .clone()
+                .ok_or_else(|| Error::BadRequest("presentation without attachment".to_string()))?;
+            let request: Option<PresentationRequest> = record
+                .presentation_request
+                .clone()
+                .and_then(|request| serde_json::from_value(request).ok());
+            let (presentation, mut verification) =
+                verify_presentation(&attachment, request.as_ref()).await?;
+            if !requested {
+                verification.verified = false;
+                verification
+                    .errors
+                    .push("no presentation was requested".to_string());
+            }
+            let presentation = serde_json::to_value(&presentation).map_err(Error::internal)?;
+            present_proof_events
+                .lock()
+                .await
+                .send(PresentProofEvent::ProofReceived(
+                    from.to_string(),
+                    presentation.clone(),
+                ))
+                .await;
+
+            record.verification = Some(verification.clone());
+            let state = match verification.verified {
+                true => PresentationExchangeState::Done,
+                false => PresentationExchangeState::Abandoned,
+            };
+            presentation::exchange::set_state(
+                presentation_exchanges,
+                present_proof_events,
+                &mut record,
+                state,
+            )
+            .await?;
+            let response = match verification.verified {
+                true => presentation::exchange::build_ack(&record.thread_id)?,
+                false => {
+                    let errors: Vec<String> = verification
+                        .errors
+                        .iter()
+                        .chain(
+                            verification
+                                .credentials
+                                .iter()
+                                .flat_map(|credential| credential.errors.iter()),
+                        )
+                        .cloned()
+                        .collect();
+                    build_problem_report(
+                        "e.p.presentation.invalid",
+                        &errors.join(", "),
+                        &record.thread_id,
+                    )?
+                }
+            };
+            present_proof_events
+                .lock()
+                .await
+                .send(PresentProofEvent::PresentationVerified {
+                    from: from.to_string(),
+                    thread_id: record.thread_id.to_string(),
+                    presentation,
+                    verification,
+                })
+                .await;
+            let keypair = KeyPair::try_from_private_key_bytes(KeyType::X25519, &private_key)
+                .map_err(Error::internal)?;
+            let response = match sign_and_encrypt(&response, &my_did, &from, &keypair).await {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!("presentation response to {}: {}", from, err);
+                    json!({})
+                }
+            };
+            Ok(Json(response))
+        }
+        presentation::exchange::ACK => {
+            presentation::exchange::receive_ack(
+                presentation_exchanges,
+                present_proof_events,
+                &received,
+            )
+            .await?;
+            Ok(Json(json!({})))
+        }
+        presentation::exchange::PROPOSE_PRESENTATION => {
+            let record = presentation::exchange::received(
+                presentation_exchanges,
+                connections,
+                present_proof_events,
+                &received,
+                PresentationExchangeRole::Verifier,
+                PresentationExchangeState::ProposalReceived,
+            )
+            .await?;
+            present_proof_events
+                .lock()
+                .await
+                .send(PresentProofEvent::ProposalReceived {
+                    from: record.their_did.to_string(),
+                    thread_id: record.thread_id.to_string(),
+                    proposal: record.presentation_proposal.clone().unwrap_or_default(),
+                })
+                .await;
+            Ok(Json(json!({})))
+        }
+        presentation::exchange::REQUEST_PRESENTATION => {
+            let record = presentation::exchange::received(
+                presentation_exchanges,
+                connections,
+                present_proof_events,
+                &received,
+                PresentationExchangeRole::Prover,
+                PresentationExchangeState::RequestReceived,
+            )
+            .await?;
+            let policy =
+                presentation::exchange::policy(&config_ext.auto_respond, connections, &record);
+            if policy.present() {
+                if let Err(err) = presentation::request::present(
+                    wallet,
+                    connections,
+                    credentials,
+                    presentation_exchanges,
+                    present_proof_events,
+                    &record,
+                    Default::default(),
+                )
+                .await
+                {
+                    warn!("auto respond to {}: {}", record.thread_id, err);
+                }
             }
             Ok(Json(json!({})))
         }
-        "https://didcomm.org/issue-credential/2.1/issue-credential" => {
+        exchange::PROPOSE_CREDENTIAL => {
+            let record = exchange::received(
+                exchanges,
+                connections,
+                issue_credential_events,
+                &received,
+                CredentialExchangeRole::Issuer,
+                CredentialExchangeState::ProposalReceived,
+            )
+            .await?;
+            respond_automatically(&agent, config_ext, schemas, &record).await;
+            Ok(Json(json!({})))
+        }
+        exchange::OFFER_CREDENTIAL => {
+            let record = exchange::received(
+                exchanges,
+                connections,
+                issue_credential_events,
+                &received,
+                CredentialExchangeRole::Holder,
+                CredentialExchangeState::OfferReceived,
+            )
+            .await?;
+            respond_automatically(&agent, config_ext, schemas, &record).await;
+            Ok(Json(json!({})))
+        }
+        exchange::REQUEST_CREDENTIAL => {
+            let record = exchange::received(
+                exchanges,
+                connections,
+                issue_credential_events,
+                &received,
+                CredentialExchangeRole::Issuer,
+                CredentialExchangeState::RequestReceived,
+            )
+            .await?;
+            respond_automatically(&agent, config_ext, schemas, &record).await;
+            Ok(Json(json!({})))
+        }
+        exchange::ISSUE_CREDENTIAL => {
+            let from = sender(&received)?;
+            let mut record = exchange::received(
+                exchanges,
+                connections,
+                issue_credential_events,
+                &received,
+                CredentialExchangeRole::Holder,
+                CredentialExchangeState::CredentialReceived,
+            )
+            .await?;
+            let store = exchange::policy(&config_ext.auto_respond, connections, &record).store();
+            let keypair = KeyPair::try_from_private_key_bytes(KeyType::X25519, &private_key)
+                .map_err(Error::internal)?;
+            let mut issued = Vec::new();
+            let mut errors = Vec::new();
             for attachment in received.get_attachments() {
-                let credential = decode(attachment.data.base64.as_ref().unwrap()).unwrap();
-                let credential = std::str::from_utf8(&credential).unwrap();
-                let credential: Credential = serde_json::from_str(credential).unwrap();
+                let attachment: Value = decode_attachment(attachment.data.base64.as_ref())?;
+                let (credential, jwt, verification) =
+                    verify_attachment(&attachment, &my_did).await?;
+                if credential.id.is_none() {
+                    return Err(Error::BadRequest("credential without id".to_string()));
+                }
+                info!("issuance: {:?}", credential);
+                errors.extend(verification.errors.iter().cloned());
+                let value = serde_json::to_value(&credential).map_err(Error::internal)?;
+                record.credential = Some(attachment);
+                issued.push((credential, jwt, verification, value));
+            }
 
+            if !errors.is_empty() {
+                exchange::set_state(
+                    exchanges,
+                    issue_credential_events,
+                    &mut record,
+                    CredentialExchangeState::Abandoned,
+                )
+                .await?;
                 issue_credential_events
-                    .try_lock()
-                    .unwrap()
-                    .send(IssueCredentialEvent::IssueCredentialReceived {
-                        from: received
-                            .get_didcomm_header()
-                            .from
-                            .as_ref()
-                            .unwrap()
-                            .to_string(),
-                        value: serde_json::to_value(&credential).unwrap(),
+                    .lock()
+                    .await
+                    .send(IssueCredentialEvent::VerificationFailed {
+                        from: from.to_string(),
+                        thread_id: record.thread_id.to_string(),
+                        errors: errors.clone(),
                     })
                     .await;
-                info!("issuance: {:?}", credential);
-                let mut lock = credentials.credentials.lock().await;
+                let problem_report = build_problem_report(
+                    "e.p.credential.invalid",
+                    &errors.join(", "),
+                    &record.thread_id,
+                )?;
+                let response =
+                    match sign_and_encrypt(&problem_report, &my_did, &from, &keypair).await {
+                        Ok(problem_report) => problem_report,
+                        Err(err) => {
+                            warn!("problem report to {}: {}", from, err);
+                            json!({})
+                        }
+                    };
+                return Ok(Json(response));
+            }
 
-                lock.insert(credential.id.clone().unwrap().to_string(), credential);
+            if store {
+                for (credential, jwt, verification, _) in &issued {
+                    credentials.insert(
+                        credential.clone(),
+                        Some(verification.clone()),
+                        jwt.clone(),
+                    )?;
+                }
             }
+            exchanges
+                .exchanges
+                .insert(&record.thread_id, record.clone())?;
+
+            let response = match store {
+                true => {
+                    let ack = exchange::build_ack(&record.thread_id)?;
+                    match sign_and_encrypt(&ack, &my_did, &from, &keypair).await {
+                        Ok(ack) => {
+                            exchange::set_state(
+                                exchanges,
+                                issue_credential_events,
+                                &mut record,
+                                CredentialExchangeState::Done,
+                            )
+                            .await?;
+                            ack
+                        }
+                        Err(err) => {
+                            warn!("ack to {}: {}", from, err);
+                            json!({})
+                        }
+                    }
+                }
+                false => json!({}),
+            };
+            for (_, _, _, value) in issued {
+                issue_credential_events
+                    .lock()
+                    .await
+                    .send(IssueCredentialEvent::IssueCredentialReceived {
+                        from: from.to_string(),
+                        value,
+                    })
+                    .await;
+            }
+            Ok(Json(response))
+        }
+        exchange::ACK => {
+            exchange::receive_ack(exchanges, issue_credential_events, &received).await?;
             Ok(Json(json!({})))
         }
         "https://didcomm.org/basicmessage/2.0/message" => {
-            let did_from = received.get_didcomm_header().from.clone().unwrap();
-            let payload = received.get_body().unwrap();
+            let did_from = sender(&received)?;
+            let payload = received
+                .get_body()
+                .map_err(|err| Error::BadRequest(format!("{:?}", err)))?;
             message_events
-                .try_lock()
-                .unwrap()
+                .lock()
+                .await
                 .send(MessageEvent::Received(did_from, payload))
                 .await;
             Ok(Json(json!({})))
         }
-        "iota/termination/0.1/termination" => {
-            let termination: Termination =
-                serde_json::from_str(&received.get_body().unwrap()).unwrap();
-            let mut lock = connections.connections.lock().await;
-            lock.remove(&termination.id).unwrap();
-            std::mem::drop(lock);
-            let termination: TerminationResponse = TerminationResponse {
-                typ: "application/didcomm-plain+json".to_string(),
-                type_: "iota/termination/0.1/termination-response".to_string(),
-                id: termination.id,
-                body: Value::default(),
-            };
-            Ok(Json(json!(termination)))
+        termination::TERMINATION => {
+            match termination::receive_termination(
+                wallet,
+                connections,
+                connection_events,
+                &received,
+            )
+            .await
+            {
+                Ok(response) => Ok(Json(response)),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
+        termination::TERMINATION_RESPONSE => {
+            match termination::receive_termination_response(
+                connections,
+                connection_events,
+                &received,
+            )
+            .await
+            {
+                Ok(_) => Ok(Json(json!({}))),
+                Err(err) => Err(Error::BadRequest(err.to_string())),
+            }
+        }
+        PROBLEM_REPORT => {
+            warn!(
+                "problem report from {:?}: {:?}",
+                received.get_didcomm_header().from,
+                received.get_body()
+            );
+            if !exchange::receive_problem_report(exchanges, issue_credential_events, &received)
+                .await?
+            {
+                presentation::exchange::receive_problem_report(
+                    presentation_exchanges,
+                    present_proof_events,
+                    &received,
+                )
+                .await?;
+            }
+            Ok(Json(json!({})))
         }
         _ => Ok(Json(json!({}))),
     }
 }
 
+pub const PROBLEM_REPORT: &str = "https://didcomm.org/report-problem/2.0/problem-report";
+
+/// Recipient dids of an inbound message, the kids of an encrypted or the
+/// `to` of a plaintext message.
+fn recipient_dids(body: &Value) -> Vec<String> {
+    let dids: Vec<&str> = match body["recipients"].as_array() {
+        Some(recipients) => recipients
+            .iter()
+            .filter_map(|recipient| recipient["header"]["kid"].as_str())
+            .collect(),
+        None => body["to"]
+            .as_array()
+            .map(|to| to.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default(),
+    };
+    dids.iter()
+        .filter_map(|did| did.split('#').next())
+        .map(|did| did.to_string())
+        .collect()
+}
+
+/// Answers the next step of a credential exchange if its policy allows it.
+async fn respond_automatically(
+    agent: &Agent,
+    config_ext: &ConfigExt,
+    schemas: &Schemas,
+    record: &CredentialExchangeRecord,
+) {
+    let policy = exchange::policy(&config_ext.auto_respond, &agent.connections, record);
+    let responded = match record.state {
+        CredentialExchangeState::ProposalReceived if policy.offer() => {
+            issue::send_offer(
+                &agent.wallet,
+                &agent.connections,
+                &agent.exchanges,
+                &agent.issue_credential_events,
+                record,
+            )
+            .await
+        }
+        CredentialExchangeState::OfferReceived if policy.request() => {
+            issue::send_request(
+                &agent.wallet,
+                &agent.connections,
+                &agent.exchanges,
+                &agent.issue_credential_events,
+                record,
+            )
+            .await
+        }
+        CredentialExchangeState::RequestReceived if policy.issue() => {
+            issue::issue_credential(
+                &agent.wallet,
+                &agent.connections,
+                &agent.exchanges,
+                &agent.issue_credential_events,
+                &agent.revocations,
+                config_ext,
+                schemas,
+                record,
+            )
+            .await
+        }
+        _ => return,
+    };
+    if let Err(err) = responded {
+        warn!("auto respond to {}: {}", record.thread_id, err);
+    }
+}
+
+/// Finds the agent of the recipient did and decrypts the message with its key.
+async fn route(
+    tenants: &Tenants,
+    body: &Value,
+    body_str: &str,
+) -> Result<(Arc<Agent>, Message), Error> {
+    let mut error = Error::BadRequest("no recipient for message".to_string());
+    for agent in tenants.recipients(&recipient_dids(body)).await {
+        let private_key = agent.wallet.lock().await.keypair().private_key_bytes();
+        match receive(body_str, &private_key, None).await {
+            Ok(received) => return Ok((agent, received)),
+            Err(err) => error = err,
+        }
+    }
+    Err(error)
+}
+
+/// Sender of an inbound message, anonymous messages are rejected.
+pub fn sender(message: &Message) -> Result<String, Error> {
+    message
+        .get_didcomm_header()
+        .from
+        .clone()
+        .ok_or_else(|| Error::BadRequest("message without sender".to_string()))
+}
+
+/// Decodes a base64 json attachment.
+fn decode_attachment<T: DeserializeOwned>(base64: Option<impl AsRef<[u8]>>) -> Result<T, Error> {
+    let data = base64.ok_or_else(|| Error::BadRequest("attachment without data".to_string()))?;
+    let data = decode(data).map_err(|err| Error::BadRequest(err.to_string()))?;
+    serde_json::from_slice(&data).map_err(|err| Error::BadRequest(err.to_string()))
+}
+
+/// Whether the body of a response holds a message returned on the return
+/// route, peers answering asynchronously return an empty body.
+pub fn is_returned_message(body: &str) -> bool {
+    matches!(serde_json::from_str::<Value>(body), Ok(Value::Object(object)) if !object.is_empty())
+}
+
+/// Builds a problem report answering the thread.
+pub fn build_problem_report(code: &str, comment: &str, thid: &str) -> Result<Message, Error> {
+    let problem_report = build_message(
+        PROBLEM_REPORT,
+        json!({
+            "code": code,
+            "comment": comment,
+        }),
+    )?;
+    with_thread(problem_report, thid, None)
+}
+
+/// Builds a plaintext message of the given type.
+pub fn build_message(type_: &str, body: Value) -> Result<Message, Error> {
+    serde_json::from_value(json!({
+        "typ": "application/didcomm-plain+json",
+        "type": type_,
+        "id": Uuid::new_v4().to_string(),
+        "body": body,
+    }))
+    .map_err(Error::internal)
+}
+
+/// The thread a message belongs to, a message without thid starts its own thread.
+pub fn thread_id(message: &Message) -> String {
+    let header = message.get_didcomm_header();
+    match &header.thid {
+        Some(thid) => thid.to_string(),
+        None => header.id.to_string(),
+    }
+}
+
+pub fn parent_thread_id(message: &Message) -> Option<String> {
+    message.get_didcomm_header().pthid.clone()
+}
+
+pub fn with_thread(message: Message, thid: &str, pthid: Option<&str>) -> Result<Message, Error> {
+    let mut value = serde_json::to_value(&message).map_err(Error::internal)?;
+    value["thid"] = json!(thid);
+    if let Some(pthid) = pthid {
+        value["pthid"] = json!(pthid);
+    }
+    serde_json::from_value(value).map_err(Error::internal)
+}
+
 pub async fn sign_and_encrypt(
     message: &Message,
     did_from: &str,
     did_to: &String,
     key: &KeyPair,
 ) -> Result<Value, Box<dyn std::error::Error>> {
-    let sign_key = KeyPair::new(KeyType::Ed25519).unwrap();
+    let sign_key = KeyPair::new(KeyType::Ed25519)?;
 
     let resolver: Resolver = Resolver::new().await?;
 
@@ -196,7 +716,7 @@ pub async fn sign_and_encrypt(
     let recipient_method: &IotaVerificationMethod = recipient_document
         .document
         .resolve_method("kex-0", Some(MethodScope::VerificationMethod))
-        .unwrap();
+        .ok_or_else(|| format!("{} has no kex-0 method", recipient_did))?;
     let recipient_key: Vec<u8> = recipient_method.data().try_decode()?;
 
     let response = message
@@ -206,42 +726,50 @@ pub async fn sign_and_encrypt(
         .as_jwe(&CryptoAlgorithm::XC20P, Some(recipient_key.to_vec()))
         .kid(&hex::encode(sign_key.public().as_ref()));
 
-    let ready_to_send = response
-        .seal_signed(
-            key.private().as_ref(),
-            Some(vec![Some(recipient_key)]),
-            SignatureAlgorithm::EdDsa,
-            &[sign_key.private().as_ref(), sign_key.public().as_ref()].concat(),
-        )
-        .unwrap();
-    Ok(serde_json::from_str(&ready_to_send).unwrap())
+    let ready_to_send = response.seal_signed(
+        key.private().as_ref(),
+        Some(vec![Some(recipient_key)]),
+        SignatureAlgorithm::EdDsa,
+        &[sign_key.private().as_ref(), sign_key.public().as_ref()].concat(),
+    )?;
+    Ok(serde_json::from_str(&ready_to_send)?)
 }
 
+/// Decrypts an inbound message, the key of an encrypting sender is resolved
+/// from the `kex-0` method of its document. Malformed messages are a
+/// `Error::BadRequest`, failed resolutions a `Error::PeerUnreachable`.
 pub async fn receive(
     message: &str,
     encryption_recipient_private_key: &[u8],
     encryption_sender_public_key: Option<Vec<u8>>,
-) -> Result<Message, didcomm_rs::Error> {
+) -> Result<Message, Error> {
     let sender_public_key = match &encryption_sender_public_key {
         Some(value) => Some(value.to_vec()),
         None => match serde_json::from_str::<Jwe>(message) {
             Ok(jwe) => {
-                let skid = &jwe
+                let skid = jwe
                     .get_skid()
-                    .ok_or_else(|| didcomm_rs::Error::Generic("skid missing".to_string()))
-                    .unwrap();
-
-                let resolver: Resolver = Resolver::new().await.unwrap();
-                let sender_did = IotaDID::from_str(skid).unwrap();
-                let sender_document = match resolver.resolve(&sender_did).await {
-                    Ok(did) => Ok(did),
-                    Err(_) => Err(didcomm_rs::Error::DidResolveFailed),
-                }?;
+                    .ok_or_else(|| Error::BadRequest("skid missing".to_string()))?;
+                let sender_did =
+                    IotaDID::from_str(&skid).map_err(|err| Error::InvalidDid(err.to_string()))?;
+                let resolver: Resolver = Resolver::new()
+                    .await
+                    .map_err(|err| Error::PeerUnreachable(err.to_string()))?;
+                let sender_document = resolver
+                    .resolve(&sender_did)
+                    .await
+                    .map_err(|err| Error::PeerUnreachable(format!("{}: {}", sender_did, err)))?;
                 let sender_method = sender_document
                     .document
                     .resolve_method("kex-0", Some(MethodScope::VerificationMethod))
-                    .ok_or(didcomm_rs::Error::DidResolveFailed);
-                Some(sender_method?.data().try_decode().unwrap())
+                    .ok_or_else(|| {
+                        Error::BadRequest(format!("{} has no kex-0 method", sender_did))
+                    })?;
+                let sender_key: Vec<u8> = sender_method
+                    .data()
+                    .try_decode()
+                    .map_err(|err| Error::BadRequest(format!("{}: {}", sender_did, err)))?;
+                Some(sender_key)
             }
             Err(_) => None,
         },
@@ -253,4 +781,5 @@ pub async fn receive(
         sender_public_key,
         None,
     )
+    .map_err(|err| Error::BadRequest(err.to_string()))
 }
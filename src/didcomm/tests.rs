@@ -1,5 +1,8 @@
-use super::{receive, sign_and_encrypt};
+use super::{build_message, receive, recipient_dids, sign_and_encrypt};
+use crate::connection::tests::connect;
+use crate::connection::{termination, ConnectionEvent, InvitationResponse};
 use crate::credential::IssueCredentialEvent;
+use crate::presentation::PresentProofEvent;
 use crate::wallet::tests::get_did;
 use crate::webhook;
 use crate::Config;
@@ -17,7 +20,7 @@ use identity_iota::credential::{Credential, CredentialBuilder, Subject};
 use identity_iota::credential::{Presentation, PresentationBuilder};
 use identity_iota::prelude::KeyPair;
 use identity_iota::prelude::*;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::local::asynchronous::Client;
 use serde_json::{json, Value};
 use std::sync::Arc;
@@ -39,6 +42,85 @@ async fn test_receive_ping() {
     assert_eq!(response.status(), Status::Ok);
 }
 
+#[test]
+fn test_recipient_dids() {
+    let encrypted = json!({
+        "recipients": [{"header": {"kid": "did:iota:foo#kex-0"}}],
+    });
+    assert_eq!(recipient_dids(&encrypted), vec!["did:iota:foo".to_string()]);
+    let plaintext = json!({"to": ["did:iota:bar"]});
+    assert_eq!(recipient_dids(&plaintext), vec!["did:iota:bar".to_string()]);
+    assert!(recipient_dids(&json!({})).is_empty());
+}
+
+#[tokio::test]
+async fn test_receive_termination() {
+    let webhook_client = Box::new(webhook::test_client::TestClient::new(
+        "http://localhost".to_string(),
+    )) as Box<dyn webhook::Webhook>;
+    let webhook_client = Arc::new(Mutex::new(webhook_client));
+    let client = Client::tracked(test_rocket_with_webhook_client(webhook_client.clone()).await)
+        .await
+        .expect("valid rocket instance");
+
+    let did = get_did(&client).await.unwrap();
+
+    let mut message = build_message(termination::TERMINATION, json!({})).unwrap();
+    message = add_return_route_all_header(message);
+    message = message.from(&did).to(&[&did]);
+
+    // unknown peer is answered with a problem report
+    let response = client.post(format!("/")).json(&message).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client
+        .post("/out-of-band/create-invitation")
+        .dispatch()
+        .await;
+    let invitation: InvitationResponse = response.into_json().await.unwrap();
+    let response = client
+        .post("/out-of-band/receive-invitation")
+        .header(ContentType::JSON)
+        .body(serde_json::to_string(&invitation.invitation).unwrap())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client.get("/connections").dispatch().await;
+    let connections: Vec<Value> = response.into_json().await.unwrap();
+    assert_eq!(connections.len(), 2);
+    let invitee = connections
+        .iter()
+        .find(|connection| connection["did"] == json!(did))
+        .unwrap();
+
+    let response = client.post(format!("/")).json(&message).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        webhook::test_client::last_response(&webhook_client).unwrap(),
+        serde_json::to_value(ConnectionEvent::Terminated(
+            invitee["id"].as_str().unwrap().to_string()
+        ))
+        .unwrap()
+    );
+
+    let response = client.get("/connections").dispatch().await;
+    let connections: Vec<Value> = response.into_json().await.unwrap();
+    assert_eq!(connections.len(), 1);
+}
+
+/// Signs the credential with the agent's wallet.
+async fn sign_credential(client: &Client, credential: &Credential) -> Credential {
+    let response = client
+        .post("/jsonld/sign")
+        .json(&json!({ "doc": { "credential": credential } }))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = response.into_json::<Value>().await.unwrap();
+    serde_json::from_value(response["signed_doc"].clone()).unwrap()
+}
+
 #[tokio::test]
 async fn test_receive_issue_credential() {
     let webhook_client = Box::new(webhook::test_client::TestClient::new(
@@ -62,6 +144,7 @@ async fn test_receive_issue_credential() {
         .subject(subject)
         .build()
         .unwrap();
+    let credential = sign_credential(&client, &credential).await;
 
     let webhook_response = serde_json::to_value(IssueCredentialEvent::IssueCredentialReceived {
         from: did.to_string(),
@@ -90,6 +173,127 @@ async fn test_receive_issue_credential() {
         webhook::test_client::last_response(&webhook_client).unwrap(),
         webhook_response
     );
+
+    // the credential is acknowledged
+    let response = client
+        .get("/issue-credential-2.1/records?state=done")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let records = response.into_json::<Value>().await.unwrap();
+    assert_eq!(records.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_receive_invalid_credential() {
+    let webhook_client = Box::new(webhook::test_client::TestClient::new(
+        "http://localhost".to_string(),
+    )) as Box<dyn webhook::Webhook>;
+    let webhook_client = Arc::new(Mutex::new(webhook_client));
+    let client = Client::tracked(test_rocket_with_webhook_client(webhook_client.clone()).await)
+        .await
+        .expect("valid rocket instance");
+
+    let did = get_did(&client).await.unwrap();
+
+    // unsigned and issued to someone else
+    let credential: Credential = CredentialBuilder::default()
+        .id(Url::parse("https://example.edu/credentials/3732").unwrap())
+        .issuer(Url::parse(did.as_str()).unwrap())
+        .subject(Subject::from_json_value(json!({"id": "did:iota:someone"})).unwrap())
+        .build()
+        .unwrap();
+
+    let mut message = IssueCredentialResponseBuilder::new()
+        .goal_code("issue-vc".to_string())
+        .attachment(serde_json::to_value(&credential).unwrap())
+        .build_issue_credential()
+        .unwrap();
+    message = message.from(&did).to(&[&did]);
+
+    let response = client.post(format!("/")).json(&message).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client.get("/credentials").dispatch().await;
+    let response = response.into_json::<Value>().await.unwrap();
+    let credentials = response.get("result").unwrap().as_array().unwrap();
+    assert_eq!(credentials.len(), 0);
+
+    let response = client
+        .get("/issue-credential-2.1/records?state=abandoned")
+        .dispatch()
+        .await;
+    let records = response.into_json::<Value>().await.unwrap();
+    assert_eq!(records.as_array().unwrap().len(), 1);
+
+    let event: IssueCredentialEvent =
+        serde_json::from_value(webhook::test_client::last_response(&webhook_client).unwrap())
+            .unwrap();
+    match event {
+        IssueCredentialEvent::VerificationFailed { from, errors, .. } => {
+            assert_eq!(from, did);
+            assert!(errors.len() >= 2);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+}
+
+#[tokio::test]
+async fn test_receive_issue_credential_without_auto_store() {
+    let client = Client::tracked(test_rocket().await)
+        .await
+        .expect("valid rocket instance");
+
+    let did = get_did(&client).await.unwrap();
+    let connection = connect(&client).await.unwrap();
+
+    let response = client
+        .post(format!("/connections/{}/auto-respond", connection.id))
+        .json(&json!({"auto_store": false}))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let subject: Subject = Subject::from_json_value(
+        json!({"id": Url::parse(did.as_str()).unwrap(), "attributes": "None".to_string()}),
+    )
+    .unwrap();
+    let credential: Credential = CredentialBuilder::default()
+        .id(Url::parse("https://example.edu/credentials/3732").unwrap())
+        .issuer(Url::parse(did.as_str()).unwrap())
+        .subject(subject)
+        .build()
+        .unwrap();
+    let credential = sign_credential(&client, &credential).await;
+
+    let mut message = IssueCredentialResponseBuilder::new()
+        .goal_code("issue-vc".to_string())
+        .attachment(serde_json::to_value(&credential).unwrap())
+        .build_issue_credential()
+        .unwrap();
+    message = message.from(&did).to(&[&did]);
+
+    let response = client.post(format!("/")).json(&message).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    // the credential waits on the exchange for the controller
+    let response = client.get("/credentials").dispatch().await;
+    let response = response.into_json::<Value>().await.unwrap();
+    let credentials = response.get("result").unwrap().as_array().unwrap();
+    assert_eq!(credentials.len(), 0);
+
+    let response = client
+        .get("/issue-credential-2.1/records?state=credential-received")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let records = response.into_json::<Value>().await.unwrap();
+    let records = records.as_array().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        records[0]["credential"],
+        serde_json::to_value(&credential).unwrap()
+    );
 }
 
 #[tokio::test]
@@ -115,6 +319,7 @@ async fn test_receive_present_proof() {
         .subject(subject)
         .build()
         .unwrap();
+    let credential = sign_credential(&client, &credential).await;
 
     let webhook_response = serde_json::to_value(IssueCredentialEvent::IssueCredentialReceived {
         from: did.to_string(),
@@ -165,6 +370,26 @@ async fn test_receive_present_proof() {
 
     let response = client.post(format!("/")).json(&message).dispatch().await;
     assert_eq!(response.status(), Status::Ok);
+
+    // the presentation is not signed by the holder
+    let event: PresentProofEvent =
+        serde_json::from_value(webhook::test_client::last_response(&webhook_client).unwrap())
+            .unwrap();
+    match event {
+        PresentProofEvent::PresentationVerified { verification, .. } => {
+            assert!(!verification.verified);
+            assert!(verification
+                .errors
+                .contains(&"no presentation was requested".to_string()));
+            assert_eq!(verification.credentials.len(), 1);
+            assert!(verification.credentials[0].verified);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // the abandoned exchange is not verified again
+    let response = client.post(format!("/")).json(&message).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
 }
 
 #[tokio::test]
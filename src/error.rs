@@ -0,0 +1,202 @@
+use crate::storage;
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::Request;
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{MediaType, RefOr, Response, Responses};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::response::OpenApiResponderInner;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Problem body returned with every error response.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Problem {
+    pub status: u16,
+    pub title: String,
+    pub detail: String,
+    /// Invalid fields of the request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// A field of the request failing validation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Errors of the route handlers, each mapped to a http status.
+#[derive(Debug)]
+pub enum Error {
+    ConnectionNotFound(String),
+    CredentialNotFound(String),
+    NotFound(String),
+    InvalidDid(String),
+    InvalidSchema(String),
+    InvalidAttributes(Vec<FieldError>),
+    BadRequest(String),
+    Unauthorized(String),
+    PeerUnreachable(String),
+    Storage(storage::Error),
+    Internal(String),
+}
+
+impl Error {
+    /// Wraps errors of the underlying libraries.
+    pub fn internal(err: impl fmt::Debug) -> Self {
+        Error::Internal(format!("{:?}", err))
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            Error::ConnectionNotFound(_) | Error::CredentialNotFound(_) | Error::NotFound(_) => {
+                Status::NotFound
+            }
+            Error::InvalidDid(_) | Error::InvalidSchema(_) | Error::BadRequest(_) => {
+                Status::BadRequest
+            }
+            Error::InvalidAttributes(_) => Status::UnprocessableEntity,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::PeerUnreachable(_) => Status::BadGateway,
+            Error::Storage(_) | Error::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let status = self.status();
+        Problem {
+            status: status.code,
+            title: status.reason_lossy().to_string(),
+            detail: self.to_string(),
+            errors: match self {
+                Error::InvalidAttributes(errors) => errors.clone(),
+                _ => Vec::new(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ConnectionNotFound(id) => write!(f, "connection not found: {}", id),
+            Error::CredentialNotFound(id) => write!(f, "credential not found: {}", id),
+            Error::NotFound(reason) => write!(f, "not found: {}", reason),
+            Error::InvalidDid(reason) => write!(f, "invalid did: {}", reason),
+            Error::InvalidSchema(reason) => write!(f, "invalid schema: {}", reason),
+            Error::InvalidAttributes(errors) => {
+                write!(f, "invalid attributes: ")?;
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} {}", error.field, error.message)?;
+                }
+                Ok(())
+            }
+            Error::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            Error::Unauthorized(reason) => write!(f, "unauthorized: {}", reason),
+            Error::PeerUnreachable(reason) => write!(f, "peer unreachable: {}", reason),
+            Error::Storage(err) => write!(f, "storage: {}", err),
+            Error::Internal(reason) => write!(f, "internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<storage::Error> for Error {
+    fn from(err: storage::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::PeerUnreachable(err.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for Error {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        Error::Internal(err.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if self.status() == Status::InternalServerError {
+            error!("{}", self);
+        } else {
+            warn!("{}", self);
+        }
+        (self.status(), Json(self.problem())).respond_to(request)
+    }
+}
+
+impl OpenApiResponderInner for Error {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let schema = gen.json_schema::<Problem>();
+        let mut responses = Responses::default();
+        for (status, description) in [
+            ("400", "Malformed did, schema or request"),
+            ("401", "Missing or invalid admin credentials"),
+            ("404", "Unknown connection, credential or record"),
+            ("422", "Attributes not matching the credential schema"),
+            ("500", "Internal error"),
+            ("502", "Peer agent or ledger unreachable"),
+        ] {
+            let mut response = Response {
+                description: description.to_string(),
+                ..Default::default()
+            };
+            response.content.insert(
+                "application/json".to_string(),
+                MediaType {
+                    schema: Some(schema.clone()),
+                    ..Default::default()
+                },
+            );
+            responses
+                .responses
+                .insert(status.to_string(), RefOr::Object(response));
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem() {
+        let problem = Error::ConnectionNotFound("foo".to_string()).problem();
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.title, "Not Found");
+        assert_eq!(problem.detail, "connection not found: foo");
+
+        assert_eq!(
+            Error::InvalidDid("foo".to_string()).status(),
+            Status::BadRequest
+        );
+        assert_eq!(
+            Error::PeerUnreachable("foo".to_string()).status(),
+            Status::BadGateway
+        );
+
+        let problem = Error::InvalidAttributes(vec![FieldError {
+            field: "/name".to_string(),
+            message: "is not a string".to_string(),
+        }])
+        .problem();
+        assert_eq!(problem.status, 422);
+        assert_eq!(problem.errors.len(), 1);
+        assert_eq!(problem.detail, "invalid attributes: /name is not a string");
+    }
+}
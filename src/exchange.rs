@@ -0,0 +1,112 @@
+use crate::connection::Connection;
+use crate::didcomm::{self, sender, thread_id, PROBLEM_REPORT};
+use crate::error::{Error, Result};
+use crate::storage::{Record, Store};
+use crate::Wallet;
+use did_key::KeyMaterial;
+use didcomm_rs::Message;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A record of an exchange with a peer, keyed by its thread id.
+pub trait ExchangeRecord: Record + Clone {
+    /// Name of the exchange in errors.
+    const NAME: &'static str;
+
+    fn thread_id(&self) -> &str;
+
+    fn their_did(&self) -> &str;
+}
+
+/// The exchange on the thread of an inbound message, exchanges of another
+/// peer are rejected.
+pub fn peer_record<T: ExchangeRecord>(store: &Store<T>, message: &Message) -> Result<Option<T>> {
+    let their_did = sender(message)?;
+    let thread_id = thread_id(message);
+    match store.get(&thread_id)? {
+        Some(record) if record.their_did() == their_did => Ok(Some(record)),
+        Some(_) => Err(Error::BadRequest(format!(
+            "{} {} belongs to another peer",
+            T::NAME,
+            thread_id
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Posts the message of an exchange to the connection and returns the
+/// message the peer returned.
+///
+/// The record is written before as the peer may answer before the post
+/// returns, so the `previous` record is restored if the peer can not be
+/// reached or answers with an error status.
+pub async fn post<T: ExchangeRecord>(
+    store: &Store<T>,
+    previous: Option<T>,
+    record: &T,
+    connection: &Connection,
+    encrypted: &Value,
+) -> Result<Value> {
+    let client = reqwest::Client::new();
+    match client
+        .post(connection.endpoint.to_string())
+        .json(encrypted)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+    {
+        Ok(res) => Ok(res.json::<Value>().await.unwrap_or_default()),
+        Err(err) => {
+            match previous {
+                Some(previous) => {
+                    let thread_id = previous.thread_id().to_string();
+                    store.insert(&thread_id, previous)?
+                }
+                None => {
+                    store.remove(record.thread_id())?;
+                }
+            }
+            Err(err.into())
+        }
+    }
+}
+
+/// Hands an ack or problem report the peer returned on the return route to
+/// the exchange, other and empty responses are ignored.
+pub async fn receive_returned<A, P>(
+    wallet: &Arc<Mutex<Wallet>>,
+    response: &Value,
+    ack_type: &str,
+    receive_ack: impl FnOnce(Message) -> A,
+    receive_problem_report: impl FnOnce(Message) -> P,
+) where
+    A: Future<Output = Result<()>>,
+    P: Future<Output = Result<bool>>,
+{
+    match response.as_object() {
+        Some(response) if !response.is_empty() => (),
+        _ => return,
+    }
+    let private_key = wallet.lock().await.keypair().private_key_bytes();
+    let message = match didcomm::receive(&response.to_string(), &private_key, None).await {
+        Ok(message) => message,
+        Err(err) => {
+            warn!("returned ack: {}", err);
+            return;
+        }
+    };
+    let m_type = message.get_didcomm_header().m_type.to_string();
+    let received = if m_type == ack_type {
+        receive_ack(message).await
+    } else if m_type == PROBLEM_REPORT {
+        warn!("returned problem report: {:?}", message.get_body());
+        receive_problem_report(message).await.map(|_| ())
+    } else {
+        Ok(())
+    };
+    if let Err(err) = received {
+        warn!("returned ack: {}", err);
+    }
+}
@@ -1,12 +1,13 @@
+use crate::auth::AdminAuth;
 use crate::credential::example_credential;
+use crate::error::Error;
+use crate::tenant::TenantState;
 use crate::wallet::Wallet;
 use identity_iota::client::ResolvedIotaDocument;
 use identity_iota::credential::Credential;
 use identity_iota::crypto::ProofOptions;
 use identity_iota::did::verifiable::VerifierOptions;
 use identity_iota::prelude::*;
-use rocket::http::Status;
-use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::okapi::schemars::{self, JsonSchema};
 use rocket_okapi::openapi;
@@ -43,26 +44,31 @@ pub struct VerifyResponse {
 #[openapi(tag = "jsonld")]
 #[post("/jsonld/sign", data = "<request>")]
 pub async fn post_sign(
-    wallet: &State<Arc<Mutex<Wallet>>>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
     request: Json<SignRequest>,
-) -> Result<Json<SignResponse>, Status> {
-    let response = match serde_json::from_value::<Credential>(
-        request.into_inner().doc.get("credential").unwrap().clone(),
-    ) {
+) -> Result<Json<SignResponse>, Error> {
+    let credential = request
+        .into_inner()
+        .doc
+        .get("credential")
+        .cloned()
+        .ok_or_else(|| Error::BadRequest("doc without credential".to_string()))?;
+    let response = match serde_json::from_value::<Credential>(credential) {
         Ok(credential) => {
-            let wallet = wallet.try_lock().unwrap();
+            let wallet = wallet.lock().await;
             let mut credential = credential.clone();
             wallet
                 .account
                 .as_ref()
-                .unwrap()
+                .ok_or_else(|| Error::NotFound("No Account".to_string()))?
                 .sign("sign-0", &mut credential, ProofOptions::default())
                 .await
-                .unwrap();
+                .map_err(Error::internal)?;
 
             SignResponse {
                 error: None,
-                signed_doc: Some(serde_json::to_value(&credential).unwrap()),
+                signed_doc: Some(serde_json::to_value(&credential).map_err(Error::internal)?),
             }
         }
         Err(err) => SignResponse {
@@ -77,14 +83,21 @@ pub async fn post_sign(
 #[openapi(tag = "jsonld")]
 #[post("/jsonld/verify", data = "<request>")]
 pub async fn post_verify(
-    wallet: &State<Arc<Mutex<Wallet>>>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
     request: Json<Value>,
-) -> Result<Json<VerifyResponse>, Status> {
+) -> Result<Json<VerifyResponse>, Error> {
     let response = match serde_json::from_value::<Credential>(request.into_inner()) {
         Ok(credential) => {
-            let wallet = wallet.try_lock().unwrap();
-            let account = wallet.account.as_ref().unwrap();
-            let resolved: ResolvedIotaDocument = account.resolve_identity().await.unwrap();
+            let wallet = wallet.lock().await;
+            let account = wallet
+                .account
+                .as_ref()
+                .ok_or_else(|| Error::NotFound("No Account".to_string()))?;
+            let resolved: ResolvedIotaDocument = account
+                .resolve_identity()
+                .await
+                .map_err(|err| Error::PeerUnreachable(err.to_string()))?;
             let valid = resolved
                 .document
                 .verify_data(&credential, &VerifierOptions::default())
@@ -103,8 +116,8 @@ pub async fn post_verify(
 mod tests {
     use super::*;
     use crate::credential::issue::example_credential_preview;
-    use crate::credential::issue::prepare_issue_credential_request;
     use crate::credential::issue::SendRequest;
+    use crate::credential::issue::{credential_id, prepare_issue_credential_request};
     use crate::test_rocket;
     use crate::Config;
     use crate::Wallet;
@@ -123,12 +136,21 @@ mod tests {
             connection_id: "".to_string(),
             comment: "".to_string(),
             credential_preview: example_credential_preview(),
+            subject_id: None,
+            schema_id: None,
+            format: Default::default(),
         };
         let did_to = wallet.did_iota().unwrap();
-        let (message, _value) =
-            prepare_issue_credential_request(&wallet, did_to.to_string(), request)
-                .await
-                .unwrap();
+        let (message, _value) = prepare_issue_credential_request(
+            &wallet,
+            did_to.to_string(),
+            request,
+            None,
+            credential_id(None).unwrap(),
+            None,
+        )
+        .await
+        .unwrap();
         let attachment = message.get_attachments().next().unwrap();
         let credential: Credential =
             serde_json::from_slice(&decode(&attachment.data.base64.as_ref().unwrap()).unwrap())
@@ -0,0 +1,303 @@
+use crate::error::{Error, Result};
+use crate::sdjwt;
+use crate::wallet::resolve_did;
+use identity_iota::account::Account;
+use identity_iota::account_storage::{KeyLocation, Storage};
+use identity_iota::core::Timestamp;
+use identity_iota::credential::{Credential, Presentation};
+use identity_iota::did::DID;
+use identity_iota::prelude::KeyType;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// Method of the wallet's did the jwts are signed with.
+pub const SIGNING_METHOD: &str = "sign-0";
+
+/// Encoding of issued credentials.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum CredentialFormat {
+    /// JSON-LD credential with embedded proof.
+    #[default]
+    #[serde(rename = "ldp_vc")]
+    LdpVc,
+    /// VC-JWT signed with `sign-0`.
+    #[serde(rename = "jwt_vc")]
+    JwtVc,
+    /// SD-JWT signed with `sign-0`, the subject's claims are disclosed one by one.
+    #[serde(rename = "vc+sd-jwt")]
+    SdJwtVc,
+}
+
+/// Encoding of sent presentations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum PresentationFormat {
+    /// JSON-LD presentation.
+    #[default]
+    #[serde(rename = "ldp_vp")]
+    LdpVp,
+    /// VP-JWT signed with `sign-0`.
+    #[serde(rename = "jwt_vp")]
+    JwtVp,
+}
+
+fn encode_part(value: &Value) -> Result<String> {
+    let json = serde_json::to_vec(value).map_err(Error::internal)?;
+    Ok(base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_part(part: &str) -> Result<Value> {
+    let json = base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| Error::BadRequest(format!("jwt: {}", err)))?;
+    serde_json::from_slice(&json).map_err(|err| Error::BadRequest(format!("jwt: {}", err)))
+}
+
+/// Whether the value looks like a compact serialized jwt.
+pub fn is_jwt(value: &str) -> bool {
+    value.split('.').count() == 3 && value.starts_with("ey") && !value.contains('~')
+}
+
+/// Signs the claims with the `sign-0` key of the account.
+pub async fn sign(account: &Account, claims: &Value) -> Result<String> {
+    let method = account
+        .document()
+        .resolve_method(SIGNING_METHOD, None)
+        .ok_or_else(|| Error::NotFound(format!("method {}", SIGNING_METHOD)))?;
+    let public_key = method.data().try_decode().map_err(Error::internal)?;
+    let header = json!({
+        "alg": "EdDSA",
+        "typ": "JWT",
+        "kid": method.id().to_string(),
+    });
+    let signing_input = format!("{}.{}", encode_part(&header)?, encode_part(claims)?);
+    let location = KeyLocation::new(KeyType::Ed25519, SIGNING_METHOD.to_string(), &public_key);
+    let signature = account
+        .storage()
+        .key_sign(account.did(), &location, signing_input.as_bytes().to_vec())
+        .await
+        .map_err(Error::internal)?;
+    Ok(format!(
+        "{}.{}",
+        signing_input,
+        base64::encode_config(signature.as_bytes(), base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// The claims of the jwt, without verifying it.
+pub fn decode_claims(jwt: &str) -> Result<Value> {
+    let parts: Vec<&str> = jwt.split('.').collect();
+    if parts.len() != 3 {
+        return Err(Error::BadRequest("jwt: expected three parts".to_string()));
+    }
+    decode_part(parts[1])
+}
+
+/// Verifies the jwt with the key of its `kid`, resolved from the ledger,
+/// and returns the claims.
+pub async fn verify(jwt: &str) -> Result<Value> {
+    let header = decode_header(jwt).map_err(|err| Error::BadRequest(format!("jwt: {}", err)))?;
+    let kid = header
+        .kid
+        .ok_or_else(|| Error::BadRequest("jwt: header without kid".to_string()))?;
+    let (did, fragment) = kid
+        .split_once('#')
+        .ok_or_else(|| Error::BadRequest(format!("jwt: kid {} without fragment", kid)))?;
+    if decode_claims(jwt)?["iss"].as_str() != Some(did) {
+        return Err(Error::BadRequest(format!("jwt: kid {} not of iss", kid)));
+    }
+    let resolved = resolve_did(did).await?;
+    let method = resolved
+        .document
+        .resolve_method(fragment, None)
+        .ok_or_else(|| Error::BadRequest(format!("jwt: unknown method {}", kid)))?;
+    let public_key = method.data().try_decode().map_err(Error::internal)?;
+    validate(jwt, &public_key)
+}
+
+/// Verifies the EdDSA signature of the jwt with the public key, and `exp`
+/// and `nbf` if present, and returns the claims.
+fn validate(jwt: &str, public_key: &[u8]) -> Result<Value> {
+    let mut validation = Validation::new(Algorithm::EdDSA);
+    validation.required_spec_claims = HashSet::new();
+    validation.validate_nbf = true;
+    decode::<Value>(jwt, &DecodingKey::from_ed_der(public_key), &validation)
+        .map(|data| data.claims)
+        .map_err(|err| Error::BadRequest(format!("jwt: {}", err)))
+}
+
+/// VC-JWT claims of the credential, the `vc` claim holds the credential
+/// without proof.
+pub fn credential_claims(credential: &Credential) -> Result<Value> {
+    let mut vc = serde_json::to_value(credential).map_err(Error::internal)?;
+    if let Some(vc) = vc.as_object_mut() {
+        vc.remove("proof");
+    }
+    let mut claims = json!({
+        "iss": credential.issuer.url().to_string(),
+        "nbf": credential.issuance_date.to_unix(),
+        "vc": vc,
+    });
+    if let Some(id) = &credential.id {
+        claims["jti"] = json!(id.to_string());
+    }
+    if let Some(sub) = credential
+        .credential_subject
+        .iter()
+        .find_map(|subject| subject.id.as_ref())
+    {
+        claims["sub"] = json!(sub.to_string());
+    }
+    if let Some(exp) = credential.expiration_date {
+        claims["exp"] = json!(exp.to_unix());
+    }
+    Ok(claims)
+}
+
+/// VP-JWT claims of the presentation. Credentials received as jwt or sd-jwt
+/// are presented as such, the challenge and domain become `nonce` and `aud`.
+pub fn presentation_claims(
+    presentation: &Presentation,
+    credential_jwts: &[Option<String>],
+    challenge: Option<&str>,
+    domain: Option<&str>,
+) -> Result<Value> {
+    let mut vp = serde_json::to_value(presentation).map_err(Error::internal)?;
+    if let Some(vp) = vp.as_object_mut() {
+        vp.remove("proof");
+    }
+    if let Some(credentials) = vp["verifiableCredential"].as_array_mut() {
+        for (credential, jwt) in credentials.iter_mut().zip(credential_jwts) {
+            if let Some(jwt) = jwt {
+                *credential = json!(jwt);
+            }
+        }
+    }
+    let mut claims = json!({
+        "nbf": Timestamp::now_utc().to_unix(),
+        "vp": vp,
+    });
+    if let Some(holder) = &presentation.holder {
+        claims["iss"] = json!(holder.to_string());
+    }
+    if let Some(id) = &presentation.id {
+        claims["jti"] = json!(id.to_string());
+    }
+    if let Some(challenge) = challenge {
+        claims["nonce"] = json!(challenge);
+    }
+    if let Some(domain) = domain {
+        claims["aud"] = json!(domain);
+    }
+    Ok(claims)
+}
+
+/// The credential of the `vc` claim of a VC-JWT, without verifying it.
+pub fn decode_credential(jwt: &str) -> Result<Credential> {
+    let claims = decode_claims(jwt)?;
+    let credential: Credential = serde_json::from_value(claims["vc"].clone())
+        .map_err(|err| Error::BadRequest(format!("jwt: vc claim: {}", err)))?;
+    if claims["iss"].as_str() != Some(credential.issuer.url().as_str()) {
+        return Err(Error::BadRequest("jwt: iss is not the issuer".to_string()));
+    }
+    Ok(credential)
+}
+
+/// Verifies a VP-JWT and the VC-JWTs and SD-JWTs it holds, returns the
+/// presentation of its `vp` claim and the claims.
+pub async fn verify_presentation(jwt: &str) -> Result<(Presentation, Value)> {
+    let claims = verify(jwt).await?;
+    let mut vp = claims["vp"].clone();
+    if let Some(credentials) = vp["verifiableCredential"].as_array_mut() {
+        for credential in credentials.iter_mut() {
+            let decoded = match credential.as_str() {
+                Some(sd_jwt) if sdjwt::is_sd_jwt(sd_jwt) => sdjwt::verify(sd_jwt).await?,
+                Some(jwt) if is_jwt(jwt) => {
+                    verify(jwt).await?;
+                    decode_credential(jwt)?
+                }
+                _ => continue,
+            };
+            *credential = serde_json::to_value(decoded).map_err(Error::internal)?;
+        }
+    }
+    let presentation: Presentation = serde_json::from_value(vp)
+        .map_err(|err| Error::BadRequest(format!("jwt: vp claim: {}", err)))?;
+    if claims["iss"].as_str() != presentation.holder.as_ref().map(|holder| holder.as_str()) {
+        return Err(Error::BadRequest("jwt: iss is not the holder".to_string()));
+    }
+    Ok((presentation, claims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use did_key::{CoreSign, Ed25519KeyPair, Generate, KeyMaterial};
+    use identity_iota::core::{FromJson, Url};
+    use identity_iota::credential::{CredentialBuilder, Subject};
+
+    fn sign_with(key: &Ed25519KeyPair, claims: &Value) -> String {
+        let header = json!({"alg": "EdDSA", "typ": "JWT", "kid": "did:iota:issuer#sign-0"});
+        let signing_input = format!(
+            "{}.{}",
+            encode_part(&header).unwrap(),
+            encode_part(claims).unwrap()
+        );
+        let signature = key.sign(signing_input.as_bytes());
+        format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        let key = Ed25519KeyPair::new_with_seed(&[1; 32]);
+        let public_key = key.public_key_bytes();
+        let now = Timestamp::now_utc().to_unix();
+
+        let claims = json!({"iss": "did:iota:issuer", "nbf": now, "exp": now + 3600});
+        let jwt = sign_with(&key, &claims);
+        assert!(is_jwt(&jwt));
+        assert_eq!(validate(&jwt, &public_key).unwrap(), claims);
+        assert!(validate(
+            &jwt,
+            &Ed25519KeyPair::new_with_seed(&[2; 32]).public_key_bytes()
+        )
+        .is_err());
+
+        let expired = sign_with(&key, &json!({"iss": "did:iota:issuer", "exp": now - 3600}));
+        assert!(validate(&expired, &public_key).is_err());
+        let not_yet_valid = sign_with(&key, &json!({"iss": "did:iota:issuer", "nbf": now + 3600}));
+        assert!(validate(&not_yet_valid, &public_key).is_err());
+        let without_times = sign_with(&key, &json!({"iss": "did:iota:issuer"}));
+        assert!(validate(&without_times, &public_key).is_ok());
+    }
+
+    #[test]
+    fn test_credential_claims() {
+        let credential: Credential = CredentialBuilder::default()
+            .id(Url::parse("urn:uuid:1").unwrap())
+            .issuer(Url::parse("did:iota:issuer").unwrap())
+            .subject(Subject::from_json_value(json!({"id": "did:iota:holder"})).unwrap())
+            .build()
+            .unwrap();
+        let claims = credential_claims(&credential).unwrap();
+        assert_eq!(claims["iss"], "did:iota:issuer");
+        assert_eq!(claims["sub"], "did:iota:holder");
+        assert_eq!(claims["jti"], "urn:uuid:1");
+        assert!(claims["vc"]["proof"].is_null());
+
+        let header = json!({"alg": "EdDSA", "typ": "JWT"});
+        let jwt = format!(
+            "{}.{}.",
+            encode_part(&header).unwrap(),
+            encode_part(&claims).unwrap()
+        );
+        assert!(is_jwt(&jwt));
+        assert_eq!(decode_credential(&jwt).unwrap(), credential);
+        assert!(!is_jwt("urn:uuid:1"));
+    }
+}
@@ -1,13 +1,13 @@
+use crate::auth::AdminAuth;
+use crate::error::{Error, Result};
+use crate::wallet::resolve_did;
 use base58::ToBase58;
 use identity_iota::client::ResolvedIotaDocument;
-use identity_iota::client::Resolver;
-use identity_iota::iota_core::IotaDID;
 use rocket::{get, serde::json::Json};
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct EndpointResponse {
@@ -24,37 +24,40 @@ pub struct VerkeyResponse {
 /// Returns verkey in base58.
 #[openapi(tag = "ledger")]
 #[get("/ledger/did-verkey?<did>")]
-pub async fn get_did_verkey(did: String) -> Json<VerkeyResponse> {
-    let did = IotaDID::from_str(&did).unwrap();
-
-    let resolver: Resolver = Resolver::new().await.unwrap();
-    let resolved_did_document: ResolvedIotaDocument = resolver.resolve(&did).await.unwrap();
+pub async fn get_did_verkey(_auth: AdminAuth, did: String) -> Result<Json<VerkeyResponse>> {
+    let resolved_did_document: ResolvedIotaDocument = resolve_did(&did).await?;
 
     let document = resolved_did_document.document;
-    let verkey = document.default_signing_method().unwrap();
-    let verkey = verkey.data().try_decode().unwrap().to_base58();
-    Json(VerkeyResponse { verkey })
+    let verkey = document
+        .default_signing_method()
+        .map_err(|err| Error::NotFound(format!("signing method of {}: {}", did, err)))?;
+    let verkey = verkey
+        .data()
+        .try_decode()
+        .map_err(Error::internal)?
+        .to_base58();
+    Ok(Json(VerkeyResponse { verkey }))
 }
 
 /// # Get the endpoint for a DID from the ledger.
 #[openapi(tag = "ledger")]
 #[get("/ledger/did-endpoint?<did>")]
-pub async fn get_did_endpoint(did: String) -> Json<EndpointResponse> {
-    let did = IotaDID::from_str(&did).unwrap();
-
-    let resolver: Resolver = Resolver::new().await.unwrap();
-    let resolved_did_document: ResolvedIotaDocument = resolver.resolve(&did).await.unwrap();
+pub async fn get_did_endpoint(_auth: AdminAuth, did: String) -> Result<Json<EndpointResponse>> {
+    let resolved_did_document: ResolvedIotaDocument = resolve_did(&did).await?;
 
     let document = resolved_did_document.document;
     let services = document.service();
-    let service = services.get(0).unwrap();
-    Json(EndpointResponse {
+    let service = services
+        .get(0)
+        .ok_or_else(|| Error::NotFound(format!("no service endpoint for {}", did)))?;
+    Ok(Json(EndpointResponse {
         endpoint: service.service_endpoint().to_string(),
-    })
+    }))
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::Problem;
     use crate::test_rocket;
     use crate::Config;
     use rocket::http::Status;
@@ -74,6 +77,14 @@ mod tests {
             .dispatch()
             .await;
         assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/ledger/did-endpoint?did=did:foo:bar")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let problem: Problem = response.into_json().await.unwrap();
+        assert_eq!(problem.status, 400);
     }
 
     #[tokio::test]
@@ -5,36 +5,40 @@ pub use didcomm_mediator::wallet::Wallet;
 use rocket::get;
 use rocket::response::Redirect;
 use rocket::{Build, Rocket};
-use rocket_okapi::{openapi, openapi_get_routes, swagger_ui::*};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use rocket_okapi::settings::OpenApiSettings;
+use rocket_okapi::{get_openapi_route, openapi, openapi_get_routes_spec, swagger_ui::*};
 use webhook::WebhookPool;
 
+pub mod auth;
+pub mod autorespond;
 pub mod configext;
 pub mod connection;
 pub mod credential;
 pub mod didcomm;
+pub mod error;
+pub mod exchange;
 pub mod jsonld;
+pub mod jwt;
 pub mod keyutils;
 pub mod ledger;
 pub mod message;
 pub mod ping;
 pub mod presentation;
 pub mod resolver;
+pub mod revocation;
 pub mod schema;
+pub mod sdjwt;
 pub mod server;
+pub mod storage;
+pub mod tenant;
 mod tests;
 pub mod topic;
 pub mod wallet;
 pub mod webhook;
 pub use configext::ConfigExt;
-use connection::{ConnectionEvents, Connections};
-use credential::{Credentials, IssueCredentialEvents};
 pub use didcomm::DidComm;
-use message::MessageEvents;
-use ping::PingEvents;
-use presentation::PresentProofEvents;
 use schema::Schemas;
+use tenant::{Agent, Tenants};
 pub use webhook::Webhook;
 
 #[openapi(skip)]
@@ -49,81 +53,94 @@ pub async fn rocket(
     webhook_pool: WebhookPool,
     didcomm: Box<dyn didcomm::DidComm>,
 ) -> Rocket<Build> {
-    let connections: Connections = Connections::default();
-    let credentials: Credentials = Credentials::default();
-    let schemas: Schemas = Schemas::default();
+    let config_ext: ConfigExt = rocket.figment().extract().expect("config ext");
+    let storage = storage::from_config(&config_ext.storage).expect("storage");
+    if config_ext.admin_api_key.is_none() && config_ext.admin_jwt_secret.is_none() {
+        warn!("no admin_api_key or admin_jwt_secret configured, admin api is open");
+    }
 
-    let cloned_config = config.clone();
-    let wallet = Wallet::new_from_config(&cloned_config).await.unwrap();
-    wallet.log();
-    let wallet = Arc::new(Mutex::new(wallet));
+    let schemas: Schemas = Schemas::new(storage.clone());
+    schemas.schemas.migrate().expect("migrate schemas");
 
-    let connection_events: Arc<Mutex<ConnectionEvents>> =
-        Arc::new(Mutex::new(ConnectionEvents::new()));
-    let issue_credential_events: Arc<Mutex<IssueCredentialEvents>> =
-        Arc::new(Mutex::new(IssueCredentialEvents::new()));
-    let ping_events: Arc<Mutex<PingEvents>> = Arc::new(Mutex::new(PingEvents::new()));
-    let message_events: Arc<Mutex<MessageEvents>> = Arc::new(Mutex::new(MessageEvents::new()));
-    let present_proof_events: Arc<Mutex<PresentProofEvents>> =
-        Arc::new(Mutex::new(PresentProofEvents::new()));
+    let agent = Agent::new(&config, storage.clone(), webhook_pool)
+        .await
+        .expect("agent");
+    let tenants = Tenants::new(config.clone(), storage, agent)
+        .await
+        .expect("tenants");
 
-    let mut webhook_pool = webhook_pool;
-
-    webhook_pool
-        .spawn_connection_events(connection_events.clone())
-        .await;
-    webhook_pool
-        .spawn_issue_credential_events(issue_credential_events.clone())
-        .await;
-    webhook_pool.spawn_ping_events(ping_events.clone()).await;
-    webhook_pool
-        .spawn_message_events(message_events.clone())
-        .await;
-    webhook_pool
-        .spawn_present_proof_events(present_proof_events.clone())
-        .await;
+    let (mut routes, mut spec) = openapi_get_routes_spec![
+        index,
+        connection::post_create_invitation,
+        connection::post_receive_invitation,
+        connection::get_invitation_qr_code,
+        connection::get_all_invitations,
+        connection::get_invitation,
+        connection::delete_invitation,
+        connection::get_invitation_record_qr_code,
+        connection::get_all_connections,
+        connection::get_connection,
+        connection::delete_connection,
+        connection::get_connection_endpoints,
+        connection::get_connection_metadata,
+        connection::post_connection_metadata,
+        connection::post_connection_auto_respond,
+        credential::issue::post_send_proposal_2,
+        credential::issue::post_send_offer_2,
+        credential::issue::post_send_2,
+        credential::issue::post_record_send_offer,
+        credential::issue::post_record_send_request,
+        credential::issue::post_record_issue,
+        credential::issue::post_record_store,
+        credential::exchange::get_all_records,
+        credential::exchange::get_record,
+        credential::exchange::post_record_auto_respond,
+        credential::exchange::delete_record,
+        credential::get_all_credentials,
+        credential::get_credential,
+        credential::delete_credential,
+        credential::put_credential_tags,
+        didcomm::didcomm_options,
+        didcomm::post_endpoint,
+        ledger::get_did_verkey,
+        ledger::get_did_endpoint,
+        jsonld::post_sign,
+        jsonld::post_verify,
+        message::post_send_message,
+        ping::post_send_ping,
+        presentation::proposal::post_send_proposal,
+        presentation::post_send_presentation,
+        presentation::request::post_send_request,
+        presentation::request::post_record_send_presentation,
+        presentation::exchange::get_all_records,
+        presentation::exchange::get_record,
+        presentation::exchange::delete_record,
+        resolver::get_resolve,
+        revocation::post_revoke,
+        revocation::post_unrevoke,
+        schema::post_schemas,
+        schema::get_all_schemas,
+        server::get_live,
+        server::get_ready,
+        tenant::post_tenant,
+        tenant::get_all_tenants,
+        tenant::delete_tenant,
+        topic::post_topic,
+        topic::post_message_topic,
+        wallet::get_all_dids,
+        wallet::get_public_did,
+        wallet::get_did_endpoint,
+        wallet::post_did_endpoint,
+        webhook::get_all_webhooks,
+        webhook::post_webhook,
+        webhook::delete_webhook,
+    ];
+    auth::add_bearer_requirement(&mut spec);
+    routes.push(get_openapi_route(spec, &OpenApiSettings::default()));
 
     rocket
-        .mount(
-            "/",
-            openapi_get_routes![
-                index,
-                connection::post_create_invitation,
-                connection::post_receive_invitation,
-                connection::get_all_connections,
-                connection::get_connection,
-                connection::delete_connection,
-                connection::get_connection_endpoints,
-                credential::issue::post_send_proposal_2,
-                credential::issue::post_send_offer_2,
-                credential::issue::post_send_2,
-                credential::get_all_credentials,
-                didcomm::didcomm_options,
-                didcomm::post_endpoint,
-                ledger::get_did_verkey,
-                ledger::get_did_endpoint,
-                jsonld::post_sign,
-                jsonld::post_verify,
-                message::post_send_message,
-                ping::post_send_ping,
-                presentation::proposal::post_send_proposal,
-                presentation::post_send_presentation,
-                resolver::get_resolve,
-                schema::post_schemas,
-                schema::get_all_schemas,
-                server::get_live,
-                server::get_ready,
-                topic::post_topic,
-                topic::post_message_topic,
-                wallet::get_all_dids,
-                wallet::get_public_did,
-                wallet::get_did_endpoint,
-                wallet::post_did_endpoint,
-                webhook::get_all_webhooks,
-                webhook::post_webhook,
-                webhook::delete_webhook,
-            ],
-        )
+        .mount("/", routes)
+        .register("/", catchers![auth::unauthorized])
         .mount(
             "/swagger-ui/",
             make_swagger_ui(&SwaggerUIConfig {
@@ -132,17 +149,10 @@ pub async fn rocket(
             }),
         )
         .manage(config)
-        .manage(wallet)
-        .manage(connections)
-        .manage(credentials)
+        .manage(config_ext)
+        .manage(tenants)
         .manage(schemas)
-        .manage(webhook_pool)
         .manage(didcomm)
-        .manage(connection_events)
-        .manage(issue_credential_events)
-        .manage(ping_events)
-        .manage(message_events)
-        .manage(present_proof_events)
 }
 
 #[cfg(test)]
@@ -156,7 +166,7 @@ pub async fn test_rocket() -> Rocket<Build> {
 
 #[cfg(test)]
 pub async fn test_rocket_with_webhook_client(
-    webhook_client: Arc<Mutex<Box<dyn webhook::Webhook>>>,
+    webhook_client: std::sync::Arc<tokio::sync::Mutex<Box<dyn webhook::Webhook>>>,
 ) -> Rocket<Build> {
     let rocket = rocket::build();
     let figment = rocket.figment();
@@ -169,7 +179,7 @@ pub async fn test_rocket_with_webhook_client(
     };
 
     let webhook_pool = WebhookPool::default();
-    webhook_pool.webhooks.try_lock().unwrap().insert(
+    webhook_pool.webhooks.lock().await.insert(
         webhook_endpoint.id.as_ref().unwrap().to_string(),
         (webhook_endpoint, webhook_client),
     );
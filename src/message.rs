@@ -1,11 +1,13 @@
+use crate::auth::AdminAuth;
 use crate::connection::Connections;
 use crate::didcomm::sign_and_encrypt;
-use crate::wallet::Wallet;
+use crate::error::{Error, Result};
+use crate::tenant::TenantState;
+use crate::wallet::{wallet_did, Wallet};
 use did_key::KeyMaterial;
 use didcomm_mediator::protocols::basicmessage::BasicMessageBuilder;
 use identity_iota::prelude::{KeyPair, KeyType};
 use rocket::http::Status;
-use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
@@ -55,45 +57,46 @@ impl Observable<MessageEvent> for MessageEvents {
 #[openapi(tag = "basicmessage")]
 #[post("/connections/<conn_id>/send-message", data = "<payload>")]
 pub async fn post_send_message(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    connections: &State<Connections>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
     conn_id: String,
     payload: Json<Value>,
-) -> Status {
+) -> Result<Status> {
+    let connection = connections.get(&conn_id)?;
+    let (did_to, endpoint) = (connection.did.to_string(), connection.endpoint);
     let (my_did, private_key) = {
-        let wallet = wallet.try_lock().unwrap();
+        let wallet = wallet.lock().await;
         (
-            wallet.did_iota().unwrap(),
+            wallet_did(&wallet)?.to_string(),
             wallet.keypair().private_key_bytes(),
         )
     };
-    let (did_to, endpoint) = {
-        let connections = connections.connections.lock().await;
-        let connection = connections.get(&conn_id).unwrap().clone();
-        (connection.did.to_string(), connection.endpoint)
-    };
-
-    let payload = serde_json::to_string(&payload.into_inner()).unwrap();
-    let message = BasicMessageBuilder::new().message(payload).build().unwrap();
 
-    let keypair = KeyPair::try_from_private_key_bytes(KeyType::X25519, &private_key).unwrap();
+    let payload = serde_json::to_string(&payload.into_inner()).map_err(Error::internal)?;
+    let message = BasicMessageBuilder::new()
+        .message(payload)
+        .build()
+        .map_err(Error::internal)?;
+    let keypair = KeyPair::try_from_private_key_bytes(KeyType::X25519, &private_key)
+        .map_err(Error::internal)?;
     let message_request = sign_and_encrypt(&message, &my_did, &did_to, &keypair)
         .await
-        .unwrap();
+        .map_err(|err| Error::PeerUnreachable(format!("{}: {:?}", did_to, err)))?;
 
     let client = reqwest::Client::new();
-    let _res = client
+    client
         .post(endpoint.to_string())
         .json(&message_request)
         .send()
-        .await
-        .unwrap();
-    Status::Ok
+        .await?
+        .error_for_status()?;
+    Ok(Status::Ok)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::connection::Connection;
+    use crate::connection::{Connection, ConnectionState};
     use crate::test_rocket;
     use rocket::http::{ContentType, Status};
     use rocket::local::asynchronous::Client;
@@ -116,7 +119,7 @@ mod tests {
             .await;
         assert_eq!(response.status(), Status::Ok);
         let invitation: Value = response.into_json::<Value>().await.unwrap();
-        let invitation: String = serde_json::to_string(&invitation).unwrap();
+        let invitation: String = serde_json::to_string(&invitation["invitation"]).unwrap();
 
         let response = client
             .post("/out-of-band/receive-invitation")
@@ -131,7 +134,12 @@ mod tests {
         let response = response.into_json::<Value>().await.unwrap();
         let connections: Vec<Connection> = from_value(response).unwrap();
 
-        let connection_id = connections[0].id.to_string();
+        let connection_id = connections
+            .iter()
+            .find(|connection| connection.state != ConnectionState::InvitationSent)
+            .unwrap()
+            .id
+            .to_string();
 
         let response = client
             .post(format!("/connections/{}/send-message", connection_id))
@@ -1,10 +1,12 @@
+use crate::auth::AdminAuth;
 use crate::connection::Connections;
+use crate::error::{Error, Result};
+use crate::tenant::TenantState;
+use crate::wallet::wallet_did;
 use crate::Wallet;
 use did_key::KeyMaterial;
 use didcomm_mediator::message::{add_return_route_all_header, receive, sign_and_encrypt};
 use didcomm_protocols::TrustPingResponseBuilder;
-use rocket::http::Status;
-use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
@@ -54,41 +56,39 @@ impl Observable<PingEvent> for PingEvents {
 #[openapi(tag = "trustping")]
 #[post("/connections/<conn_id>/send-ping")]
 pub async fn post_send_ping(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    connections: &State<Connections>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
     conn_id: String,
-) -> Result<Json<Value>, Status> {
-    let wallet = wallet.try_lock().unwrap();
+) -> Result<Json<Value>> {
+    let connection = connections.get(&conn_id)?;
+    let (did_to, endpoint) = (connection.did.to_string(), connection.endpoint);
 
-    let (did_to, endpoint) = {
-        let connections = connections.connections.lock().await;
-        let connection = connections.get(&conn_id).unwrap().clone();
-        (connection.did.to_string(), connection.endpoint)
+    let (did_from, keypair) = {
+        let wallet = wallet.lock().await;
+        (wallet_did(&wallet)?.to_string(), wallet.keypair())
     };
-    let did_from = wallet.did_iota().unwrap();
-    let keypair = wallet.keypair();
-    drop(wallet);
-    let mut message = TrustPingResponseBuilder::new().build_ping().unwrap();
+    let mut message = TrustPingResponseBuilder::new()
+        .build_ping()
+        .map_err(Error::internal)?;
     message = add_return_route_all_header(message);
     let ping = sign_and_encrypt(&message, &did_from, &did_to, &keypair)
         .await
-        .unwrap();
+        .map_err(|err| Error::PeerUnreachable(format!("{}: {:?}", did_to, err)))?;
 
     let client = reqwest::Client::new();
     let res = client
         .post(endpoint.to_string())
         .json(&ping)
         .send()
-        .await
-        .unwrap();
-    let json: Value = res.json().await.unwrap();
-    let body_str = serde_json::to_string(&json).unwrap();
+        .await?
+        .error_for_status()?;
+    let body_str = res.text().await?;
 
-    let received = match receive(&body_str, Some(&keypair.private_key_bytes()), None, None).await {
-        Ok(received) => received,
-        Err(_) => return Err(Status::BadRequest),
-    };
-    let received: Value = serde_json::to_value(&received).unwrap();
+    let received = receive(&body_str, Some(&keypair.private_key_bytes()), None, None)
+        .await
+        .map_err(|err| Error::PeerUnreachable(format!("invalid ping response: {:?}", err)))?;
+    let received: Value = serde_json::to_value(&received).map_err(Error::internal)?;
     Ok(Json(received))
 }
 
@@ -113,7 +113,13 @@ mod tests {
             .post(format!("/connections/{}/send-ping", connection_id))
             .dispatch()
             .await;
-        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(response.status(), Status::BadGateway);
+
+        let response = client
+            .post("/connections/unknown/send-ping")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
     }
 
     #[tokio::test]
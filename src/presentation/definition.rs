@@ -0,0 +1,608 @@
+use crate::credential::{CredentialQuery, CredentialRecord, Credentials};
+use crate::error::{Error, Result};
+use crate::jwt::CredentialFormat;
+use crate::sdjwt;
+use jsonschema::JSONSchema;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Property of the presentation holding its submission.
+pub const PRESENTATION_SUBMISSION: &str = "presentation_submission";
+
+/// What a verifier asks to be presented, as DIF Presentation Exchange v2
+/// definition.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationDefinition {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    /// One credential is presented for each descriptor.
+    pub input_descriptors: Vec<InputDescriptor>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct InputDescriptor {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    #[serde(default)]
+    pub constraints: Constraints,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Constraints {
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit_disclosure: Option<LimitDisclosure>,
+}
+
+/// Whether the holder must present the constrained fields only.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitDisclosure {
+    Required,
+    Preferred,
+}
+
+/// A credential property constrained by JSON paths into the credential and a
+/// JSON schema its value must match. String values also match as the number
+/// or boolean they spell, as preview attributes are strings.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Field {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Paths like `$.credentialSubject.age`, the first one selecting a
+    /// matching value satisfies the field.
+    pub path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+/// Maps the input descriptors of a definition to the presented credentials.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationSubmission {
+    pub id: String,
+    pub definition_id: String,
+    pub descriptor_map: Vec<DescriptorMapping>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DescriptorMapping {
+    /// Id of the input descriptor.
+    pub id: String,
+    pub format: CredentialFormat,
+    /// Path of the credential in the presentation.
+    pub path: String,
+}
+
+/// The stored credentials chosen for the input descriptors of a definition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub definition_id: String,
+    /// Pairs of input descriptor id and credential id.
+    pub descriptors: Vec<(String, String)>,
+}
+
+impl Assignment {
+    /// The assigned credentials, each once.
+    pub fn credential_ids(&self) -> Vec<String> {
+        let mut credential_ids: Vec<String> = Vec::new();
+        for (_, credential_id) in &self.descriptors {
+            if !credential_ids.contains(credential_id) {
+                credential_ids.push(credential_id.to_string());
+            }
+        }
+        credential_ids
+    }
+
+    /// The submission of a presentation of the credentials in the given order
+    /// and formats.
+    pub fn submission(
+        &self,
+        credential_ids: &[String],
+        formats: &[CredentialFormat],
+    ) -> Result<PresentationSubmission> {
+        let descriptor_map = self
+            .descriptors
+            .iter()
+            .map(|(descriptor_id, credential_id)| {
+                let index = credential_ids
+                    .iter()
+                    .position(|id| id == credential_id)
+                    .ok_or_else(|| {
+                        Error::Internal(format!("credential {} not presented", credential_id))
+                    })?;
+                Ok(DescriptorMapping {
+                    id: descriptor_id.to_string(),
+                    format: formats.get(index).copied().unwrap_or_default(),
+                    path: format!("$.verifiableCredential[{}]", index),
+                })
+            })
+            .collect::<Result<Vec<DescriptorMapping>>>()?;
+        Ok(PresentationSubmission {
+            id: Uuid::new_v4().to_string(),
+            definition_id: self.definition_id.to_string(),
+            descriptor_map,
+        })
+    }
+}
+
+impl Field {
+    fn compile_filter(&self) -> Result<Option<JSONSchema>> {
+        self.filter
+            .as_ref()
+            .map(|filter| {
+                JSONSchema::compile(filter)
+                    .map_err(|err| Error::BadRequest(format!("filter: {}", err)))
+            })
+            .transpose()
+    }
+
+    /// Whether one of the paths selects a value matching the filter.
+    pub fn matches(&self, credential: &Value) -> Result<bool> {
+        if self.optional {
+            return Ok(true);
+        }
+        let filter = self.compile_filter()?;
+        for path in &self.path {
+            let matched = select(credential, path)?
+                .into_iter()
+                .any(|value| match &filter {
+                    Some(filter) => {
+                        filter.is_valid(value)
+                            || typed(value).map_or(false, |typed| filter.is_valid(&typed))
+                    }
+                    None => true,
+                });
+            if matched {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl InputDescriptor {
+    /// Whether the credential satisfies all fields of the descriptor.
+    pub fn matches(&self, credential: &Value) -> Result<bool> {
+        let credential = subject_claims(credential);
+        for field in &self.constraints.fields {
+            if !field.matches(&credential)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl PresentationDefinition {
+    /// Rejects definitions without descriptors, with duplicate descriptor ids,
+    /// unsupported paths or invalid filters.
+    pub fn validate(&self) -> Result<()> {
+        if self.input_descriptors.is_empty() {
+            return Err(Error::BadRequest(
+                "presentation definition without input descriptors".to_string(),
+            ));
+        }
+        for (index, descriptor) in self.input_descriptors.iter().enumerate() {
+            if self.input_descriptors[..index]
+                .iter()
+                .any(|other| other.id == descriptor.id)
+            {
+                return Err(Error::BadRequest(format!(
+                    "duplicate input descriptor {}",
+                    descriptor.id
+                )));
+            }
+            for field in &descriptor.constraints.fields {
+                for path in &field.path {
+                    parse_path(path)?;
+                }
+                field.compile_filter()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any descriptor requires limiting the disclosed claims.
+    pub fn limits_disclosure(&self) -> bool {
+        self.input_descriptors.iter().any(|descriptor| {
+            descriptor.constraints.limit_disclosure == Some(LimitDisclosure::Required)
+        })
+    }
+
+    /// Names of the subject claims the fields constrain, which are also the
+    /// names of the preview attributes.
+    pub fn disclosed_claims(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let paths = self
+            .input_descriptors
+            .iter()
+            .flat_map(|descriptor| descriptor.constraints.fields.iter())
+            .flat_map(|field| field.path.iter());
+        for path in paths {
+            let segments = parse_path(path).unwrap_or_default();
+            let name = segments.windows(2).find_map(|window| match window {
+                [Segment::Name(subject), Segment::Name(name)] if subject == "credentialSubject" => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            });
+            if let Some(name) = name {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Picks a valid stored credential for each input descriptor, among the
+    /// given credentials if set.
+    pub fn assign(
+        &self,
+        credentials: &Credentials,
+        credential_ids: Option<&[String]>,
+    ) -> Result<Assignment> {
+        let valid = CredentialQuery {
+            valid: Some(true),
+            ..Default::default()
+        };
+        let candidates: Vec<CredentialRecord> = match credential_ids {
+            Some(credential_ids) => credential_ids
+                .iter()
+                .map(|credential_id| credentials.get_record(credential_id))
+                .collect::<Result<Vec<CredentialRecord>>>()?,
+            None => credentials
+                .credentials
+                .values()?
+                .into_iter()
+                .filter(|record| valid.matches(record))
+                .collect(),
+        };
+        let mut descriptors = Vec::new();
+        for descriptor in &self.input_descriptors {
+            let mut assigned = None;
+            for record in &candidates {
+                let credential =
+                    serde_json::to_value(&record.credential).map_err(Error::internal)?;
+                if descriptor.matches(&credential)? {
+                    assigned = record.credential.id.as_ref().map(|id| id.to_string());
+                    break;
+                }
+            }
+            let credential_id =
+                assigned.ok_or_else(|| Error::CredentialNotFound(descriptor.id.to_string()))?;
+            descriptors.push((descriptor.id.to_string(), credential_id));
+        }
+        Ok(Assignment {
+            definition_id: self.id.to_string(),
+            descriptors,
+        })
+    }
+
+    /// Rejects the assignment if a descriptor requiring limited disclosure is
+    /// answered by a credential that can not disclose claims selectively, as
+    /// only SD-JWT credentials can.
+    pub fn check_disclosure(
+        &self,
+        credentials: &Credentials,
+        assignment: &Assignment,
+    ) -> Result<()> {
+        for descriptor in &self.input_descriptors {
+            if descriptor.constraints.limit_disclosure != Some(LimitDisclosure::Required) {
+                continue;
+            }
+            let credential_ids = assignment
+                .descriptors
+                .iter()
+                .filter(|(descriptor_id, _)| descriptor_id == &descriptor.id)
+                .map(|(_, credential_id)| credential_id);
+            for credential_id in credential_ids {
+                let record = credentials.get_record(credential_id)?;
+                if !record.jwt.as_deref().map_or(false, sdjwt::is_sd_jwt) {
+                    return Err(Error::BadRequest(format!(
+                        "input descriptor {} requires limited disclosure, credential {} is no sd-jwt",
+                        descriptor.id, credential_id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Verifier side: checks the submission of the presentation answers each
+/// input descriptor with a credential matching its constraints. Returns the
+/// failed checks.
+pub fn evaluate(definition: &PresentationDefinition, presentation: &Value) -> Vec<String> {
+    let submission: PresentationSubmission =
+        match serde_json::from_value(presentation[PRESENTATION_SUBMISSION].clone()) {
+            Ok(submission) => submission,
+            Err(err) => return vec![format!("presentation submission: {}", err)],
+        };
+    let mut errors = Vec::new();
+    if submission.definition_id != definition.id {
+        errors.push(format!(
+            "submission answers definition {}",
+            submission.definition_id
+        ));
+    }
+    for mapping in &submission.descriptor_map {
+        if !definition
+            .input_descriptors
+            .iter()
+            .any(|descriptor| descriptor.id == mapping.id)
+        {
+            errors.push(format!("unknown input descriptor {}", mapping.id));
+        }
+    }
+    for descriptor in &definition.input_descriptors {
+        let mappings: Vec<&DescriptorMapping> = submission
+            .descriptor_map
+            .iter()
+            .filter(|mapping| mapping.id == descriptor.id)
+            .collect();
+        if mappings.is_empty() {
+            errors.push(format!(
+                "no credential for input descriptor {}",
+                descriptor.id
+            ));
+        }
+        for mapping in mappings {
+            let credential = match select(presentation, &mapping.path) {
+                Ok(selected) => selected.into_iter().next(),
+                Err(err) => {
+                    errors.push(err.to_string());
+                    continue;
+                }
+            };
+            match credential.map(|credential| descriptor.matches(credential)) {
+                Some(Ok(true)) => (),
+                Some(Ok(false)) => errors.push(format!(
+                    "credential for input descriptor {} does not match its constraints",
+                    descriptor.id
+                )),
+                Some(Err(err)) => errors.push(err.to_string()),
+                None => errors.push(format!(
+                    "no credential at {} for input descriptor {}",
+                    mapping.path, descriptor.id
+                )),
+            }
+        }
+    }
+    errors
+}
+
+/// The credential with the preview attributes of its subjects, held as
+/// `credentialSubject.attributes[{name, value}]`, as subject claims so paths
+/// like `$.credentialSubject.age` select them.
+fn subject_claims(credential: &Value) -> Value {
+    let mut credential = credential.clone();
+    let subjects: Vec<&mut Value> = match credential.get_mut("credentialSubject") {
+        Some(Value::Array(subjects)) => subjects.iter_mut().collect(),
+        Some(subject) => vec![subject],
+        None => Vec::new(),
+    };
+    for subject in subjects.into_iter().filter_map(Value::as_object_mut) {
+        let attributes = match subject.get("attributes") {
+            Some(Value::Array(attributes)) => attributes.clone(),
+            _ => continue,
+        };
+        for attribute in attributes {
+            if let Some(name) = attribute["name"].as_str() {
+                subject
+                    .entry(name)
+                    .or_insert_with(|| attribute["value"].clone());
+            }
+        }
+    }
+    credential
+}
+
+/// The number or boolean a string value spells.
+fn typed(value: &Value) -> Option<Value> {
+    value
+        .as_str()
+        .and_then(|value| serde_json::from_str::<Value>(value).ok())
+        .filter(|typed| typed.is_number() || typed.is_boolean())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Name(String),
+    Index(usize),
+    Wildcard,
+}
+
+/// Parses the JSON paths of the forms `$.a.b`, `$['a']`, `$.a[0]` and
+/// `$.a[*]`.
+fn parse_path(path: &str) -> Result<Vec<Segment>> {
+    let invalid = || Error::BadRequest(format!("unsupported json path {}", path));
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(|c| c == '.' || c == '[').unwrap_or(after.len());
+            let name = &after[..end];
+            segments.push(match name {
+                "" => return Err(invalid()),
+                "*" => Segment::Wildcard,
+                name => Segment::Name(name.to_string()),
+            });
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let selector = &after[..end];
+            let quoted = selector
+                .strip_prefix('\'')
+                .and_then(|name| name.strip_suffix('\''))
+                .or_else(|| {
+                    selector
+                        .strip_prefix('"')
+                        .and_then(|name| name.strip_suffix('"'))
+                });
+            segments.push(match (selector, quoted) {
+                ("*", _) => Segment::Wildcard,
+                (_, Some(name)) => Segment::Name(name.to_string()),
+                (index, None) => Segment::Index(index.parse().map_err(|_| invalid())?),
+            });
+            rest = &after[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(segments)
+}
+
+/// The values the JSON path selects.
+fn select<'a>(value: &'a Value, path: &str) -> Result<Vec<&'a Value>> {
+    let mut selected = vec![value];
+    for segment in parse_path(path)? {
+        selected = selected
+            .into_iter()
+            .flat_map(|value| -> Vec<&'a Value> {
+                match (&segment, value) {
+                    (Segment::Name(name), Value::Object(object)) => {
+                        object.get(name).into_iter().collect()
+                    }
+                    (Segment::Index(index), Value::Array(array)) => {
+                        array.get(*index).into_iter().collect()
+                    }
+                    (Segment::Wildcard, Value::Array(array)) => array.iter().collect(),
+                    (Segment::Wildcard, Value::Object(object)) => object.values().collect(),
+                    _ => Vec::new(),
+                }
+            })
+            .collect();
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::CredentialVerification;
+    use identity_iota::core::{FromJson, Url};
+    use identity_iota::credential::{Credential, CredentialBuilder, Subject};
+    use serde_json::json;
+
+    fn definition() -> PresentationDefinition {
+        serde_json::from_value(json!({
+            "id": "age-check",
+            "input_descriptors": [{
+                "id": "age",
+                "constraints": {
+                    "limit_disclosure": "required",
+                    "fields": [
+                        {"path": ["$.type[*]"], "filter": {"const": "AgeCredential"}},
+                        {"path": ["$.credentialSubject.age"], "filter": {"type": "number", "minimum": 18}}
+                    ]
+                }
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_select() {
+        let value = json!({"a": {"b": [1, {"c": 2}]}});
+        assert_eq!(select(&value, "$.a.b[0]").unwrap(), vec![&json!(1)]);
+        assert_eq!(select(&value, "$['a'].b[1].c").unwrap(), vec![&json!(2)]);
+        assert_eq!(select(&value, "$.a.b[*]").unwrap().len(), 2);
+        assert!(select(&value, "$.a.x").unwrap().is_empty());
+        assert!(select(&value, "a.b").is_err());
+        assert!(select(&value, "$..b").is_err());
+    }
+
+    #[test]
+    fn test_assign_and_evaluate() {
+        let definition = definition();
+        definition.validate().unwrap();
+        assert!(definition.limits_disclosure());
+        assert_eq!(definition.disclosed_claims(), vec!["age".to_string()]);
+
+        let credentials = Credentials::default();
+        for (id, age) in [("urn:uuid:1", 16), ("urn:uuid:2", 42)] {
+            let credential: Credential = CredentialBuilder::default()
+                .id(Url::parse(id).unwrap())
+                .issuer(Url::parse("did:iota:issuer").unwrap())
+                .type_("AgeCredential")
+                .subject(Subject::from_json_value(json!({ "age": age })).unwrap())
+                .build()
+                .unwrap();
+            let verification = CredentialVerification {
+                verified: true,
+                errors: Vec::new(),
+                verified_at: "".to_string(),
+            };
+            credentials
+                .insert(credential, Some(verification), None)
+                .unwrap();
+        }
+        let assignment = definition.assign(&credentials, None).unwrap();
+        assert_eq!(assignment.credential_ids(), vec!["urn:uuid:2".to_string()]);
+        // disclosure of a credential issued as json-ld can not be limited
+        assert!(matches!(
+            definition.check_disclosure(&credentials, &assignment),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(definition
+            .assign(&credentials, Some(&["urn:uuid:1".to_string()]))
+            .is_err());
+
+        let submission = assignment
+            .submission(&assignment.credential_ids(), &[CredentialFormat::LdpVc])
+            .unwrap();
+        assert_eq!(
+            submission.descriptor_map[0].path,
+            "$.verifiableCredential[0]"
+        );
+        let credential = credentials.get_record("urn:uuid:2").unwrap().credential;
+        let mut presentation = json!({
+            "verifiableCredential": [credential],
+            PRESENTATION_SUBMISSION: submission,
+        });
+        assert!(evaluate(&definition, &presentation).is_empty());
+
+        presentation["verifiableCredential"][0]["credentialSubject"]["age"] = json!(16);
+        assert_eq!(evaluate(&definition, &presentation).len(), 1);
+        presentation[PRESENTATION_SUBMISSION] = Value::Null;
+        assert_eq!(evaluate(&definition, &presentation).len(), 1);
+    }
+
+    #[test]
+    fn test_preview_attributes() {
+        let descriptor = &definition().input_descriptors[0];
+        let mut credential = json!({
+            "type": ["VerifiableCredential", "AgeCredential"],
+            "credentialSubject": {
+                "id": "did:iota:holder",
+                "attributes": [{"name": "age", "value": "42"}]
+            }
+        });
+        assert!(descriptor.matches(&credential).unwrap());
+        credential["credentialSubject"]["attributes"][0]["value"] = json!("16");
+        assert!(!descriptor.matches(&credential).unwrap());
+        credential["credentialSubject"]["attributes"][0]["value"] = json!("adult");
+        assert!(!descriptor.matches(&credential).unwrap());
+
+        // disclosed by an sd-jwt the attributes are subject claims
+        let credential = json!({
+            "type": ["VerifiableCredential", "AgeCredential"],
+            "credentialSubject": [{"id": "did:iota:holder", "age": "42"}]
+        });
+        assert!(descriptor.matches(&credential).unwrap());
+    }
+}
@@ -1,3 +1,5 @@
+use super::exchange::PresentationExchangeState;
+use super::verification::PresentationVerification;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use {futures::SinkExt, pharos::*};
@@ -5,6 +7,25 @@ use {futures::SinkExt, pharos::*};
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PresentProofEvent {
     ProofReceived(String, Value),
+    StateChanged {
+        thread_id: String,
+        state: PresentationExchangeState,
+    },
+    /// The prover proposed a presentation, answer it with a request on the
+    /// thread.
+    ProposalReceived {
+        from: String,
+        thread_id: String,
+        proposal: Value,
+    },
+    /// A received presentation was verified, the exchange is done if it is
+    /// valid and abandoned otherwise.
+    PresentationVerified {
+        from: String,
+        thread_id: String,
+        presentation: Value,
+        verification: PresentationVerification,
+    },
 }
 
 pub struct PresentProofEvents {
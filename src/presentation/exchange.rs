@@ -0,0 +1,465 @@
+use super::verification::PresentationVerification;
+use super::{PresentProofEvent, PresentProofEvents};
+use crate::auth::AdminAuth;
+use crate::autorespond::AutoRespond;
+use crate::connection::{reuse, Connection, Connections};
+use crate::credential::exchange::application_param;
+use crate::didcomm::{build_message, sender, thread_id, with_thread};
+use crate::error::{Error, Result};
+use crate::exchange::{peer_record, post, ExchangeRecord};
+use crate::storage::{MemoryStorage, Record, Storage, Store};
+use crate::tenant::TenantState;
+use didcomm_rs::Message;
+use identity_iota::core::Timestamp;
+use rocket::http::Status;
+use rocket::{get, serde::json::Json};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const PROPOSE_PRESENTATION: &str = "https://didcomm.org/present-proof/2.1/propose-presentation";
+pub const REQUEST_PRESENTATION: &str = "https://didcomm.org/present-proof/2.1/request-presentation";
+pub const PRESENTATION: &str = "https://didcomm.org/present-proof/2.1/presentation";
+pub const ACK: &str = "https://didcomm.org/present-proof/2.1/ack";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema, FromFormField)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresentationExchangeState {
+    #[field(value = "proposal-sent")]
+    ProposalSent,
+    #[field(value = "proposal-received")]
+    ProposalReceived,
+    #[field(value = "request-sent")]
+    RequestSent,
+    #[field(value = "request-received")]
+    RequestReceived,
+    #[field(value = "presentation-sent")]
+    PresentationSent,
+    #[field(value = "presentation-received")]
+    PresentationReceived,
+    #[field(value = "done")]
+    Done,
+    #[field(value = "abandoned")]
+    Abandoned,
+}
+
+/// Our role in the presentation exchange.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresentationExchangeRole {
+    Verifier,
+    Prover,
+}
+
+/// A present-proof exchange, keyed by its thread id.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationExchangeRecord {
+    pub thread_id: String,
+    pub connection_id: Option<String>,
+    pub their_did: String,
+    pub role: PresentationExchangeRole,
+    pub state: PresentationExchangeState,
+    pub comment: Option<String>,
+    /// What the prover offered to present.
+    #[serde(default)]
+    pub presentation_proposal: Option<Value>,
+    /// What the verifier asked to be presented.
+    pub presentation_request: Option<Value>,
+    pub presentation: Option<Value>,
+    /// Result of verifying the received presentation.
+    #[serde(default)]
+    pub verification: Option<PresentationVerification>,
+    #[serde(default)]
+    pub auto_respond: Option<AutoRespond>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Record for PresentationExchangeRecord {}
+
+impl ExchangeRecord for PresentationExchangeRecord {
+    const NAME: &'static str = "presentation exchange";
+
+    fn thread_id(&self) -> &str {
+        &self.thread_id
+    }
+
+    fn their_did(&self) -> &str {
+        &self.their_did
+    }
+}
+
+impl PresentationExchangeRecord {
+    pub fn new(
+        thread_id: &str,
+        their_did: &str,
+        role: PresentationExchangeRole,
+        state: PresentationExchangeState,
+    ) -> Self {
+        let now = Timestamp::now_utc().to_string();
+        PresentationExchangeRecord {
+            thread_id: thread_id.to_string(),
+            connection_id: None,
+            their_did: their_did.to_string(),
+            role,
+            state,
+            comment: None,
+            presentation_proposal: None,
+            presentation_request: None,
+            presentation: None,
+            verification: None,
+            auto_respond: None,
+            created_at: now.to_string(),
+            updated_at: now,
+        }
+    }
+}
+
+pub struct PresentationExchanges {
+    pub exchanges: Store<PresentationExchangeRecord>,
+}
+
+impl PresentationExchanges {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        PresentationExchanges {
+            exchanges: Store::new(storage, "presentation_exchanges"),
+        }
+    }
+
+    /// Fetches the exchange of a thread, unknown threads are an `Error::NotFound`.
+    pub fn get(&self, thread_id: &str) -> Result<PresentationExchangeRecord> {
+        self.exchanges
+            .get(thread_id)?
+            .ok_or_else(|| Error::NotFound(format!("presentation exchange {}", thread_id)))
+    }
+}
+
+impl Default for PresentationExchanges {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
+}
+
+/// The connection the exchange runs on, looked up by did for inbound exchanges.
+pub fn connection(
+    connections: &Connections,
+    record: &PresentationExchangeRecord,
+) -> Result<Connection> {
+    match &record.connection_id {
+        Some(connection_id) => connections.get(connection_id),
+        None => reuse::find_existing(connections, &record.their_did)?
+            .ok_or_else(|| Error::ConnectionNotFound(record.their_did.to_string())),
+    }
+}
+
+/// The auto-respond policies of an exchange, layered over the connection's
+/// and the global ones.
+pub fn policy(
+    global: &AutoRespond,
+    connections: &Connections,
+    record: &PresentationExchangeRecord,
+) -> AutoRespond {
+    let mut policy = global.clone();
+    if let Some(auto_respond) = connection(connections, record)
+        .ok()
+        .and_then(|connection| connection.auto_respond)
+    {
+        policy = policy.merge(&auto_respond);
+    }
+    if let Some(auto_respond) = &record.auto_respond {
+        policy = policy.merge(auto_respond);
+    }
+    policy
+}
+
+/// Stores the exchange in its new state and notifies the observers.
+pub async fn set_state(
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    record: &mut PresentationExchangeRecord,
+    state: PresentationExchangeState,
+) -> Result<()> {
+    record.state = state;
+    record.updated_at = Timestamp::now_utc().to_string();
+    exchanges
+        .exchanges
+        .insert(&record.thread_id, record.clone())?;
+    present_proof_events
+        .lock()
+        .await
+        .send(PresentProofEvent::StateChanged {
+            thread_id: record.thread_id.to_string(),
+            state: record.state.clone(),
+        })
+        .await;
+    Ok(())
+}
+
+/// Records a message we sent on the connection and posts it to the peer.
+///
+/// Returns the record and the message the peer returned.
+pub async fn send(
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    connection: &Connection,
+    message: &Message,
+    encrypted: &Value,
+    role: PresentationExchangeRole,
+    state: PresentationExchangeState,
+) -> Result<(PresentationExchangeRecord, Value)> {
+    let thread_id = thread_id(message);
+    let previous = exchanges.exchanges.get(&thread_id)?;
+    let mut record = match &previous {
+        Some(record) => record.clone(),
+        None => PresentationExchangeRecord::new(&thread_id, &connection.did, role, state.clone()),
+    };
+    record.connection_id = Some(connection.id.to_string());
+    update(&mut record, message);
+    set_state(exchanges, present_proof_events, &mut record, state).await?;
+    let response = post(
+        &exchanges.exchanges,
+        previous,
+        &record,
+        connection,
+        encrypted,
+    )
+    .await?;
+    Ok((record, response))
+}
+
+/// Records an inbound message, exchanges started by the peer are created.
+pub async fn received(
+    exchanges: &PresentationExchanges,
+    connections: &Connections,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    message: &Message,
+    role: PresentationExchangeRole,
+    state: PresentationExchangeState,
+) -> Result<PresentationExchangeRecord> {
+    let their_did = sender(message)?;
+    let mut record = match peer_record(&exchanges.exchanges, message)? {
+        Some(record) => record,
+        None => {
+            let mut record = PresentationExchangeRecord::new(
+                &thread_id(message),
+                &their_did,
+                role,
+                state.clone(),
+            );
+            record.connection_id =
+                reuse::find_existing(connections, &their_did)?.map(|connection| connection.id);
+            record
+        }
+    };
+    update(&mut record, message);
+    set_state(exchanges, present_proof_events, &mut record, state).await?;
+    Ok(record)
+}
+
+/// Builds the ack of a verified presentation.
+pub fn build_ack(thread_id: &str) -> Result<Message> {
+    with_thread(
+        build_message(ACK, json!({"status": "OK"}))?,
+        thread_id,
+        None,
+    )
+}
+
+/// Prover side: the verifier accepted the presentation.
+///
+/// Only a sent presentation is acknowledged, acks of done exchanges are
+/// ignored.
+pub async fn receive_ack(
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    ack: &Message,
+) -> Result<()> {
+    let thread_id = thread_id(ack);
+    let mut record = peer_record(&exchanges.exchanges, ack)?
+        .ok_or_else(|| Error::NotFound(format!("presentation exchange {}", thread_id)))?;
+    match (&record.role, &record.state) {
+        (_, PresentationExchangeState::Done) => return Ok(()),
+        (PresentationExchangeRole::Prover, PresentationExchangeState::PresentationSent) => (),
+        (role, state) => {
+            return Err(Error::BadRequest(format!(
+                "no ack expected as {:?} in presentation exchange {} in state {:?}",
+                role, thread_id, state
+            )))
+        }
+    }
+    set_state(
+        exchanges,
+        present_proof_events,
+        &mut record,
+        PresentationExchangeState::Done,
+    )
+    .await
+}
+
+/// The peer reported a problem on the thread of an exchange, which is
+/// abandoned unless it is already over. Returns false if the thread is no
+/// presentation exchange.
+pub async fn receive_problem_report(
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    problem_report: &Message,
+) -> Result<bool> {
+    let mut record = match peer_record(&exchanges.exchanges, problem_report)? {
+        Some(record) => record,
+        None => return Ok(false),
+    };
+    if matches!(
+        record.state,
+        PresentationExchangeState::Done | PresentationExchangeState::Abandoned
+    ) {
+        return Ok(true);
+    }
+    set_state(
+        exchanges,
+        present_proof_events,
+        &mut record,
+        PresentationExchangeState::Abandoned,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// The first attachment of the message as json.
+pub fn attachment(message: &Message) -> Option<Value> {
+    message
+        .get_attachments()
+        .next()
+        .and_then(|attachment| attachment.data.base64.as_ref())
+        .and_then(|data| base64::decode(data).ok())
+        .and_then(|data| serde_json::from_slice(&data).ok())
+}
+
+fn update(record: &mut PresentationExchangeRecord, message: &Message) {
+    if let Some(comment) = application_param(message, "comment") {
+        record.comment = comment.as_str().map(|comment| comment.to_string());
+    }
+    match message.get_didcomm_header().m_type.as_str() {
+        PROPOSE_PRESENTATION => record.presentation_proposal = attachment(message),
+        REQUEST_PRESENTATION => record.presentation_request = attachment(message),
+        PRESENTATION => record.presentation = attachment(message),
+        _ => (),
+    }
+}
+
+/// # Query presentation exchange records
+#[openapi(tag = "present-proof")]
+#[get("/present-proof/records?<state>&<connection_id>")]
+pub async fn get_all_records(
+    _auth: AdminAuth,
+    exchanges: &TenantState<PresentationExchanges>,
+    state: Option<PresentationExchangeState>,
+    connection_id: Option<String>,
+) -> Result<Json<Vec<PresentationExchangeRecord>>> {
+    let mut records: Vec<PresentationExchangeRecord> = exchanges
+        .exchanges
+        .values()?
+        .into_iter()
+        .filter(|record| state.is_none() || state.as_ref() == Some(&record.state))
+        .filter(|record| connection_id.is_none() || connection_id == record.connection_id)
+        .collect();
+    records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(Json(records))
+}
+
+/// # Fetch a single presentation exchange record
+#[openapi(tag = "present-proof")]
+#[get("/present-proof/records/<thread_id>")]
+pub async fn get_record(
+    _auth: AdminAuth,
+    exchanges: &TenantState<PresentationExchanges>,
+    thread_id: String,
+) -> Result<Json<PresentationExchangeRecord>> {
+    Ok(Json(exchanges.get(&thread_id)?))
+}
+
+/// # Remove a presentation exchange record
+#[openapi(tag = "present-proof")]
+#[delete("/present-proof/records/<thread_id>")]
+pub async fn delete_record(
+    _auth: AdminAuth,
+    exchanges: &TenantState<PresentationExchanges>,
+    thread_id: String,
+) -> Result<Status> {
+    exchanges
+        .exchanges
+        .remove(&thread_id)?
+        .ok_or_else(|| Error::NotFound(format!("presentation exchange {}", thread_id)))?;
+    Ok(Status::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::didcomm::build_problem_report;
+    use crate::test_rocket;
+    use crate::wallet::tests::get_did;
+    use didcomm_protocols::PresentProofResponseBuilder;
+    use rocket::local::asynchronous::Client;
+
+    #[tokio::test]
+    async fn test_ack_and_problem_report() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let did = get_did(&client).await.unwrap();
+
+        let proposal = PresentProofResponseBuilder::new()
+            .goal_code("present-proof".to_string())
+            .attachment(json!({"attributes": ["age"]}))
+            .build_propose_presentation()
+            .unwrap()
+            .from(&did)
+            .to(&[&did]);
+        let response = client.post("/").json(&proposal).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let thread_id = proposal.get_didcomm_header().id.to_string();
+
+        // acks are only taken from the peer of a sent presentation
+        let other = "did:iota:H3C2AVvLMv6gmMNam3uVAjZpfkcJCwDwnZn6z3wXmqPV";
+        let ack = build_ack(&thread_id).unwrap().from(other).to(&[&did]);
+        let response = client.post("/").json(&ack).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let ack = build_ack(&thread_id).unwrap().from(&did).to(&[&did]);
+        let response = client.post("/").json(&ack).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // problem reports are only taken from the peer
+        let problem_report = build_problem_report("e.p.test", "test", &thread_id)
+            .unwrap()
+            .from(other)
+            .to(&[&did]);
+        let response = client.post("/").json(&problem_report).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let record: PresentationExchangeRecord = client
+            .get(format!("/present-proof/records/{}", thread_id))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(record.state, PresentationExchangeState::ProposalReceived);
+        let problem_report = build_problem_report("e.p.test", "test", &thread_id)
+            .unwrap()
+            .from(&did)
+            .to(&[&did]);
+        let response = client.post("/").json(&problem_report).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let record: PresentationExchangeRecord = client
+            .get(format!("/present-proof/records/{}", thread_id))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(record.state, PresentationExchangeState::Abandoned);
+    }
+}
@@ -1,30 +1,43 @@
-use crate::connection::Connections;
+use crate::auth::AdminAuth;
+use crate::connection::{Connection, Connections};
 use crate::credential::Credentials;
-use crate::wallet::Wallet;
+use crate::didcomm::with_thread;
+use crate::error::{Error, Result};
+use crate::jwt::{self, CredentialFormat, PresentationFormat};
+use crate::sdjwt::{self, SdJwt};
+use crate::tenant::TenantState;
+use crate::wallet::{wallet_did, Wallet};
+use definition::{Assignment, PRESENTATION_SUBMISSION};
 use didcomm_mediator::message::{add_return_route_all_header, sign_and_encrypt};
 use didcomm_protocols::PresentProofResponseBuilder;
 use identity_iota::core::Url;
 use identity_iota::credential::Presentation;
 use identity_iota::credential::PresentationBuilder;
+use identity_iota::crypto::ProofOptions;
 use identity_iota::did::DID;
 use identity_iota::iota_core::IotaDID;
-use identity_iota::prelude::KeyPair;
-use identity_iota::prelude::*;
-use rocket::http::Status;
-use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::okapi::schemars::{self, JsonSchema};
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use uuid::Uuid;
 
+pub mod definition;
 pub mod events;
+pub mod exchange;
 pub mod proposal;
+pub mod request;
+pub mod verification;
 
 pub use events::{PresentProofEvent, PresentProofEvents};
+pub use exchange::{
+    PresentationExchangeRecord, PresentationExchangeRole, PresentationExchangeState,
+    PresentationExchanges,
+};
+pub use verification::PresentationVerification;
 
 fn example_connection_id() -> &'static str {
     "2fecc993-b92c-4152-8c81-35adde124382"
@@ -38,67 +51,300 @@ fn example_credential_id() -> &'static str {
 pub struct ProofRequest {
     #[schemars(example = "example_connection_id")]
     pub connection_id: String,
+    /// Credential to present, bundled with `credential_ids`.
     #[schemars(example = "example_credential_id")]
-    pub credential_id: String,
+    #[serde(default)]
+    pub credential_id: Option<String>,
+    /// Credentials to present together.
+    #[serde(default)]
+    pub credential_ids: Vec<String>,
+    /// `ldp_vp` for a JSON-LD presentation or `jwt_vp` for a VP-JWT.
+    #[serde(default)]
+    pub format: PresentationFormat,
+    /// Claims of an SD-JWT credential to disclose, all if unset.
+    #[serde(default)]
+    pub disclose: Option<Vec<String>>,
+    /// Challenge of the verifier the holder proof is bound to.
+    #[serde(default)]
+    pub challenge: Option<String>,
+    /// Domain of the verifier the holder proof is bound to.
+    #[serde(default)]
+    pub domain: Option<String>,
 }
 
-#[openapi(tag = "present-proof")]
-#[post("/present-proof/send-presentation", data = "<request>")]
-pub async fn post_send_presentation(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    credentials: &State<Credentials>,
-    connections: &State<Connections>,
-    request: Json<ProofRequest>,
-) -> Result<Json<Value>, Status> {
-    let (did_to, endpoint) = {
-        let connections = connections.connections.lock().await;
-        let connection = connections.get(&request.connection_id).unwrap().clone();
-        (connection.did.to_string(), connection.endpoint)
-    };
-    let wallet = wallet.try_lock().unwrap();
-    let iota_did: IotaDID = IotaDID::from_str(&wallet.did_iota().unwrap()).unwrap();
-    let did = iota_did.clone();
-    let did_from = wallet.did_iota().unwrap();
-    let keypair = wallet.keypair();
-    drop(wallet);
+impl ProofRequest {
+    /// The credentials to present, `credential_id` first.
+    pub fn credential_ids(&self) -> Vec<String> {
+        let mut credential_ids: Vec<String> = self.credential_id.iter().cloned().collect();
+        for credential_id in &self.credential_ids {
+            if !credential_ids.contains(credential_id) {
+                credential_ids.push(credential_id.to_string());
+            }
+        }
+        credential_ids
+    }
+}
 
-    let request = request.into_inner();
+/// Builds the presentation of the stored credentials and returns it with the
+/// attachment to send: JSON-LD with a proof of `sign-0`, or a VP-JWT signed
+/// with `sign-0`. Both bind the verifier's challenge and domain.
+///
+/// SD-JWT credentials disclose those claims of `disclose` they hold, all if
+/// unset. The presentation carries the submission of the assignment if given.
+#[allow(clippy::too_many_arguments)]
+pub async fn prepare_presentation(
+    wallet: &Wallet,
+    credentials: &Credentials,
+    credential_ids: &[String],
+    format: PresentationFormat,
+    disclose: Option<&[String]>,
+    challenge: Option<&str>,
+    domain: Option<&str>,
+    assignment: Option<&Assignment>,
+) -> Result<(Presentation, Value)> {
+    let did: IotaDID = wallet_did(wallet)?;
 
-    let presentation_key: KeyPair = KeyPair::new(KeyType::Ed25519).unwrap();
-    let presentation_did: IotaDID = IotaDID::new(presentation_key.public().as_ref()).unwrap();
+    let account = wallet
+        .account
+        .as_ref()
+        .ok_or_else(|| Error::NotFound("No Account".to_string()))?;
 
-    let credentials = credentials.credentials.lock().await;
-    let credential = credentials.get(&request.credential_id).unwrap();
+    let mut builder = PresentationBuilder::default()
+        .id(Url::parse(format!("urn:uuid:{}", Uuid::new_v4())).map_err(Error::internal)?)
+        .holder(Url::parse(did.as_str()).map_err(Error::internal)?);
+    let mut credential_jwts = Vec::new();
+    let mut credential_formats = Vec::new();
+    for credential_id in credential_ids {
+        let record = credentials.get_record(credential_id)?;
+        let (credential, credential_jwt) = match (disclose, record.jwt) {
+            (Some(names), Some(sd_jwt)) if sdjwt::is_sd_jwt(&sd_jwt) => {
+                let sd_jwt = SdJwt::parse(&sd_jwt)?;
+                let known = sd_jwt.names()?;
+                let names: Vec<String> = names
+                    .iter()
+                    .filter(|name| known.contains(name))
+                    .cloned()
+                    .collect();
+                let selected = sd_jwt.select(&names)?.serialize();
+                (sdjwt::decode(&selected)?, Some(selected))
+            }
+            (_, jwt) => (record.credential, jwt),
+        };
+        credential_formats.push(match (format, &credential_jwt) {
+            (PresentationFormat::JwtVp, Some(jwt)) if sdjwt::is_sd_jwt(jwt) => {
+                CredentialFormat::SdJwtVc
+            }
+            (PresentationFormat::JwtVp, Some(_)) => CredentialFormat::JwtVc,
+            _ => CredentialFormat::LdpVc,
+        });
+        builder = builder.credential(credential);
+        credential_jwts.push(credential_jwt);
+    }
+    let mut presentation: Presentation = builder.build().map_err(Error::internal)?;
+    if let Some(assignment) = assignment {
+        let submission = assignment.submission(credential_ids, &credential_formats)?;
+        presentation.properties.insert(
+            PRESENTATION_SUBMISSION.to_string(),
+            serde_json::to_value(submission).map_err(Error::internal)?,
+        );
+    }
 
-    let presentation: Presentation = PresentationBuilder::default()
-        .id(Url::parse(presentation_did.as_str()).unwrap())
-        .holder(Url::parse(did.as_str()).unwrap())
-        .credential(credential.clone())
-        .build()
-        .unwrap();
+    let attachment = match format {
+        PresentationFormat::LdpVp => {
+            let mut options = ProofOptions::default();
+            if let Some(challenge) = challenge {
+                options = options.challenge(challenge.to_string());
+            }
+            if let Some(domain) = domain {
+                options = options.domain(domain.to_string());
+            }
+            account
+                .sign(jwt::SIGNING_METHOD, &mut presentation, options)
+                .await
+                .map_err(Error::internal)?;
+            serde_json::to_value(&presentation).map_err(Error::internal)?
+        }
+        PresentationFormat::JwtVp => {
+            let claims =
+                jwt::presentation_claims(&presentation, &credential_jwts, challenge, domain)?;
+            json!(jwt::sign(account, &claims).await?)
+        }
+    };
+    Ok((presentation, attachment))
+}
 
-    let attachment = serde_json::to_value(&presentation).unwrap();
+/// Sends the presentation attachment to the connection, on the thread of a
+/// presentation request if given, and records the exchange.
+pub async fn send_presentation(
+    wallet: &Arc<Mutex<Wallet>>,
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    connection: &Connection,
+    attachment: Value,
+    thid: Option<&str>,
+) -> Result<PresentationExchangeRecord> {
     let mut proof = PresentProofResponseBuilder::new()
         .goal_code("present-proof".to_string())
         .attachment(attachment)
         .build_presentation()
-        .unwrap();
+        .map_err(Error::internal)?;
+    if let Some(thid) = thid {
+        proof = with_thread(proof, thid, None)?;
+    }
     proof = add_return_route_all_header(proof);
-    let message = sign_and_encrypt(&proof, &did_from, &did_to, &keypair)
-        .await
-        .unwrap();
+    let message = {
+        let wallet = wallet.lock().await;
+        let did_from = wallet_did(&wallet)?.to_string();
+        sign_and_encrypt(&proof, &did_from, &connection.did, &wallet.keypair())
+            .await
+            .map_err(|err| Error::PeerUnreachable(format!("{}: {:?}", connection.did, err)))?
+    };
+    let (record, response) = exchange::send(
+        exchanges,
+        present_proof_events,
+        connection,
+        &proof,
+        &message,
+        PresentationExchangeRole::Prover,
+        PresentationExchangeState::PresentationSent,
+    )
+    .await?;
+    receive_returned_ack(wallet, exchanges, present_proof_events, &response).await;
+    exchanges.get(&record.thread_id)
+}
 
-    let client = reqwest::Client::new();
-    match client
-        .post(endpoint.to_string())
-        .json(&message)
-        .send()
-        .await
-    {
-        Ok(_) => Ok(Json(json!(presentation))),
-        Err(err) => {
-            println!("{:?}", err);
-            Err(Status::InternalServerError)
+/// Completes the exchange with an ack the verifier returned on the return
+/// route, or abandons it on a returned problem report.
+async fn receive_returned_ack(
+    wallet: &Arc<Mutex<Wallet>>,
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    response: &Value,
+) {
+    crate::exchange::receive_returned(
+        wallet,
+        response,
+        exchange::ACK,
+        |ack| async move { exchange::receive_ack(exchanges, present_proof_events, &ack).await },
+        |problem_report| async move {
+            exchange::receive_problem_report(exchanges, present_proof_events, &problem_report).await
+        },
+    )
+    .await
+}
+
+#[openapi(tag = "present-proof")]
+#[post("/present-proof/send-presentation", data = "<request>")]
+pub async fn post_send_presentation(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    credentials: &TenantState<Credentials>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<PresentationExchanges>,
+    present_proof_events: &TenantState<Arc<Mutex<PresentProofEvents>>>,
+    request: Json<ProofRequest>,
+) -> Result<Json<Value>> {
+    let connection = connections.get(&request.connection_id)?;
+    let request = request.into_inner();
+
+    let credential_ids = request.credential_ids();
+    if credential_ids.is_empty() {
+        return Err(Error::BadRequest("no credential to present".to_string()));
+    }
+
+    if let Some(names) = &request.disclose {
+        if request.format != PresentationFormat::JwtVp {
+            return Err(Error::BadRequest(
+                "disclosures are presented as jwt_vp".to_string(),
+            ));
+        }
+        let mut known = Vec::new();
+        for credential_id in &credential_ids {
+            if let Some(sd_jwt) = credentials
+                .get_record(credential_id)?
+                .jwt
+                .filter(|jwt| sdjwt::is_sd_jwt(jwt))
+            {
+                known.extend(SdJwt::parse(&sd_jwt)?.names()?);
+            }
         }
+        if let Some(unknown) = names.iter().find(|name| !known.contains(name)) {
+            return Err(Error::BadRequest(format!(
+                "no sd-jwt credential discloses {}",
+                unknown
+            )));
+        }
+    }
+
+    let (presentation, attachment) = {
+        let wallet = wallet.lock().await;
+        prepare_presentation(
+            &wallet,
+            credentials,
+            &credential_ids,
+            request.format,
+            request.disclose.as_deref(),
+            request.challenge.as_deref(),
+            request.domain.as_deref(),
+            None,
+        )
+        .await?
+    };
+    send_presentation(
+        wallet,
+        exchanges,
+        present_proof_events,
+        &connection,
+        attachment,
+        None,
+    )
+    .await?;
+    Ok(Json(json!(presentation)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rocket;
+    use crate::Config;
+    use identity_iota::core::FromJson;
+    use identity_iota::credential::{Credential, CredentialBuilder, Subject};
+
+    #[tokio::test]
+    async fn test_prepare_signed_presentation() {
+        let rocket = test_rocket().await;
+        let config: Config = rocket.figment().extract().expect("config");
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        let credentials = Credentials::default();
+        for id in ["urn:uuid:1", "urn:uuid:2"] {
+            let credential: Credential = CredentialBuilder::default()
+                .id(Url::parse(id).unwrap())
+                .issuer(Url::parse("did:iota:issuer").unwrap())
+                .subject(Subject::from_json_value(json!({"age": "42"})).unwrap())
+                .build()
+                .unwrap();
+            credentials.insert(credential, None, None).unwrap();
+        }
+
+        let (presentation, attachment) = prepare_presentation(
+            &wallet,
+            &credentials,
+            &["urn:uuid:1".to_string(), "urn:uuid:2".to_string()],
+            PresentationFormat::LdpVp,
+            None,
+            Some("challenge"),
+            Some("example.com"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(presentation.verifiable_credential.len(), 2);
+        assert!(presentation.id.unwrap().as_str().starts_with("urn:uuid:"));
+        assert_eq!(attachment["proof"]["challenge"], "challenge");
+        assert_eq!(attachment["proof"]["domain"], "example.com");
+        assert!(attachment["proof"]["verificationMethod"]
+            .as_str()
+            .unwrap()
+            .ends_with(jwt::SIGNING_METHOD));
     }
 }
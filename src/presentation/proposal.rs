@@ -1,20 +1,19 @@
+use super::exchange::{self, PresentationExchangeRole, PresentationExchangeState};
+use super::{PresentProofEvents, PresentationExchangeRecord, PresentationExchanges};
+use crate::auth::AdminAuth;
+use crate::connection::Connections;
 use crate::credential::Credentials;
-use crate::wallet::Wallet;
-use identity_iota::core::Url;
-use identity_iota::credential::Presentation;
-use identity_iota::credential::PresentationBuilder;
-use identity_iota::did::DID;
-use identity_iota::iota_core::IotaDID;
-use identity_iota::prelude::KeyPair;
-use identity_iota::prelude::*;
-use rocket::State;
+use crate::error::{Error, Result};
+use crate::tenant::TenantState;
+use crate::wallet::{wallet_did, Wallet};
+use didcomm_mediator::message::{add_return_route_all_header, sign_and_encrypt};
+use didcomm_protocols::PresentProofResponseBuilder;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -26,46 +25,116 @@ fn example_credential_id() -> &'static str {
     "https://example.edu/credentials/3732"
 }
 
+/// What the prover offers to present, the attachment of a
+/// `propose-presentation`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationProposal {
+    #[serde(default)]
+    pub credential_types: Vec<String>,
+    /// Subject attributes the prover can present.
+    #[serde(default)]
+    pub attributes: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ProofRequest {
     #[schemars(example = "example_connection_id")]
     pub connection_id: String,
+    /// Stored credential whose types and subject attributes are proposed.
     #[schemars(example = "example_credential_id")]
-    pub credential_id: String,
+    #[serde(default)]
+    pub credential_id: Option<String>,
+    #[serde(default)]
+    pub credential_types: Vec<String>,
+    #[serde(default)]
+    pub attributes: Vec<String>,
+}
+
+/// The proposal of the requested types and attributes and those of the
+/// credential.
+fn proposal(credentials: &Credentials, request: &ProofRequest) -> Result<PresentationProposal> {
+    let mut proposal = PresentationProposal {
+        credential_types: request.credential_types.clone(),
+        attributes: request.attributes.clone(),
+    };
+    if let Some(credential_id) = &request.credential_id {
+        let credential = credentials.get(credential_id)?;
+        for credential_type in credential.types.iter() {
+            if credential_type != "VerifiableCredential"
+                && !proposal.credential_types.contains(credential_type)
+            {
+                proposal.credential_types.push(credential_type.to_string());
+            }
+        }
+        for subject in credential.credential_subject.iter() {
+            for (name, value) in subject.properties.iter() {
+                let names: Vec<String> = match (name.as_str(), value) {
+                    ("attributes", Value::Array(attributes)) => attributes
+                        .iter()
+                        .filter_map(|attribute| attribute["name"].as_str())
+                        .map(ToString::to_string)
+                        .collect(),
+                    _ => vec![name.to_string()],
+                };
+                for name in names {
+                    if !proposal.attributes.contains(&name) {
+                        proposal.attributes.push(name);
+                    }
+                }
+            }
+        }
+    }
+    Ok(proposal)
 }
 
+/// # Propose a presentation to the connection
 #[openapi(tag = "present-proof")]
 #[post("/present-proof/send-proposal", data = "<proof_request>")]
 pub async fn post_send_proposal(
-    wallet: &State<Arc<Mutex<Wallet>>>,
-    credentials: &State<Credentials>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    credentials: &TenantState<Credentials>,
+    exchanges: &TenantState<PresentationExchanges>,
+    present_proof_events: &TenantState<Arc<Mutex<PresentProofEvents>>>,
     proof_request: Json<ProofRequest>,
-) -> Json<Value> {
-    let wallet = wallet.try_lock().unwrap();
-    let iota_did: IotaDID = IotaDID::from_str(&wallet.did_iota().unwrap()).unwrap();
-    let did = iota_did.clone();
-
-    let proof_request = proof_request.into_inner();
-
-    let presentation_key: KeyPair = KeyPair::new(KeyType::Ed25519).unwrap();
-    let presentation_did: IotaDID = IotaDID::new(presentation_key.public().as_ref()).unwrap();
-
-    let credentials = credentials.credentials.lock().await;
-    let credential = credentials.get(&proof_request.credential_id).unwrap();
-
-    let presentation: Presentation = PresentationBuilder::default()
-        .id(Url::parse(presentation_did.as_str()).unwrap())
-        .holder(Url::parse(did.as_str()).unwrap())
-        .credential(credential.clone())
-        .build()
-        .unwrap();
+) -> Result<Json<PresentationExchangeRecord>> {
+    let connection = connections.get(&proof_request.connection_id)?;
+    let proposal = proposal(credentials, &proof_request)?;
+    if proposal.credential_types.is_empty() && proposal.attributes.is_empty() {
+        return Err(Error::BadRequest("nothing to propose".to_string()));
+    }
 
-    Json(json!(presentation))
+    let mut message = PresentProofResponseBuilder::new()
+        .goal_code("present-proof".to_string())
+        .attachment(json!(proposal))
+        .build_propose_presentation()
+        .map_err(Error::internal)?;
+    message = add_return_route_all_header(message);
+    let encrypted = {
+        let wallet = wallet.lock().await;
+        let did_from = wallet_did(&wallet)?.to_string();
+        sign_and_encrypt(&message, &did_from, &connection.did, &wallet.keypair())
+            .await
+            .map_err(|err| Error::PeerUnreachable(format!("{}: {:?}", connection.did, err)))?
+    };
+    let (record, _) = exchange::send(
+        exchanges,
+        present_proof_events,
+        &connection,
+        &message,
+        &encrypted,
+        PresentationExchangeRole::Prover,
+        PresentationExchangeState::ProposalSent,
+    )
+    .await?;
+    Ok(Json(record))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connection::tests::connect;
     use crate::test_rocket;
     use rocket::http::{ContentType, Status};
     use rocket::local::asynchronous::Client;
@@ -78,7 +147,9 @@ mod tests {
 
         let proof_request = ProofRequest {
             connection_id: "foo".to_string(),
-            credential_id: "bar".to_string(),
+            credential_id: Some("bar".to_string()),
+            credential_types: Vec::new(),
+            attributes: Vec::new(),
         };
         let invitation: String = serde_json::to_string(&proof_request).unwrap();
 
@@ -88,6 +159,25 @@ mod tests {
             .body(invitation)
             .dispatch()
             .await;
-        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(response.status(), Status::NotFound);
+
+        let connection = connect(&client).await.unwrap();
+        let response = client
+            .post("/present-proof/send-proposal")
+            .json(&ProofRequest {
+                connection_id: connection.id.to_string(),
+                credential_id: None,
+                credential_types: vec!["AgeCredential".to_string()],
+                attributes: vec!["age".to_string()],
+            })
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let record: PresentationExchangeRecord = response.into_json().await.unwrap();
+        assert_eq!(record.role, PresentationExchangeRole::Prover);
+        assert_eq!(record.state, PresentationExchangeState::ProposalSent);
+        let proposal: PresentationProposal =
+            serde_json::from_value(record.presentation_proposal.unwrap()).unwrap();
+        assert_eq!(proposal.attributes, vec!["age".to_string()]);
     }
 }
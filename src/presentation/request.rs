@@ -0,0 +1,477 @@
+use super::definition::PresentationDefinition;
+use super::exchange::{self, PresentationExchangeRole, PresentationExchangeState};
+use super::{
+    prepare_presentation, send_presentation, PresentProofEvents, PresentationExchangeRecord,
+    PresentationExchanges,
+};
+use crate::auth::AdminAuth;
+use crate::connection::Connections;
+use crate::credential::{CredentialQuery, CredentialRecord, Credentials};
+use crate::didcomm::with_thread;
+use crate::error::{Error, Result};
+use crate::jwt::PresentationFormat;
+use crate::tenant::TenantState;
+use crate::wallet::{wallet_did, Wallet};
+use didcomm_mediator::message::{add_return_route_all_header, sign_and_encrypt};
+use didcomm_protocols::PresentProofResponseBuilder;
+use rocket::{post, serde::json::Json};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+fn example_connection_id() -> &'static str {
+    "2fecc993-b92c-4152-8c81-35adde124382"
+}
+
+fn example_credential_types() -> Vec<String> {
+    vec!["UniversityDegreeCredential".to_string()]
+}
+
+/// What the verifier asks to be presented, the attachment of a
+/// `request-presentation`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationRequest {
+    /// Types of the credentials to present, one credential each.
+    #[serde(default)]
+    pub credential_types: Vec<String>,
+    /// Subject attributes the presented credentials must hold.
+    #[serde(default)]
+    pub attributes: Vec<String>,
+    /// Nonce the presentation is bound to.
+    pub challenge: String,
+    /// Verifier the presentation is meant for.
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// DIF Presentation Exchange definition the presentation must answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presentation_definition: Option<PresentationDefinition>,
+}
+
+impl PresentationRequest {
+    /// Ids of valid stored credentials answering the request: one for each
+    /// input descriptor of the definition, else one of each requested type,
+    /// or one holding all attributes if no type is requested.
+    pub fn matching_credentials(&self, credentials: &Credentials) -> Result<Vec<String>> {
+        if let Some(definition) = &self.presentation_definition {
+            return Ok(definition.assign(credentials, None)?.credential_ids());
+        }
+        let records = credentials.credentials.values()?;
+        let has_attributes = |record: &CredentialRecord| {
+            self.attributes.iter().all(|name| {
+                CredentialQuery {
+                    attribute: Some((name.to_string(), None)),
+                    ..Default::default()
+                }
+                .matches(record)
+            })
+        };
+        let types: Vec<Option<String>> = match self.credential_types.is_empty() {
+            true => vec![None],
+            false => self.credential_types.iter().cloned().map(Some).collect(),
+        };
+        let mut matching: Vec<&CredentialRecord> = Vec::new();
+        for credential_type in types {
+            let query = CredentialQuery {
+                credential_type: credential_type.clone(),
+                valid: Some(true),
+                ..Default::default()
+            };
+            let candidates: Vec<&CredentialRecord> = records
+                .iter()
+                .filter(|record| query.matches(record))
+                .collect();
+            let record = candidates
+                .iter()
+                .copied()
+                .find(|record| has_attributes(record))
+                .or_else(|| candidates.first().copied())
+                .ok_or_else(|| {
+                    Error::CredentialNotFound(
+                        credential_type.unwrap_or_else(|| self.attributes.join(", ")),
+                    )
+                })?;
+            matching.push(record);
+        }
+        let missing: Vec<&String> = self
+            .attributes
+            .iter()
+            .filter(|name| {
+                let query = CredentialQuery {
+                    attribute: Some((name.to_string(), None)),
+                    ..Default::default()
+                };
+                !matching.iter().any(|record| query.matches(record))
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::CredentialNotFound(format!(
+                "attributes {:?}",
+                missing
+            )));
+        }
+        matching
+            .iter()
+            .map(|record| {
+                record
+                    .credential
+                    .id
+                    .as_ref()
+                    .map(|id| id.to_string())
+                    .ok_or_else(|| Error::BadRequest("credential without id".to_string()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SendPresentationRequest {
+    #[schemars(example = "example_connection_id")]
+    pub connection_id: String,
+    /// Exchange whose received proposal the request answers.
+    #[serde(default)]
+    pub thread_id: Option<String>,
+    #[schemars(example = "example_credential_types")]
+    #[serde(default)]
+    pub credential_types: Vec<String>,
+    #[serde(default)]
+    pub attributes: Vec<String>,
+    /// Nonce the presentation is bound to, a random uuid if unset.
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// DIF Presentation Exchange definition, replaces types and attributes
+    /// when picking the credentials to present.
+    #[serde(default)]
+    pub presentation_definition: Option<PresentationDefinition>,
+}
+
+/// # Request a presentation from the connection
+#[openapi(tag = "present-proof")]
+#[post("/present-proof/send-request", data = "<request>")]
+pub async fn post_send_request(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    exchanges: &TenantState<PresentationExchanges>,
+    present_proof_events: &TenantState<Arc<Mutex<PresentProofEvents>>>,
+    request: Json<SendPresentationRequest>,
+) -> Result<Json<PresentationExchangeRecord>> {
+    let connection = connections.get(&request.connection_id)?;
+    let request = request.into_inner();
+    if let Some(thread_id) = &request.thread_id {
+        let record = exchanges.get(thread_id)?;
+        if record.state != PresentationExchangeState::ProposalReceived
+            || record.connection_id.as_ref() != Some(&connection.id)
+        {
+            return Err(Error::BadRequest(format!(
+                "presentation exchange {} is no proposal of the connection",
+                thread_id
+            )));
+        }
+    }
+    if let Some(definition) = &request.presentation_definition {
+        definition.validate()?;
+    }
+    let presentation_request = PresentationRequest {
+        credential_types: request.credential_types,
+        attributes: request.attributes,
+        challenge: request
+            .challenge
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        domain: request.domain,
+        presentation_definition: request.presentation_definition,
+    };
+
+    let mut message = PresentProofResponseBuilder::new()
+        .goal_code("present-proof".to_string())
+        .attachment(json!(presentation_request))
+        .build_request_presentation()
+        .map_err(Error::internal)?;
+    if let Some(thread_id) = &request.thread_id {
+        message = with_thread(message, thread_id, None)?;
+    }
+    message = add_return_route_all_header(message);
+    let encrypted = {
+        let wallet = wallet.lock().await;
+        let did_from = wallet_did(&wallet)?.to_string();
+        sign_and_encrypt(&message, &did_from, &connection.did, &wallet.keypair())
+            .await
+            .map_err(|err| Error::PeerUnreachable(format!("{}: {:?}", connection.did, err)))?
+    };
+    let (record, _) = exchange::send(
+        exchanges,
+        present_proof_events,
+        &connection,
+        &message,
+        &encrypted,
+        PresentationExchangeRole::Verifier,
+        PresentationExchangeState::RequestSent,
+    )
+    .await?;
+    Ok(Json(record))
+}
+
+/// Credentials and format of a presentation answering a request, matching
+/// credentials are picked if unset.
+#[derive(Default, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationAnswer {
+    #[serde(default)]
+    pub credential_ids: Option<Vec<String>>,
+    /// `jwt_vp` if any of the credentials was received as jwt, `ldp_vp`
+    /// otherwise if unset.
+    #[serde(default)]
+    pub format: Option<PresentationFormat>,
+}
+
+/// Prover side: answers a received presentation request on its thread.
+/// SD-JWT credentials disclose the requested attributes only, and the claims
+/// of a definition limiting disclosure, which other credentials can not answer.
+pub async fn present(
+    wallet: &Arc<Mutex<Wallet>>,
+    connections: &Connections,
+    credentials: &Credentials,
+    exchanges: &PresentationExchanges,
+    present_proof_events: &Arc<Mutex<PresentProofEvents>>,
+    record: &PresentationExchangeRecord,
+    answer: PresentationAnswer,
+) -> Result<PresentationExchangeRecord> {
+    if record.state != PresentationExchangeState::RequestReceived {
+        return Err(Error::BadRequest(format!(
+            "presentation exchange {} is {:?}, expected {:?}",
+            record.thread_id,
+            record.state,
+            PresentationExchangeState::RequestReceived
+        )));
+    }
+    let request: PresentationRequest = record
+        .presentation_request
+        .clone()
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(|err| Error::BadRequest(err.to_string()))?
+        .ok_or_else(|| {
+            Error::BadRequest(format!(
+                "presentation exchange {} without request",
+                record.thread_id
+            ))
+        })?;
+    let connection = exchange::connection(connections, record)?;
+    let assignment = match &request.presentation_definition {
+        Some(definition) => {
+            let assignment = definition.assign(credentials, answer.credential_ids.as_deref())?;
+            definition.check_disclosure(credentials, &assignment)?;
+            Some(assignment)
+        }
+        None => None,
+    };
+    let credential_ids = match (&assignment, answer.credential_ids) {
+        (Some(assignment), _) => assignment.credential_ids(),
+        (None, Some(credential_ids)) => credential_ids,
+        (None, None) => request.matching_credentials(credentials)?,
+    };
+    let format = match answer.format {
+        Some(format) => format,
+        None => {
+            let any_jwt = credential_ids.iter().any(|credential_id| {
+                credentials
+                    .get_record(credential_id)
+                    .map(|record| record.jwt.is_some())
+                    .unwrap_or(false)
+            });
+            match any_jwt {
+                true => PresentationFormat::JwtVp,
+                false => PresentationFormat::LdpVp,
+            }
+        }
+    };
+    let mut disclose = request.attributes.clone();
+    let limited = match &request.presentation_definition {
+        Some(definition) if definition.limits_disclosure() => {
+            disclose.extend(definition.disclosed_claims());
+            true
+        }
+        _ => false,
+    };
+    let disclose = match limited || !disclose.is_empty() {
+        true => Some(disclose.as_slice()),
+        false => None,
+    };
+    let (_, attachment) = {
+        let wallet = wallet.lock().await;
+        prepare_presentation(
+            &wallet,
+            credentials,
+            &credential_ids,
+            format,
+            disclose,
+            Some(&request.challenge),
+            request.domain.as_deref(),
+            assignment.as_ref(),
+        )
+        .await?
+    };
+    send_presentation(
+        wallet,
+        exchanges,
+        present_proof_events,
+        &connection,
+        attachment,
+        Some(&record.thread_id),
+    )
+    .await
+}
+
+/// # Send a presentation answering a received request
+#[openapi(tag = "present-proof")]
+#[post(
+    "/present-proof/records/<thread_id>/send-presentation",
+    data = "<answer>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn post_record_send_presentation(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    connections: &TenantState<Connections>,
+    credentials: &TenantState<Credentials>,
+    exchanges: &TenantState<PresentationExchanges>,
+    present_proof_events: &TenantState<Arc<Mutex<PresentProofEvents>>>,
+    thread_id: String,
+    answer: Json<PresentationAnswer>,
+) -> Result<Json<PresentationExchangeRecord>> {
+    let record = exchanges.get(&thread_id)?;
+    let record = present(
+        wallet,
+        connections,
+        credentials,
+        exchanges,
+        present_proof_events,
+        &record,
+        answer.into_inner(),
+    )
+    .await?;
+    Ok(Json(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::connect;
+    use crate::credential::CredentialVerification;
+    use crate::test_rocket;
+    use identity_iota::core::{FromJson, Url};
+    use identity_iota::credential::{Credential, CredentialBuilder, Subject};
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use serde_json::Value;
+
+    fn credential(id: &str, type_: &str, subject: Value) -> Credential {
+        CredentialBuilder::default()
+            .id(Url::parse(id).unwrap())
+            .issuer(Url::parse("did:iota:issuer").unwrap())
+            .type_(type_)
+            .subject(Subject::from_json_value(subject).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_matching_credentials() {
+        let credentials = Credentials::default();
+        let verified = CredentialVerification {
+            verified: true,
+            errors: Vec::new(),
+            verified_at: "".to_string(),
+        };
+        credentials
+            .insert(
+                credential("urn:uuid:1", "DegreeCredential", json!({"degree": "BSc"})),
+                Some(verified.clone()),
+                None,
+            )
+            .unwrap();
+        credentials
+            .insert(
+                credential("urn:uuid:2", "AgeCredential", json!({"age": "42"})),
+                Some(verified),
+                None,
+            )
+            .unwrap();
+        credentials
+            .insert(
+                credential("urn:uuid:3", "AgeCredential", json!({"age": "18"})),
+                None,
+                None,
+            )
+            .unwrap();
+
+        let request = PresentationRequest {
+            credential_types: vec!["DegreeCredential".to_string(), "AgeCredential".to_string()],
+            attributes: vec!["age".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            request.matching_credentials(&credentials).unwrap(),
+            vec!["urn:uuid:1".to_string(), "urn:uuid:2".to_string()]
+        );
+        let request = PresentationRequest {
+            attributes: vec!["degree".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            request.matching_credentials(&credentials).unwrap(),
+            vec!["urn:uuid:1".to_string()]
+        );
+        let request = PresentationRequest {
+            credential_types: vec!["DegreeCredential".to_string()],
+            attributes: vec!["address".to_string()],
+            ..Default::default()
+        };
+        assert!(request.matching_credentials(&credentials).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_request() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+        let connection = connect(&client).await.unwrap();
+
+        let response = client
+            .post("/present-proof/send-request")
+            .json(&SendPresentationRequest {
+                connection_id: connection.id.to_string(),
+                thread_id: None,
+                credential_types: example_credential_types(),
+                attributes: Vec::new(),
+                challenge: Some("challenge".to_string()),
+                domain: None,
+                presentation_definition: None,
+            })
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let record: PresentationExchangeRecord = response.into_json().await.unwrap();
+        assert_eq!(record.role, PresentationExchangeRole::Verifier);
+        assert_eq!(record.state, PresentationExchangeState::RequestSent);
+        let request: PresentationRequest =
+            serde_json::from_value(record.presentation_request.unwrap()).unwrap();
+        assert_eq!(request.challenge, "challenge");
+
+        // the connection is to ourselves, so we received the request as well,
+        // but have no credential to present
+        let response = client
+            .post(format!(
+                "/present-proof/records/{}/send-presentation",
+                record.thread_id
+            ))
+            .json(&PresentationAnswer::default())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
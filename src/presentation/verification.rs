@@ -0,0 +1,318 @@
+use super::definition;
+use super::request::PresentationRequest;
+use crate::credential::verification::verify_attachment;
+use crate::error::{Error, Result};
+use crate::jwt;
+use identity_iota::client::{PresentationValidator, Resolver};
+use identity_iota::core::Timestamp;
+use identity_iota::credential::Presentation;
+use identity_iota::did::verifiable::VerifierOptions;
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Result of verifying one credential of a received presentation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PresentedCredential {
+    pub id: Option<String>,
+    pub issuer: Option<String>,
+    pub verified: bool,
+    /// Failed checks, empty if verified.
+    pub errors: Vec<String>,
+}
+
+/// Result of verifying a received presentation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PresentationVerification {
+    /// The holder signature and all credentials are valid and answer the request.
+    pub verified: bool,
+    /// Failed checks of the presentation itself.
+    pub errors: Vec<String>,
+    pub credentials: Vec<PresentedCredential>,
+    pub verified_at: String,
+}
+
+/// Verifies a received presentation attachment, a JSON-LD presentation or a
+/// VP-JWT: the holder signature with the challenge and domain of the request,
+/// each credential's issuer signature, expiry, revocation and binding to the
+/// holder, and that the requested types and attributes are presented and the
+/// submission answers the presentation definition.
+///
+/// Returns the presentation with decoded credentials and the result.
+pub async fn verify_presentation(
+    attachment: &Value,
+    request: Option<&PresentationRequest>,
+) -> Result<(Presentation, PresentationVerification)> {
+    let challenge = request.map(|request| request.challenge.as_str());
+    let domain = request.and_then(|request| request.domain.as_deref());
+    let mut errors = Vec::new();
+    let (mut vp, holder) = match attachment.as_str().filter(|value| jwt::is_jwt(value)) {
+        Some(vp_jwt) => {
+            let claims = match jwt::verify(vp_jwt).await {
+                Ok(claims) => claims,
+                Err(err) => {
+                    errors.push(err.to_string());
+                    jwt::decode_claims(vp_jwt)?
+                }
+            };
+            if let Some(challenge) = challenge {
+                if claims["nonce"].as_str() != Some(challenge) {
+                    errors.push("presentation nonce is not the challenge".to_string());
+                }
+            }
+            if let Some(domain) = domain {
+                if claims["aud"].as_str() != Some(domain) {
+                    errors.push("presentation aud is not the domain".to_string());
+                }
+            }
+            let holder = claims["iss"].as_str().unwrap_or_default().to_string();
+            (claims["vp"].clone(), holder)
+        }
+        None => {
+            let presentation: Presentation = serde_json::from_value(attachment.clone())
+                .map_err(|err| Error::BadRequest(err.to_string()))?;
+            errors.extend(verify_holder_signature(&presentation, challenge, domain).await);
+            let holder = presentation
+                .holder
+                .as_ref()
+                .map(|holder| holder.to_string())
+                .unwrap_or_default();
+            (attachment.clone(), holder)
+        }
+    };
+    if vp["holder"].as_str() != Some(holder.as_str()) {
+        errors.push("presentation holder is not the signer".to_string());
+    }
+
+    let entries = match vp["verifiableCredential"].take() {
+        Value::Array(entries) => entries,
+        Value::Null => Vec::new(),
+        entry => vec![entry],
+    };
+    let mut decoded = Vec::new();
+    let mut credentials = Vec::new();
+    for entry in entries {
+        match verify_attachment(&entry, &holder).await {
+            Ok((credential, _, verification)) => {
+                credentials.push(PresentedCredential {
+                    id: credential.id.as_ref().map(|id| id.to_string()),
+                    issuer: Some(credential.issuer.url().to_string()),
+                    verified: verification.verified,
+                    errors: verification.errors,
+                });
+                decoded.push(serde_json::to_value(&credential).map_err(Error::internal)?);
+            }
+            Err(err) => {
+                credentials.push(PresentedCredential {
+                    id: None,
+                    issuer: None,
+                    verified: false,
+                    errors: vec![err.to_string()],
+                });
+                decoded.push(entry);
+            }
+        }
+    }
+    vp["verifiableCredential"] = Value::Array(decoded);
+    let presentation: Presentation =
+        serde_json::from_value(vp).map_err(|err| Error::BadRequest(err.to_string()))?;
+
+    if let Some(request) = request {
+        errors.extend(check_request(&presentation, request));
+        if let Some(definition) = &request.presentation_definition {
+            let presented = serde_json::to_value(&presentation).map_err(Error::internal)?;
+            errors.extend(definition::evaluate(definition, &presented));
+        }
+    }
+    let verified = errors.is_empty() && credentials.iter().all(|credential| credential.verified);
+    Ok((
+        presentation,
+        PresentationVerification {
+            verified,
+            errors,
+            credentials,
+            verified_at: Timestamp::now_utc().to_string(),
+        },
+    ))
+}
+
+async fn verify_holder_signature(
+    presentation: &Presentation,
+    challenge: Option<&str>,
+    domain: Option<&str>,
+) -> Vec<String> {
+    if presentation.proof.is_none() {
+        return vec!["presentation is not signed".to_string()];
+    }
+    let mut options = VerifierOptions::new();
+    if let Some(challenge) = challenge {
+        options = options.challenge(challenge.to_string());
+    }
+    if let Some(domain) = domain {
+        options = options.domain(domain.to_string());
+    }
+    let resolver = match Resolver::new().await {
+        Ok(resolver) => resolver,
+        Err(err) => return vec![format!("resolver: {}", err)],
+    };
+    let holder = match resolver.resolve_presentation_holder(presentation).await {
+        Ok(holder) => holder,
+        Err(err) => return vec![format!("holder: {}", err)],
+    };
+    match PresentationValidator::verify_presentation_signature(presentation, &holder, &options) {
+        Ok(()) => Vec::new(),
+        Err(err) => vec![err.to_string()],
+    }
+}
+
+/// Requested credential types and attributes missing in the presentation.
+fn check_request(presentation: &Presentation, request: &PresentationRequest) -> Vec<String> {
+    let mut errors = Vec::new();
+    for credential_type in &request.credential_types {
+        let presented = presentation.verifiable_credential.iter().any(|credential| {
+            credential
+                .types
+                .iter()
+                .any(|type_| type_ == credential_type)
+        });
+        if !presented {
+            errors.push(format!("no credential of type {}", credential_type));
+        }
+    }
+    for attribute in &request.attributes {
+        let presented = presentation
+            .verifiable_credential
+            .iter()
+            .flat_map(|credential| credential.credential_subject.iter())
+            .any(|subject| {
+                subject.properties.contains_key(attribute)
+                    || subject
+                        .properties
+                        .get("attributes")
+                        .and_then(Value::as_array)
+                        .map(|attributes| {
+                            attributes
+                                .iter()
+                                .any(|entry| entry["name"].as_str() == Some(attribute.as_str()))
+                        })
+                        .unwrap_or(false)
+            });
+        if !presented {
+            errors.push(format!("attribute {} not presented", attribute));
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::Credentials;
+    use crate::jwt::PresentationFormat;
+    use crate::presentation::prepare_presentation;
+    use crate::test_rocket;
+    use crate::wallet::wallet_did;
+    use crate::{Config, Wallet};
+    use identity_iota::core::{FromJson, Url};
+    use identity_iota::credential::{Credential, CredentialBuilder, PresentationBuilder, Subject};
+    use identity_iota::crypto::ProofOptions;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_verify_presentation() {
+        let rocket = test_rocket().await;
+        let config: Config = rocket.figment().extract().expect("config");
+        let wallet = Wallet::new_from_config(&config).await.unwrap();
+        let did = wallet_did(&wallet).unwrap().to_string();
+        let mut credential: Credential = CredentialBuilder::default()
+            .id(Url::parse("urn:uuid:1").unwrap())
+            .issuer(Url::parse(&did).unwrap())
+            .type_("AgeCredential")
+            .subject(Subject::from_json_value(json!({"id": did, "age": "42"})).unwrap())
+            .build()
+            .unwrap();
+        wallet
+            .account
+            .as_ref()
+            .unwrap()
+            .sign(
+                jwt::SIGNING_METHOD,
+                &mut credential,
+                ProofOptions::default(),
+            )
+            .await
+            .unwrap();
+        let credentials = Credentials::default();
+        credentials.insert(credential, None, None).unwrap();
+
+        let request = PresentationRequest {
+            credential_types: vec!["AgeCredential".to_string()],
+            attributes: vec!["age".to_string()],
+            challenge: "challenge".to_string(),
+            ..Default::default()
+        };
+        let other_challenge = PresentationRequest {
+            challenge: "other".to_string(),
+            ..request.clone()
+        };
+        for format in [PresentationFormat::LdpVp, PresentationFormat::JwtVp] {
+            let (_, attachment) = prepare_presentation(
+                &wallet,
+                &credentials,
+                &["urn:uuid:1".to_string()],
+                format,
+                None,
+                Some(&request.challenge),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+            let (presentation, verification) = verify_presentation(&attachment, Some(&request))
+                .await
+                .unwrap();
+            assert!(verification.verified, "{:?}", verification);
+            assert_eq!(presentation.verifiable_credential.len(), 1);
+
+            let (_, verification) = verify_presentation(&attachment, Some(&other_challenge))
+                .await
+                .unwrap();
+            assert!(!verification.verified);
+            assert!(!verification.errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_check_request() {
+        let credential: Credential = CredentialBuilder::default()
+            .issuer(Url::parse("did:iota:issuer").unwrap())
+            .type_("AgeCredential")
+            .subject(
+                Subject::from_json_value(json!({
+                    "id": "did:iota:holder",
+                    "attributes": [{"name": "age", "value": "42"}]
+                }))
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let presentation: Presentation = PresentationBuilder::default()
+            .holder(Url::parse("did:iota:holder").unwrap())
+            .credential(credential)
+            .build()
+            .unwrap();
+        let request = PresentationRequest {
+            credential_types: vec!["AgeCredential".to_string()],
+            attributes: vec!["age".to_string()],
+            ..Default::default()
+        };
+        assert!(check_request(&presentation, &request).is_empty());
+        let request = PresentationRequest {
+            credential_types: vec!["DegreeCredential".to_string()],
+            attributes: vec!["address".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(check_request(&presentation, &request).len(), 2);
+    }
+}
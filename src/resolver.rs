@@ -1,3 +1,6 @@
+use crate::auth::AdminAuth;
+use crate::error::{Error, Result};
+use crate::wallet::parse_did;
 use identity_iota::client::ExplorerUrl;
 use identity_iota::iota_core::IotaDID;
 use rocket::{get, serde::json::Json};
@@ -6,8 +9,9 @@ use rocket_okapi::openapi;
 /// # did resolver interface
 #[openapi(tag = "resolver")]
 #[get("/resolver/resolve/<did>")]
-pub fn get_resolve(did: String) -> Json<String> {
-    let iota_did: IotaDID = IotaDID::try_from(did).unwrap();
+pub fn get_resolve(_auth: AdminAuth, did: String) -> Result<Json<String>> {
+    let iota_did: IotaDID = parse_did(&did)?;
     let explorer: &ExplorerUrl = ExplorerUrl::mainnet();
-    Json(explorer.resolver_url(&iota_did).unwrap().to_string())
+    let resolver_url = explorer.resolver_url(&iota_did).map_err(Error::internal)?;
+    Ok(Json(resolver_url.to_string()))
 }
@@ -0,0 +1,293 @@
+use crate::auth::AdminAuth;
+use crate::error::{Error, Result};
+use crate::storage::{MemoryStorage, Record, Storage, Store};
+use crate::tenant::TenantState;
+use crate::wallet::Wallet;
+use identity_iota::account::Account;
+use identity_iota::core::Timestamp;
+use identity_iota::credential::{RevocationBitmapStatus, Status};
+use identity_iota::did::{RevocationBitmap, DID};
+use identity_iota::iota_core::IotaDIDUrl;
+use rocket::{post, serde::json::Json};
+use rocket_okapi::okapi::schemars::{self, JsonSchema};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Fragment of the revocation bitmap service in our did document.
+pub const REVOCATION_SERVICE: &str = "revocation-bitmap";
+
+/// Revocation index allocated to an issued credential.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RevocationEntry {
+    pub credential_id: String,
+    pub index: u32,
+    pub revoked: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Record for RevocationEntry {}
+
+pub struct Revocations {
+    pub entries: Store<RevocationEntry>,
+    allocating: std::sync::Mutex<()>,
+    service: Mutex<Option<IotaDIDUrl>>,
+}
+
+impl Revocations {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Revocations {
+            entries: Store::new(storage, "revocations"),
+            allocating: std::sync::Mutex::new(()),
+            service: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self, credential_id: &str) -> Result<RevocationEntry> {
+        self.entries
+            .get(credential_id)?
+            .ok_or_else(|| Error::CredentialNotFound(credential_id.to_string()))
+    }
+
+    fn next_index(&self) -> Result<u32> {
+        Ok(self
+            .entries
+            .values()?
+            .iter()
+            .map(|entry| entry.index + 1)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Stores the next index for the credential. Concurrent issuances are
+    /// allocated one after the other, so no index is handed out twice.
+    fn allocate_index(&self, credential_id: &str) -> Result<u32> {
+        let _allocating = self
+            .allocating
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let index = self.next_index()?;
+        let now = Timestamp::now_utc().to_string();
+        self.entries.insert(
+            credential_id,
+            RevocationEntry {
+                credential_id: credential_id.to_string(),
+                index,
+                revoked: false,
+                created_at: now.to_string(),
+                updated_at: now,
+            },
+        )?;
+        Ok(index)
+    }
+
+    /// Frees the index of a credential that was not issued.
+    pub fn release(&self, credential_id: &str) -> Result<()> {
+        self.entries.remove(credential_id)?;
+        Ok(())
+    }
+
+    /// The revocation bitmap service of our did document.
+    ///
+    /// The service is published with the first issuance, later issuances
+    /// neither wait for the ledger nor lock the wallet.
+    async fn service(&self, wallet: &Arc<Mutex<Wallet>>) -> Result<IotaDIDUrl> {
+        let mut service = self.service.lock().await;
+        if let Some(service) = service.as_ref() {
+            return Ok(service.clone());
+        }
+        let mut wallet = wallet.lock().await;
+        let account = account(&mut wallet)?;
+        ensure_service(account).await?;
+        let url = account
+            .did()
+            .to_url()
+            .join(format!("#{}", REVOCATION_SERVICE))
+            .map_err(Error::internal)?;
+        *service = Some(url.clone());
+        Ok(url)
+    }
+}
+
+impl Default for Revocations {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
+}
+
+fn account(wallet: &mut Wallet) -> Result<&mut Account> {
+    wallet
+        .account
+        .as_mut()
+        .ok_or_else(|| Error::NotFound("No Account".to_string()))
+}
+
+/// Publishes an empty revocation bitmap service unless the did document has one.
+async fn ensure_service(account: &mut Account) -> Result<()> {
+    let exists = account
+        .document()
+        .service()
+        .iter()
+        .any(|service| service.id().fragment() == Some(REVOCATION_SERVICE));
+    if exists {
+        return Ok(());
+    }
+    let endpoint = RevocationBitmap::new()
+        .to_endpoint()
+        .map_err(Error::internal)?;
+    account
+        .update_identity()
+        .create_service()
+        .fragment(REVOCATION_SERVICE)
+        .type_(RevocationBitmap::TYPE)
+        .endpoint(endpoint)
+        .apply()
+        .await
+        .map_err(|err| Error::PeerUnreachable(err.to_string()))
+}
+
+/// Allocates the next revocation index to the credential and returns the
+/// `credentialStatus` pointing into our revocation bitmap.
+///
+/// The index is kept until the credential is `release`d.
+pub async fn allocate(
+    revocations: &Revocations,
+    wallet: &Arc<Mutex<Wallet>>,
+    credential_id: &str,
+) -> Result<Status> {
+    let service = revocations.service(wallet).await?;
+    let index = revocations.allocate_index(credential_id)?;
+    Ok(RevocationBitmapStatus::new(service, index).into())
+}
+
+/// Sets the revocation state of the credential in the bitmap on the ledger.
+async fn set_revoked(
+    revocations: &Revocations,
+    wallet: &Arc<Mutex<Wallet>>,
+    credential_id: &str,
+    revoked: bool,
+) -> Result<RevocationEntry> {
+    let mut entry = revocations.get(credential_id)?;
+    let mut wallet = wallet.lock().await;
+    let account = account(&mut wallet)?;
+    let updated = match revoked {
+        true => {
+            account
+                .revoke_credentials(REVOCATION_SERVICE, &[entry.index])
+                .await
+        }
+        false => {
+            account
+                .unrevoke_credentials(REVOCATION_SERVICE, &[entry.index])
+                .await
+        }
+    };
+    updated.map_err(|err| Error::PeerUnreachable(err.to_string()))?;
+    entry.revoked = revoked;
+    entry.updated_at = Timestamp::now_utc().to_string();
+    revocations.entries.insert(credential_id, entry.clone())?;
+    Ok(entry)
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RevokeRequest {
+    pub credential_id: String,
+}
+
+/// # Revoke an issued credential
+#[openapi(tag = "revocation")]
+#[post("/revocation/revoke", data = "<request>")]
+pub async fn post_revoke(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    revocations: &TenantState<Revocations>,
+    request: Json<RevokeRequest>,
+) -> Result<Json<RevocationEntry>> {
+    let entry = set_revoked(revocations, wallet, &request.credential_id, true).await?;
+    Ok(Json(entry))
+}
+
+/// # Reinstate a revoked credential
+#[openapi(tag = "revocation")]
+#[post("/revocation/unrevoke", data = "<request>")]
+pub async fn post_unrevoke(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+    revocations: &TenantState<Revocations>,
+    request: Json<RevokeRequest>,
+) -> Result<Json<RevocationEntry>> {
+    let entry = set_revoked(revocations, wallet, &request.credential_id, false).await?;
+    Ok(Json(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rocket;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+
+    #[test]
+    fn test_next_index() {
+        let revocations = Revocations::default();
+        assert_eq!(revocations.next_index().unwrap(), 0);
+        for (credential_id, index) in [("a", 0), ("b", 4)] {
+            revocations
+                .entries
+                .insert(
+                    credential_id,
+                    RevocationEntry {
+                        credential_id: credential_id.to_string(),
+                        index,
+                        revoked: false,
+                        created_at: "".to_string(),
+                        updated_at: "".to_string(),
+                    },
+                )
+                .unwrap();
+        }
+        assert_eq!(revocations.next_index().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_allocate_index() {
+        let revocations = Arc::new(Revocations::default());
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let revocations = revocations.clone();
+                std::thread::spawn(move || {
+                    revocations
+                        .allocate_index(&format!("urn:uuid:{}", i))
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut indices: Vec<u32> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..8).collect::<Vec<u32>>());
+
+        revocations.release("urn:uuid:7").unwrap();
+        assert!(revocations.get("urn:uuid:7").is_err());
+        assert_eq!(revocations.allocate_index("urn:uuid:8").unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_revoke_unknown() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+        let response = client
+            .post("/revocation/revoke")
+            .json(&RevokeRequest {
+                credential_id: "urn:uuid:unknown".to_string(),
+            })
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
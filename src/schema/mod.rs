@@ -1,5 +1,9 @@
+use crate::auth::AdminAuth;
+use crate::error::{Error, FieldError, Result};
+use crate::storage::{MemoryStorage, Record, Storage, Store};
 use identity_iota::core::FromJson;
 use identity_iota::credential::Schema;
+use jsonschema::JSONSchema;
 use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::okapi::schemars;
@@ -7,13 +11,52 @@ use rocket_okapi::okapi::schemars::JsonSchema;
 use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
-#[derive(Default)]
+impl Record for Schema {}
+
 pub struct Schemas {
-    pub schemas: Arc<Mutex<HashMap<String, Schema>>>,
+    pub schemas: Store<Schema>,
+}
+
+impl Schemas {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Schemas {
+            schemas: Store::new(storage, "schemas"),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Schema> {
+        self.schemas
+            .get(id)?
+            .ok_or_else(|| Error::NotFound(format!("schema {}", id)))
+    }
+
+    /// Validates the attributes against the registered JSON schema, failures
+    /// are reported per field.
+    pub fn validate(&self, id: &str, attributes: &Value) -> Result<Schema> {
+        let schema = self.get(id)?;
+        let value = serde_json::to_value(&schema).map_err(Error::internal)?;
+        let compiled =
+            JSONSchema::compile(&value).map_err(|err| Error::InvalidSchema(err.to_string()))?;
+        let result = compiled.validate(attributes);
+        if let Err(errors) = result {
+            let errors = errors
+                .map(|error| FieldError {
+                    field: error.instance_path.to_string(),
+                    message: error.to_string(),
+                })
+                .collect();
+            return Err(Error::InvalidAttributes(errors));
+        }
+        Ok(schema)
+    }
+}
+
+impl Default for Schemas {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()))
+    }
 }
 
 fn example_schema() -> &'static str {
@@ -27,32 +70,33 @@ pub struct SchemaRequest(Value);
 #[openapi(tag = "schema")]
 #[post("/schemas", data = "<schema_request>")]
 pub async fn post_schemas(
+    _auth: AdminAuth,
     schemas: &State<Schemas>,
     schema_request: Json<SchemaRequest>,
-) -> Json<SchemaRequest> {
+) -> Result<Json<SchemaRequest>> {
     let schema = schema_request.clone();
-    let schema = serde_json::to_string(&schema.into_inner()).unwrap();
-    let schema: Schema = Schema::from_json(&schema).unwrap();
-    let mut schemas = schemas.schemas.lock().await;
-    schemas.insert(schema.id.to_string(), schema);
-    schema_request
+    let schema = serde_json::to_string(&schema.into_inner()).map_err(Error::internal)?;
+    let schema: Schema =
+        Schema::from_json(&schema).map_err(|err| Error::InvalidSchema(err.to_string()))?;
+    schemas.schemas.insert(&schema.id.to_string(), schema)?;
+    Ok(schema_request)
 }
 
 #[openapi(tag = "schema")]
 #[get("/schemas")]
-pub async fn get_all_schemas(schemas: &State<Schemas>) -> Json<Value> {
-    let lock = schemas.schemas.lock().await;
-    let schemas: Vec<Schema> = lock.values().cloned().collect();
-    let schemas = serde_json::to_value(schemas).unwrap();
-    Json(schemas)
+pub async fn get_all_schemas(_auth: AdminAuth, schemas: &State<Schemas>) -> Result<Json<Value>> {
+    let schemas: Vec<Schema> = schemas.schemas.values()?;
+    let schemas = serde_json::to_value(schemas).map_err(Error::internal)?;
+    Ok(Json(schemas))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::test_rocket;
     use rocket::http::{ContentType, Status};
     use rocket::local::asynchronous::Client;
-    use serde_json::Value;
+    use serde_json::json;
 
     #[tokio::test]
     async fn test_schema() {
@@ -75,5 +119,42 @@ mod tests {
         let response = response.into_json::<Value>().await.unwrap();
         let schemas = response.as_array().unwrap();
         assert_eq!(schemas.len(), 1);
+
+        let response = client
+            .post("/schemas")
+            .header(ContentType::JSON)
+            .body(r#"{"type": 42}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_validate() {
+        let schemas = Schemas::default();
+        let schema: Schema =
+            Schema::from_json(include_str!("../assets/degree_schema.json")).unwrap();
+        let id = schema.id.to_string();
+        schemas.schemas.insert(&id, schema).unwrap();
+
+        let attributes = json!({
+            "id": "did:example:123",
+            "name": "Alice",
+            "degree": {"type": "BachelorDegree", "name": "Bachelor of Science and Arts"},
+            "GPA": "4.0"
+        });
+        assert!(schemas.validate(&id, &attributes).is_ok());
+
+        match schemas.validate(&id, &json!({"id": "did:example:123", "name": 42})) {
+            Err(Error::InvalidAttributes(errors)) => {
+                assert!(errors.iter().any(|error| error.field == "/name"));
+                assert!(errors.iter().any(|error| error.field.is_empty()));
+            }
+            _ => panic!("expected invalid attributes"),
+        }
+        assert!(matches!(
+            schemas.validate("https://example.org/unknown.json", &attributes),
+            Err(Error::NotFound(_))
+        ));
     }
 }
@@ -0,0 +1,282 @@
+use crate::error::{Error, Result};
+use crate::jwt;
+use identity_iota::account::Account;
+use identity_iota::credential::Credential;
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// Hash algorithm of the disclosure digests.
+pub const SD_ALG: &str = "sha-256";
+
+/// An issuer signed jwt with the disclosures of its selectively disclosable
+/// claims, serialized as `<jwt>~<disclosure>~...~`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdJwt {
+    pub jwt: String,
+    pub disclosures: Vec<String>,
+}
+
+impl SdJwt {
+    pub fn parse(sd_jwt: &str) -> Result<Self> {
+        let mut parts = sd_jwt.split('~');
+        let jwt = parts.next().unwrap_or_default().to_string();
+        if !jwt::is_jwt(&jwt) {
+            return Err(Error::BadRequest("sd-jwt: invalid jwt".to_string()));
+        }
+        let disclosures = parts
+            .filter(|disclosure| !disclosure.is_empty())
+            .map(ToString::to_string)
+            .collect();
+        Ok(SdJwt { jwt, disclosures })
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut sd_jwt = format!("{}~", self.jwt);
+        for disclosure in &self.disclosures {
+            sd_jwt.push_str(disclosure);
+            sd_jwt.push('~');
+        }
+        sd_jwt
+    }
+
+    /// Claim names of the disclosures.
+    pub fn names(&self) -> Result<Vec<String>> {
+        self.disclosures
+            .iter()
+            .map(|disclosure| decode_disclosure(disclosure).map(|(name, _)| name))
+            .collect()
+    }
+
+    /// Keeps the disclosures of the named claims only.
+    pub fn select(&self, names: &[String]) -> Result<Self> {
+        let known = self.names()?;
+        if let Some(unknown) = names.iter().find(|name| !known.contains(name)) {
+            return Err(Error::BadRequest(format!(
+                "sd-jwt: no disclosure for {}",
+                unknown
+            )));
+        }
+        let disclosures = self
+            .disclosures
+            .iter()
+            .zip(known)
+            .filter(|(_, name)| names.contains(name))
+            .map(|(disclosure, _)| disclosure.to_string())
+            .collect();
+        Ok(SdJwt {
+            jwt: self.jwt.to_string(),
+            disclosures,
+        })
+    }
+}
+
+/// Whether the value looks like a serialized sd-jwt.
+pub fn is_sd_jwt(value: &str) -> bool {
+    value.contains('~') && SdJwt::parse(value).is_ok()
+}
+
+fn encode_disclosure(name: &str, value: &Value) -> Result<String> {
+    let salt = base64::encode_config(
+        rand::thread_rng().gen::<[u8; 16]>(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let json = serde_json::to_vec(&json!([salt, name, value])).map_err(Error::internal)?;
+    Ok(base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_disclosure(disclosure: &str) -> Result<(String, Value)> {
+    let json = base64::decode_config(disclosure, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| Error::BadRequest(format!("sd-jwt: {}", err)))?;
+    let decoded: (String, String, Value) = serde_json::from_slice(&json)
+        .map_err(|err| Error::BadRequest(format!("sd-jwt: disclosure: {}", err)))?;
+    Ok((decoded.1, decoded.2))
+}
+
+fn digest(disclosure: &str) -> String {
+    base64::encode_config(
+        Sha256::digest(disclosure.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Replaces the claims of the credential subject, except its `id`, by their
+/// digests in `_sd` and returns the disclosures. Preview attributes are
+/// disclosed one by one.
+fn conceal_subject(claims: &mut Value) -> Result<Vec<String>> {
+    let subject = match &mut claims["vc"]["credentialSubject"] {
+        Value::Array(subjects) if subjects.len() == 1 => &mut subjects[0],
+        subject => subject,
+    };
+    let subject = subject
+        .as_object_mut()
+        .ok_or_else(|| Error::BadRequest("sd-jwt: one credential subject expected".to_string()))?;
+    let mut concealed = Vec::new();
+    for (name, value) in std::mem::take(subject) {
+        match (name.as_str(), value) {
+            ("id", value) => {
+                subject.insert(name, value);
+            }
+            ("attributes", Value::Array(attributes)) => {
+                for attribute in attributes {
+                    let name = attribute["name"].as_str().unwrap_or_default().to_string();
+                    concealed.push((name, attribute["value"].clone()));
+                }
+            }
+            (_, value) => concealed.push((name, value)),
+        }
+    }
+    let mut names = HashSet::new();
+    if let Some((name, _)) = concealed
+        .iter()
+        .find(|(name, _)| name.as_str() == "id" || !names.insert(name))
+    {
+        return Err(Error::BadRequest(format!(
+            "sd-jwt: claim {} can not be disclosed on its own",
+            name
+        )));
+    }
+    let disclosures = concealed
+        .iter()
+        .map(|(name, value)| encode_disclosure(name, value))
+        .collect::<Result<Vec<String>>>()?;
+    let mut digests: Vec<String> = disclosures.iter().map(|d| digest(d)).collect();
+    digests.sort();
+    subject.insert("_sd".to_string(), json!(digests));
+    claims["_sd_alg"] = json!(SD_ALG);
+    Ok(disclosures)
+}
+
+/// Issues the credential as sd-jwt signed with `sign-0`, each claim of its
+/// subject can be disclosed on its own.
+pub async fn issue(account: &Account, credential: &Credential) -> Result<String> {
+    let mut claims = jwt::credential_claims(credential)?;
+    let disclosures = conceal_subject(&mut claims)?;
+    let jwt = jwt::sign(account, &claims).await?;
+    Ok(SdJwt { jwt, disclosures }.serialize())
+}
+
+/// The credential with the disclosed claims, without verifying the issuer
+/// signature. Disclosures not matching a digest or overriding a signed claim
+/// are rejected.
+pub fn decode(sd_jwt: &str) -> Result<Credential> {
+    let sd_jwt = SdJwt::parse(sd_jwt)?;
+    let claims = jwt::decode_claims(&sd_jwt.jwt)?;
+    if claims["_sd_alg"] != SD_ALG {
+        return Err(Error::BadRequest(format!(
+            "sd-jwt: unsupported _sd_alg {}",
+            claims["_sd_alg"]
+        )));
+    }
+    let mut vc = claims["vc"].clone();
+    let subject = match &mut vc["credentialSubject"] {
+        Value::Array(subjects) if subjects.len() == 1 => &mut subjects[0],
+        subject => subject,
+    };
+    let subject = subject
+        .as_object_mut()
+        .ok_or_else(|| Error::BadRequest("sd-jwt: one credential subject expected".to_string()))?;
+    let digests: HashSet<String> = subject
+        .remove("_sd")
+        .and_then(|digests| serde_json::from_value(digests).ok())
+        .unwrap_or_default();
+    let mut disclosed = Map::new();
+    for disclosure in &sd_jwt.disclosures {
+        if !digests.contains(&digest(disclosure)) {
+            return Err(Error::BadRequest(
+                "sd-jwt: disclosure without digest".to_string(),
+            ));
+        }
+        let (name, value) = decode_disclosure(disclosure)?;
+        if name == "id" || subject.contains_key(&name) || disclosed.contains_key(&name) {
+            return Err(Error::BadRequest(format!(
+                "sd-jwt: disclosure of {} overrides a claim",
+                name
+            )));
+        }
+        disclosed.insert(name, value);
+    }
+    subject.extend(disclosed);
+    let credential: Credential = serde_json::from_value(vc)
+        .map_err(|err| Error::BadRequest(format!("sd-jwt: vc claim: {}", err)))?;
+    if claims["iss"].as_str() != Some(credential.issuer.url().as_str()) {
+        return Err(Error::BadRequest(
+            "sd-jwt: iss is not the issuer".to_string(),
+        ));
+    }
+    Ok(credential)
+}
+
+/// Verifies the issuer signature and the disclosures and returns the
+/// credential with the disclosed claims.
+pub async fn verify(sd_jwt: &str) -> Result<Credential> {
+    jwt::verify(&SdJwt::parse(sd_jwt)?.jwt).await?;
+    decode(sd_jwt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identity_iota::core::{FromJson, Url};
+    use identity_iota::credential::{CredentialBuilder, Subject};
+
+    #[test]
+    fn test_disclosures() {
+        let credential: Credential = CredentialBuilder::default()
+            .id(Url::parse("urn:uuid:1").unwrap())
+            .issuer(Url::parse("did:iota:issuer").unwrap())
+            .subject(
+                Subject::from_json_value(json!({
+                    "id": "did:iota:holder",
+                    "attributes": [
+                        {"name": "age", "value": "42"},
+                        {"name": "address", "value": "Main Street 1"}
+                    ]
+                }))
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let mut claims = jwt::credential_claims(&credential).unwrap();
+        let disclosures = conceal_subject(&mut claims).unwrap();
+        assert_eq!(disclosures.len(), 2);
+        assert!(claims["vc"]["credentialSubject"]["attributes"].is_null());
+
+        let header = base64::encode_config(r#"{"alg":"EdDSA"}"#, base64::URL_SAFE_NO_PAD);
+        let payload = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+        let sd_jwt = SdJwt {
+            jwt: format!("{}.{}.", header, payload),
+            disclosures,
+        };
+        assert!(is_sd_jwt(&sd_jwt.serialize()));
+
+        let selected = sd_jwt.select(&["age".to_string()]).unwrap();
+        let decoded = decode(&selected.serialize()).unwrap();
+        let subject = decoded.credential_subject.first().unwrap();
+        assert_eq!(subject.properties["age"], "42");
+        assert!(!subject.properties.contains_key("address"));
+        assert!(sd_jwt.select(&["name".to_string()]).is_err());
+
+        let forged = SdJwt {
+            jwt: sd_jwt.jwt.clone(),
+            disclosures: vec![encode_disclosure("age", &json!("18")).unwrap()],
+        };
+        assert!(decode(&forged.serialize()).is_err());
+
+        let holder = encode_disclosure("id", &json!("did:iota:attacker")).unwrap();
+        let mut digests: Vec<String> =
+            serde_json::from_value(claims["vc"]["credentialSubject"]["_sd"].clone()).unwrap();
+        digests.push(digest(&holder));
+        claims["vc"]["credentialSubject"]["_sd"] = json!(digests);
+        let payload = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+        let overriding = SdJwt {
+            jwt: format!("{}.{}.", header, payload),
+            disclosures: vec![holder],
+        };
+        assert!(matches!(
+            decode(&overriding.serialize()),
+            Err(Error::BadRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,41 @@
+use super::{Error, Storage};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: Mutex<HashMap<String, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let trees = self.trees.lock().unwrap();
+        Ok(trees.get(tree).and_then(|tree| tree.get(key)).cloned())
+    }
+
+    fn insert(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        let mut trees = self.trees.lock().unwrap();
+        trees
+            .entry(tree.to_string())
+            .or_default()
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut trees = self.trees.lock().unwrap();
+        Ok(trees.get_mut(tree).and_then(|tree| tree.remove(key)))
+    }
+
+    fn entries(&self, tree: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let trees = self.trees.lock().unwrap();
+        Ok(trees
+            .get(tree)
+            .map(|tree| {
+                tree.iter()
+                    .map(|(key, value)| (key.to_string(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
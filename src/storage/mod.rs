@@ -0,0 +1,285 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+pub mod memory;
+pub mod prefixed;
+pub mod sled;
+
+pub use self::memory::MemoryStorage;
+pub use self::prefixed::PrefixedStorage;
+pub use self::sled::SledStorage;
+
+#[derive(Debug)]
+pub enum Error {
+    Backend(String),
+    Serde(serde_json::Error),
+    Migration { version: u32, reason: String },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Backend(err) => write!(f, "storage backend: {}", err),
+            Error::Serde(err) => write!(f, "storage serialization: {}", err),
+            Error::Migration { version, reason } => {
+                write!(f, "migration from version {}: {}", version, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+/// Key value backend the managed states are persisted with.
+///
+/// Records are grouped in trees, one per managed state.
+pub trait Storage: Send + Sync {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn insert(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), Error>;
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn entries(&self, tree: &str) -> Result<Vec<(String, Vec<u8>)>, Error>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Memory,
+    Sled,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: StorageBackend,
+    pub path: Option<String>,
+}
+
+pub fn from_config(config: &StorageConfig) -> Result<Arc<dyn Storage>, Error> {
+    match config.backend {
+        StorageBackend::Memory => Ok(Arc::new(MemoryStorage::default())),
+        StorageBackend::Sled => {
+            let path = config.path.as_deref().unwrap_or("storage.sled");
+            Ok(Arc::new(SledStorage::open(path)?))
+        }
+    }
+}
+
+/// A record kept in a `Store`.
+///
+/// Bump `VERSION` when the serialized form changes and upgrade older
+/// values in `migrate`.
+pub trait Record: Serialize + DeserializeOwned {
+    const VERSION: u32 = 1;
+
+    fn migrate(version: u32, _value: Value) -> Result<Value, Error> {
+        Err(Error::Migration {
+            version,
+            reason: "no migration defined".to_string(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    record: Value,
+}
+
+pub struct Store<T> {
+    storage: Arc<dyn Storage>,
+    tree: String,
+    _record: PhantomData<T>,
+}
+
+impl<T> Clone for Store<T> {
+    fn clone(&self) -> Self {
+        Store {
+            storage: self.storage.clone(),
+            tree: self.tree.clone(),
+            _record: PhantomData,
+        }
+    }
+}
+
+impl<T: Record> Store<T> {
+    pub fn new(storage: Arc<dyn Storage>, tree: &str) -> Self {
+        Store {
+            storage,
+            tree: tree.to_string(),
+            _record: PhantomData,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<T>, Error> {
+        match self.storage.get(&self.tree, id)? {
+            Some(bytes) => Ok(Some(self.decode(id, &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, id: &str, record: T) -> Result<(), Error> {
+        let envelope = Envelope {
+            version: T::VERSION,
+            record: serde_json::to_value(&record)?,
+        };
+        self.storage
+            .insert(&self.tree, id, serde_json::to_vec(&envelope)?)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<T>, Error> {
+        match self.storage.remove(&self.tree, id)? {
+            Some(bytes) => Ok(Some(self.decode(id, &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn values(&self) -> Result<Vec<T>, Error> {
+        self.storage
+            .entries(&self.tree)?
+            .iter()
+            .map(|(id, bytes)| self.decode(id, bytes))
+            .collect()
+    }
+
+    /// Removes all records of the store.
+    pub fn clear(&self) -> Result<usize, Error> {
+        let entries = self.storage.entries(&self.tree)?;
+        for (id, _) in &entries {
+            self.storage.remove(&self.tree, id)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Upgrades all records of older versions and writes them back.
+    ///
+    /// Returns the number of migrated records.
+    pub fn migrate(&self) -> Result<usize, Error> {
+        let mut migrated = 0;
+        for (id, bytes) in self.storage.entries(&self.tree)? {
+            let envelope: Envelope = serde_json::from_slice(&bytes)?;
+            if envelope.version < T::VERSION {
+                let record = self.decode(&id, &bytes)?;
+                self.insert(&id, record)?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+
+    fn decode(&self, id: &str, bytes: &[u8]) -> Result<T, Error> {
+        let envelope: Envelope = serde_json::from_slice(bytes)?;
+        let mut version = envelope.version;
+        let mut value = envelope.record;
+        while version < T::VERSION {
+            value = T::migrate(version, value)?;
+            version += 1;
+        }
+        if version > T::VERSION {
+            return Err(Error::Migration {
+                version,
+                reason: format!("record {} in {} is newer than supported", id, self.tree),
+            });
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Old {
+        name: String,
+    }
+
+    impl Record for Old {}
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct New {
+        name: String,
+        alias: String,
+    }
+
+    impl Record for New {
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, mut value: Value) -> Result<Value, Error> {
+            match version {
+                1 => {
+                    value["alias"] = value["name"].clone();
+                    Ok(value)
+                }
+                _ => Ok(value),
+            }
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let store: Store<Old> = Store::new(storage, "old");
+        store
+            .insert(
+                "1",
+                Old {
+                    name: "foo".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(store.values().unwrap().len(), 1);
+        assert_eq!(store.get("1").unwrap().unwrap().name, "foo");
+        assert!(store.remove("1").unwrap().is_some());
+        assert!(store.get("1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let old: Store<Old> = Store::new(storage.clone(), "records");
+        old.insert(
+            "1",
+            Old {
+                name: "foo".to_string(),
+            },
+        )
+        .unwrap();
+
+        let new: Store<New> = Store::new(storage.clone(), "records");
+        assert_eq!(new.get("1").unwrap().unwrap().alias, "foo");
+        assert_eq!(new.migrate().unwrap(), 1);
+        assert_eq!(new.migrate().unwrap(), 0);
+        assert!(old.get("1").is_err());
+    }
+
+    #[test]
+    fn test_prefixed() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let tenant: Store<Old> = Store::new(
+            Arc::new(PrefixedStorage::new(storage.clone(), "tenant")),
+            "records",
+        );
+        let other: Store<Old> = Store::new(storage, "records");
+        tenant
+            .insert(
+                "1",
+                Old {
+                    name: "foo".to_string(),
+                },
+            )
+            .unwrap();
+        assert!(other.get("1").unwrap().is_none());
+        assert_eq!(tenant.clear().unwrap(), 1);
+        assert!(tenant.values().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use super::{Error, Storage};
+use std::sync::Arc;
+
+/// Keeps the trees of a tenant apart from others in a shared storage.
+pub struct PrefixedStorage {
+    storage: Arc<dyn Storage>,
+    prefix: String,
+}
+
+impl PrefixedStorage {
+    pub fn new(storage: Arc<dyn Storage>, prefix: &str) -> Self {
+        PrefixedStorage {
+            storage,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn tree(&self, tree: &str) -> String {
+        format!("{}/{}", self.prefix, tree)
+    }
+}
+
+impl Storage for PrefixedStorage {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.storage.get(&self.tree(tree), key)
+    }
+
+    fn insert(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.storage.insert(&self.tree(tree), key, value)
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.storage.remove(&self.tree(tree), key)
+    }
+
+    fn entries(&self, tree: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.storage.entries(&self.tree(tree))
+    }
+}
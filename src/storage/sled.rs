@@ -0,0 +1,72 @@
+use super::{Error, Storage};
+
+pub struct SledStorage {
+    db: ::sled::Db,
+}
+
+impl From<::sled::Error> for Error {
+    fn from(err: ::sled::Error) -> Self {
+        Error::Backend(err.to_string())
+    }
+}
+
+impl SledStorage {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Ok(SledStorage {
+            db: ::sled::open(path)?,
+        })
+    }
+
+    pub fn temporary() -> Result<Self, Error> {
+        Ok(SledStorage {
+            db: ::sled::Config::new().temporary(true).open()?,
+        })
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let tree = self.db.open_tree(tree)?;
+        Ok(tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        let tree = self.db.open_tree(tree)?;
+        tree.insert(key, value)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let tree = self.db.open_tree(tree)?;
+        let removed = tree.remove(key)?.map(|value| value.to_vec());
+        tree.flush()?;
+        Ok(removed)
+    }
+
+    fn entries(&self, tree: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let tree = self.db.open_tree(tree)?;
+        tree.iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((String::from_utf8_lossy(&key).to_string(), value.to_vec()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sled_storage() {
+        let storage = SledStorage::temporary().unwrap();
+        storage.insert("tree", "key", b"value".to_vec()).unwrap();
+        assert_eq!(storage.get("tree", "key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(storage.entries("tree").unwrap().len(), 1);
+        assert!(storage.get("other", "key").unwrap().is_none());
+        storage.remove("tree", "key").unwrap();
+        assert!(storage.entries("tree").unwrap().is_empty());
+    }
+}
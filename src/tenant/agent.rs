@@ -0,0 +1,211 @@
+use super::{Tenants, TENANT_TOKEN_HEADER};
+use crate::connection::{invitation::Invitations, ConnectionEvents, Connections};
+use crate::credential::{CredentialExchanges, Credentials, IssueCredentialEvents};
+use crate::error::{Error, Result};
+use crate::message::MessageEvents;
+use crate::ping::PingEvents;
+use crate::presentation::{PresentProofEvents, PresentationExchanges};
+use crate::revocation::Revocations;
+use crate::storage::Storage;
+use crate::wallet::wallet_did;
+use crate::webhook::WebhookPool;
+use crate::{Config, Wallet};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Wallet and state of the agent itself or one of its tenants.
+pub struct Agent {
+    pub did: String,
+    pub wallet: Arc<Mutex<Wallet>>,
+    pub connections: Connections,
+    pub invitations: Invitations,
+    pub credentials: Credentials,
+    pub exchanges: CredentialExchanges,
+    pub presentation_exchanges: PresentationExchanges,
+    pub revocations: Revocations,
+    pub webhook_pool: WebhookPool,
+    pub connection_events: Arc<Mutex<ConnectionEvents>>,
+    pub issue_credential_events: Arc<Mutex<IssueCredentialEvents>>,
+    pub ping_events: Arc<Mutex<PingEvents>>,
+    pub message_events: Arc<Mutex<MessageEvents>>,
+    pub present_proof_events: Arc<Mutex<PresentProofEvents>>,
+}
+
+impl Agent {
+    /// Opens the wallet of the config and the records kept in the storage,
+    /// events are posted to the webhooks of the pool.
+    pub async fn new(
+        config: &Config,
+        storage: Arc<dyn Storage>,
+        webhook_pool: WebhookPool,
+    ) -> Result<Self> {
+        let connections: Connections = Connections::new(storage.clone());
+        let credentials: Credentials = Credentials::new(storage.clone());
+        let invitations: Invitations = Invitations::new(storage.clone());
+        let exchanges: CredentialExchanges = CredentialExchanges::new(storage.clone());
+        let presentation_exchanges: PresentationExchanges =
+            PresentationExchanges::new(storage.clone());
+        let revocations: Revocations = Revocations::new(storage);
+        connections.connections.migrate()?;
+        credentials.credentials.migrate()?;
+        invitations.invitations.migrate()?;
+        exchanges.exchanges.migrate()?;
+        presentation_exchanges.exchanges.migrate()?;
+        revocations.entries.migrate()?;
+
+        let wallet = Wallet::new_from_config(config)
+            .await
+            .map_err(Error::internal)?;
+        wallet.log();
+        let did = wallet_did(&wallet)?.to_string();
+        let wallet = Arc::new(Mutex::new(wallet));
+
+        let connection_events: Arc<Mutex<ConnectionEvents>> =
+            Arc::new(Mutex::new(ConnectionEvents::new()));
+        let issue_credential_events: Arc<Mutex<IssueCredentialEvents>> =
+            Arc::new(Mutex::new(IssueCredentialEvents::new()));
+        let ping_events: Arc<Mutex<PingEvents>> = Arc::new(Mutex::new(PingEvents::new()));
+        let message_events: Arc<Mutex<MessageEvents>> = Arc::new(Mutex::new(MessageEvents::new()));
+        let present_proof_events: Arc<Mutex<PresentProofEvents>> =
+            Arc::new(Mutex::new(PresentProofEvents::new()));
+
+        let mut webhook_pool = webhook_pool;
+
+        webhook_pool
+            .spawn_connection_events(connection_events.clone())
+            .await;
+        webhook_pool
+            .spawn_issue_credential_events(issue_credential_events.clone())
+            .await;
+        webhook_pool.spawn_ping_events(ping_events.clone()).await;
+        webhook_pool
+            .spawn_message_events(message_events.clone())
+            .await;
+        webhook_pool
+            .spawn_present_proof_events(present_proof_events.clone())
+            .await;
+
+        Ok(Agent {
+            did,
+            wallet,
+            connections,
+            invitations,
+            credentials,
+            exchanges,
+            presentation_exchanges,
+            revocations,
+            webhook_pool,
+            connection_events,
+            issue_credential_events,
+            ping_events,
+            message_events,
+            present_proof_events,
+        })
+    }
+
+    /// Removes all records of the agent.
+    pub fn clear(&self) -> Result<()> {
+        self.connections.connections.clear()?;
+        self.invitations.invitations.clear()?;
+        self.credentials.credentials.clear()?;
+        self.exchanges.exchanges.clear()?;
+        self.presentation_exchanges.exchanges.clear()?;
+        self.revocations.entries.clear()?;
+        Ok(())
+    }
+}
+
+/// State each agent keeps for itself.
+pub trait AgentState: Send + Sync + 'static {
+    fn state(agent: &Agent) -> &Self;
+}
+
+macro_rules! agent_state {
+    ($type:ty, $field:ident) => {
+        impl AgentState for $type {
+            fn state(agent: &Agent) -> &Self {
+                &agent.$field
+            }
+        }
+    };
+}
+
+agent_state!(Arc<Mutex<Wallet>>, wallet);
+agent_state!(Connections, connections);
+agent_state!(Invitations, invitations);
+agent_state!(Credentials, credentials);
+agent_state!(CredentialExchanges, exchanges);
+agent_state!(PresentationExchanges, presentation_exchanges);
+agent_state!(Revocations, revocations);
+agent_state!(WebhookPool, webhook_pool);
+agent_state!(Arc<Mutex<ConnectionEvents>>, connection_events);
+agent_state!(Arc<Mutex<IssueCredentialEvents>>, issue_credential_events);
+agent_state!(Arc<Mutex<PingEvents>>, ping_events);
+agent_state!(Arc<Mutex<MessageEvents>>, message_events);
+agent_state!(Arc<Mutex<PresentProofEvents>>, present_proof_events);
+
+/// Request guard to the state of the agent selected by the tenant token.
+///
+/// Requests without `X-Tenant-Token` header use the agent itself.
+pub struct TenantState<T> {
+    agent: Arc<Agent>,
+    _state: PhantomData<fn() -> T>,
+}
+
+impl<T: AgentState> Deref for TenantState<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        T::state(&self.agent)
+    }
+}
+
+/// Agent of the request, cached so all guards of a request share it.
+struct RequestAgent(Option<Arc<Agent>>);
+
+#[rocket::async_trait]
+impl<'r, T: AgentState> FromRequest<'r> for &'r TenantState<T> {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tenants = match request.rocket().state::<Tenants>() {
+            Some(tenants) => tenants,
+            None => {
+                let err = Error::Internal("tenants not managed".to_string());
+                return Outcome::Failure((err.status(), err));
+            }
+        };
+        let agent = request
+            .local_cache_async(async {
+                let token = request.headers().get_one(TENANT_TOKEN_HEADER);
+                RequestAgent(tenants.agent(token).await)
+            })
+            .await;
+        match &agent.0 {
+            Some(agent) => Outcome::Success(request.local_cache(|| TenantState::<T> {
+                agent: agent.clone(),
+                _state: PhantomData,
+            })),
+            None => {
+                let err = Error::Unauthorized("unknown tenant token".to_string());
+                warn!("{}", err);
+                Outcome::Failure((err.status(), err))
+            }
+        }
+    }
+}
+
+impl<'r, T: AgentState> OpenApiFromRequest<'r> for &'r TenantState<T> {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
@@ -0,0 +1,352 @@
+use crate::auth::{digest_matches, key_digest, AdminAuth};
+use crate::error::{Error, Result};
+use crate::storage::{self, PrefixedStorage, Record, Storage, Store};
+use crate::webhook::WebhookPool;
+use crate::Config;
+use base58::ToBase58;
+use did_key::{generate, DIDCore, KeyMaterial, X25519KeyPair};
+use identity_iota::core::Timestamp;
+use rocket::http::Status;
+use rocket::State;
+use rocket::{get, post, serde::json::Json};
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+pub mod agent;
+pub use agent::{Agent, AgentState, TenantState};
+
+pub const TENANT_TOKEN_HEADER: &str = "X-Tenant-Token";
+
+/// A sub wallet of the agent with its own did and records.
+///
+/// The key of a tenant is derived from the key of the agent, so no key
+/// material is stored with the tenant. Neither is its token, only the digest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tenant {
+    pub id: String,
+    pub label: Option<String>,
+    pub did: String,
+    pub token_digest: String,
+    pub created_at: String,
+}
+
+impl Record for Tenant {
+    const VERSION: u32 = 2;
+
+    fn migrate(version: u32, mut value: Value) -> std::result::Result<Value, storage::Error> {
+        if version == 1 {
+            let token = value["token"].as_str().unwrap_or_default().to_string();
+            value["token_digest"] = json!(key_digest(&token));
+            if let Some(tenant) = value.as_object_mut() {
+                tenant.remove("token");
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct CreateTenantRequest {
+    pub label: Option<String>,
+}
+
+/// A tenant as shown on the admin api, the token only on creation.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct TenantResponse {
+    pub tenant_id: String,
+    pub label: Option<String>,
+    pub did: String,
+    pub token: Option<String>,
+    pub created_at: String,
+}
+
+impl From<&Tenant> for TenantResponse {
+    fn from(tenant: &Tenant) -> Self {
+        TenantResponse {
+            tenant_id: tenant.id.to_string(),
+            label: tenant.label.clone(),
+            did: tenant.did.to_string(),
+            token: None,
+            created_at: tenant.created_at.to_string(),
+        }
+    }
+}
+
+/// The agent and the agents of its tenants.
+pub struct Tenants {
+    pub tenants: Store<Tenant>,
+    base: Arc<Agent>,
+    agents: Mutex<HashMap<String, Arc<Agent>>>,
+    storage: Arc<dyn Storage>,
+    config: Config,
+}
+
+impl Tenants {
+    /// Opens the agents of all stored tenants next to the base agent.
+    pub async fn new(config: Config, storage: Arc<dyn Storage>, base: Agent) -> Result<Self> {
+        let tenants: Store<Tenant> = Store::new(storage.clone(), "tenants");
+        tenants.migrate()?;
+        let mut agents = HashMap::new();
+        for tenant in tenants.values()? {
+            let seed = key_seed(&base, &tenant.id).await;
+            let agent =
+                open_agent(&config, storage.clone(), &tenant, &seed, Some(&tenant.did)).await?;
+            agents.insert(tenant.id.to_string(), Arc::new(agent));
+        }
+        Ok(Tenants {
+            tenants,
+            base: Arc::new(base),
+            agents: Mutex::new(agents),
+            storage,
+            config,
+        })
+    }
+
+    pub fn base(&self) -> Arc<Agent> {
+        self.base.clone()
+    }
+
+    /// Creates a tenant with a new wallet and did, returned with its token.
+    pub async fn create(&self, label: Option<String>) -> Result<(Tenant, String)> {
+        let token = Uuid::new_v4().to_simple().to_string();
+        let mut tenant = Tenant {
+            id: Uuid::new_v4().to_string(),
+            label,
+            did: String::new(),
+            token_digest: key_digest(&token),
+            created_at: Timestamp::now_utc().to_string(),
+        };
+        let seed = key_seed(&self.base, &tenant.id).await;
+        let agent = open_agent(&self.config, self.storage.clone(), &tenant, &seed, None).await?;
+        tenant.did = agent.did.to_string();
+        self.tenants.insert(&tenant.id, tenant.clone())?;
+        self.agents
+            .lock()
+            .await
+            .insert(tenant.id.to_string(), Arc::new(agent));
+        Ok((tenant, token))
+    }
+
+    /// Removes a tenant, all its records and its wallet file.
+    pub async fn remove(&self, tenant_id: &str) -> Result<Tenant> {
+        let tenant = self
+            .tenants
+            .remove(tenant_id)?
+            .ok_or_else(|| Error::NotFound(format!("tenant {}", tenant_id)))?;
+        if let Some(agent) = self.agents.lock().await.remove(tenant_id) {
+            agent.clear()?;
+        }
+        if let Some(path) = wallet_path(&self.config, &tenant) {
+            if Path::new(&path).exists() {
+                std::fs::remove_file(&path).map_err(Error::internal)?;
+            }
+        }
+        Ok(tenant)
+    }
+
+    /// Agent of a tenant token, the base agent without token.
+    pub async fn agent(&self, token: Option<&str>) -> Option<Arc<Agent>> {
+        let token = match token {
+            Some(token) => token,
+            None => return Some(self.base()),
+        };
+        let tenant = self
+            .tenants
+            .values()
+            .ok()?
+            .into_iter()
+            .find(|tenant| digest_matches(token, &tenant.token_digest))?;
+        self.agents.lock().await.get(&tenant.id).cloned()
+    }
+
+    /// Agents an inbound message for the recipient dids is tried with.
+    ///
+    /// Messages without known recipient are tried with the base agent first,
+    /// then with all tenants.
+    pub async fn recipients(&self, dids: &[String]) -> Vec<Arc<Agent>> {
+        let agents = self.agents.lock().await;
+        let mut all: Vec<Arc<Agent>> = vec![self.base()];
+        all.extend(agents.values().cloned());
+        let matching: Vec<Arc<Agent>> = all
+            .iter()
+            .filter(|agent| dids.contains(&agent.did))
+            .cloned()
+            .collect();
+        match matching.is_empty() {
+            true => all,
+            false => matching,
+        }
+    }
+}
+
+/// Key seed of a tenant, the hash of the agent key and the tenant id.
+async fn key_seed(base: &Agent, tenant_id: &str) -> Vec<u8> {
+    let private_key = base.wallet.lock().await.keypair().private_key_bytes();
+    let mut hasher = Sha256::new();
+    hasher.update(&private_key);
+    hasher.update(tenant_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// Wallet file of a tenant next to the wallet of the agent.
+fn wallet_path(config: &Config, tenant: &Tenant) -> Option<String> {
+    config
+        .wallet_path
+        .as_ref()
+        .map(|path| format!("{}.{}", path, tenant.id))
+}
+
+/// Opens the wallet of a tenant, a new did is created without `did`.
+async fn open_agent(
+    config: &Config,
+    storage: Arc<dyn Storage>,
+    tenant: &Tenant,
+    seed: &[u8],
+    did: Option<&str>,
+) -> Result<Agent> {
+    let key = generate::<X25519KeyPair>(Some(seed));
+    let mut config = config.clone();
+    config.key_seed = Some(seed.to_base58());
+    config.did_key = Some(key.get_did_document(Default::default()).id);
+    config.did_iota = did.map(|did| did.to_string());
+    config.wallet_path = wallet_path(&config, tenant);
+    let storage = Arc::new(PrefixedStorage::new(
+        storage,
+        &format!("tenant/{}", tenant.id),
+    ));
+    Agent::new(&config, storage, WebhookPool::default()).await
+}
+
+/// # Create a tenant wallet
+///
+/// The returned token selects the tenant in the `X-Tenant-Token` header
+/// of admin requests.
+#[openapi(tag = "multitenancy")]
+#[post("/multitenancy/wallet", data = "<request>")]
+pub async fn post_tenant(
+    _auth: AdminAuth,
+    tenants: &State<Tenants>,
+    request: Json<CreateTenantRequest>,
+) -> Result<Json<TenantResponse>> {
+    let (tenant, token) = tenants.create(request.into_inner().label).await?;
+    Ok(Json(TenantResponse {
+        token: Some(token),
+        ..TenantResponse::from(&tenant)
+    }))
+}
+
+/// # List tenant wallets
+#[openapi(tag = "multitenancy")]
+#[get("/multitenancy/wallets")]
+pub async fn get_all_tenants(
+    _auth: AdminAuth,
+    tenants: &State<Tenants>,
+) -> Result<Json<Vec<TenantResponse>>> {
+    let mut tenants: Vec<Tenant> = tenants.tenants.values()?;
+    tenants.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(Json(tenants.iter().map(TenantResponse::from).collect()))
+}
+
+/// # Delete a tenant wallet and its records
+#[openapi(tag = "multitenancy")]
+#[delete("/multitenancy/wallet/<tenant_id>")]
+pub async fn delete_tenant(
+    _auth: AdminAuth,
+    tenants: &State<Tenants>,
+    tenant_id: String,
+) -> Result<Status> {
+    tenants.remove(&tenant_id).await?;
+    Ok(Status::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::connect;
+    use crate::test_rocket;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let client = Client::tracked(test_rocket().await)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .post("/multitenancy/wallet")
+            .header(ContentType::JSON)
+            .body(r#"{"label": "tenant"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let tenant: TenantResponse = response.into_json().await.unwrap();
+        let token = tenant.token.unwrap();
+
+        let response = client
+            .get("/wallet/did/public")
+            .header(Header::new(TENANT_TOKEN_HEADER, token.to_string()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let did: Value = response.into_json().await.unwrap();
+        assert_eq!(did["id"], tenant.did);
+
+        connect(&client).await.unwrap();
+        let response = client
+            .get("/connections")
+            .header(Header::new(TENANT_TOKEN_HEADER, token.to_string()))
+            .dispatch()
+            .await;
+        let connections: Value = response.into_json().await.unwrap();
+        assert_eq!(connections.as_array().unwrap().len(), 0);
+
+        let response = client.get("/multitenancy/wallets").dispatch().await;
+        let tenants: Vec<TenantResponse> = response.into_json().await.unwrap();
+        assert_eq!(tenants.len(), 1);
+        assert!(tenants[0].token.is_none());
+
+        let tenants = client.rocket().state::<Tenants>().unwrap();
+        let stored = tenants.tenants.get(&tenant.tenant_id).unwrap().unwrap();
+        assert_eq!(stored.token_digest, key_digest(&token));
+        let path = wallet_path(&tenants.config, &stored);
+
+        let response = client
+            .delete(format!("/multitenancy/wallet/{}", tenant.tenant_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        if let Some(path) = path {
+            assert!(!Path::new(&path).exists());
+        }
+
+        let response = client
+            .get("/connections")
+            .header(Header::new(TENANT_TOKEN_HEADER, token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let tenant = json!({
+            "id": "1",
+            "label": null,
+            "did": "did:iota:tenant",
+            "token": "secret",
+            "created_at": "",
+        });
+        let migrated: Tenant = serde_json::from_value(Tenant::migrate(1, tenant).unwrap()).unwrap();
+        assert!(digest_matches("secret", &migrated.token_digest));
+    }
+}
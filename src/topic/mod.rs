@@ -1,3 +1,4 @@
+use crate::auth::AdminAuth;
 use rocket::http::Status;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::openapi;
@@ -5,14 +6,14 @@ use serde_json::Value;
 
 #[openapi(tag = "topic")]
 #[post("/topic/<name>")]
-pub async fn post_topic(name: String) -> Status {
+pub async fn post_topic(_auth: AdminAuth, name: String) -> Status {
     info!("name: {}", name);
     Status::Ok
 }
 
 #[openapi(tag = "topic")]
 #[post("/topic/message", data = "<payload>")]
-pub async fn post_message_topic(payload: Json<Value>) -> Status {
+pub async fn post_message_topic(_auth: AdminAuth, payload: Json<Value>) -> Status {
     debug!("payload: {}", payload.into_inner());
     Status::Ok
 }
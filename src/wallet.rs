@@ -1,11 +1,11 @@
+use crate::auth::AdminAuth;
+use crate::error::{Error, Result};
+use crate::tenant::TenantState;
 pub use didcomm_mediator::wallet::Wallet;
-use identity_iota::account::Result;
 use identity_iota::client::ResolvedIotaDocument;
 use identity_iota::client::Resolver;
 use identity_iota::core::Url;
 use identity_iota::iota_core::IotaDID;
-use rocket::response::status::NotFound;
-use rocket::State;
 use rocket::{get, post, serde::json::Json};
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
@@ -28,69 +28,104 @@ pub struct DidEndpoint {
     endpoint: String,
 }
 
+/// Parses an iota did, rejecting malformed dids as bad request.
+pub fn parse_did(did: &str) -> Result<IotaDID> {
+    IotaDID::from_str(did).map_err(|err| Error::InvalidDid(format!("{}: {}", did, err)))
+}
+
+/// Resolves the did document from the ledger.
+pub async fn resolve_did(did: &str) -> Result<ResolvedIotaDocument> {
+    let did = parse_did(did)?;
+    let resolver: Resolver = Resolver::new()
+        .await
+        .map_err(|err| Error::PeerUnreachable(err.to_string()))?;
+    resolver
+        .resolve(&did)
+        .await
+        .map_err(|err| Error::PeerUnreachable(format!("resolve {}: {}", did, err)))
+}
+
+/// The did of the wallet, a wallet without did is an internal error.
+pub fn wallet_did(wallet: &Wallet) -> Result<IotaDID> {
+    let did = wallet
+        .did_iota()
+        .ok_or_else(|| Error::Internal("wallet has no did".to_string()))?;
+    parse_did(&did)
+}
+
 #[openapi(tag = "wallet")]
 #[get("/wallet/did")]
-pub async fn get_all_dids(wallet: &State<Arc<Mutex<Wallet>>>) -> Json<Vec<Did>> {
-    let wallet = wallet.try_lock().unwrap();
-    let did: IotaDID = IotaDID::from_str(&wallet.did_iota().unwrap()).unwrap();
+pub async fn get_all_dids(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+) -> Result<Json<Vec<Did>>> {
+    let wallet = wallet.lock().await;
+    let did: IotaDID = wallet_did(&wallet)?;
     let key_type = "Ed25519VerificationKey2018".to_string();
-    Json(vec![Did {
+    Ok(Json(vec![Did {
         id: did.to_string(),
         key_type,
-    }])
+    }]))
 }
 
 #[openapi(tag = "wallet")]
 #[get("/wallet/did/public")]
-pub async fn get_public_did(wallet: &State<Arc<Mutex<Wallet>>>) -> Json<Did> {
-    let wallet = wallet.try_lock().unwrap();
-    let did: IotaDID = IotaDID::from_str(&wallet.did_iota().unwrap()).unwrap();
+pub async fn get_public_did(
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
+) -> Result<Json<Did>> {
+    let wallet = wallet.lock().await;
+    let did: IotaDID = wallet_did(&wallet)?;
     let key_type = "Ed25519VerificationKey2018".to_string();
-    Json(Did {
+    Ok(Json(Did {
         id: did.to_string(),
         key_type,
-    })
+    }))
 }
 
-#[openapi(tag = "wallet")]
-#[get("/wallet/get-did-endpoint?<did>")]
-pub async fn get_did_endpoint(did: String) -> Json<String> {
-    let did = IotaDID::from_str(&did).unwrap();
-    let resolver: Resolver = Resolver::new().await.unwrap();
-    let resolved_did_document: ResolvedIotaDocument = resolver.resolve(&did).await.unwrap();
+/// Resolves the service endpoint of a did.
+pub async fn did_endpoint(did: &str) -> Result<String> {
+    let resolved_did_document: ResolvedIotaDocument = resolve_did(did).await?;
 
     let document = resolved_did_document.document;
     let services = document.service();
-    let service = services.first().unwrap();
+    let service = services
+        .first()
+        .ok_or_else(|| Error::NotFound(format!("no service endpoint for {}", did)))?;
     let endpoint = service.service_endpoint().to_string();
-    let endpoint = endpoint.replace('\"', "");
-    Json(endpoint)
+    Ok(endpoint.replace('\"', ""))
+}
+
+#[openapi(tag = "wallet")]
+#[get("/wallet/get-did-endpoint?<did>")]
+pub async fn get_did_endpoint(_auth: AdminAuth, did: String) -> Result<Json<String>> {
+    Ok(Json(did_endpoint(&did).await?))
 }
 
 #[openapi(tag = "wallet")]
 #[post("/wallet/set-did-endpoint", data = "<post_data>")]
 pub async fn post_did_endpoint(
-    wallet: &State<Arc<Mutex<Wallet>>>,
+    _auth: AdminAuth,
+    wallet: &TenantState<Arc<Mutex<Wallet>>>,
     post_data: Json<DidEndpoint>,
-) -> Result<(), NotFound<String>> {
-    let mut wallet = wallet.try_lock().unwrap();
-    let updated = match wallet.account.as_mut() {
-        Some(account) => {
-            account
-                .update_identity()
-                .create_service()
-                .fragment("endpoint")
-                .type_("Endpoint")
-                .endpoint(Url::parse(&post_data.endpoint).unwrap())
-                .apply()
-                .await
-                .unwrap();
-            Ok(())
-        }
-        None => Err(NotFound("No Account".to_string())),
-    };
-    std::mem::drop(wallet);
-    updated
+) -> Result<()> {
+    let endpoint = Url::parse(&post_data.endpoint)
+        .map_err(|err| Error::BadRequest(format!("{}: {}", post_data.endpoint, err)))?;
+    let mut wallet = wallet.lock().await;
+    let account = wallet
+        .account
+        .as_mut()
+        .ok_or_else(|| Error::NotFound("No Account".to_string()))?;
+    account
+        .update_identity()
+        .create_service()
+        .fragment("endpoint")
+        .type_("Endpoint")
+        .endpoint(endpoint)
+        .apply()
+        .await
+        .map_err(|err| Error::PeerUnreachable(err.to_string()))?;
+    Ok(())
 }
 
 #[cfg(test)]
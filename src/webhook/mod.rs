@@ -1,12 +1,14 @@
+use crate::auth::AdminAuth;
 use crate::connection::ConnectionEvents;
 use crate::credential::IssueCredentialEvents;
+use crate::error::Error;
 use crate::message::MessageEvents;
 use crate::ping::PingEvents;
 use crate::presentation::PresentProofEvents;
+use crate::tenant::TenantState;
 use async_trait::async_trait;
 use reqwest::RequestBuilder;
 use rocket::http::Status;
-use rocket::State;
 use rocket::{post, serde::json::Json};
 use rocket_okapi::openapi;
 use serde_json::Value;
@@ -73,10 +75,10 @@ impl WebhookPool {
         body: &Value,
         webhooks: WebhookHashMap,
     ) -> Result<(), reqwest::Error> {
-        let map = webhooks.try_lock().unwrap();
+        let map = webhooks.lock().await;
         for (key, value) in &*map {
             let (_webhook_endpoint, webhook) = value;
-            match webhook.try_lock().unwrap().post(topic, body).await {
+            match webhook.lock().await.post(topic, body).await {
                 Ok(_) => (),
                 Err(err) => println!("{}: {:?}", key, err),
             }
@@ -89,7 +91,7 @@ impl WebhookPool {
         connection_events: Arc<Mutex<ConnectionEvents>>,
     ) {
         let mut events = {
-            let mut connection_events = connection_events.try_lock().unwrap();
+            let mut connection_events = connection_events.lock().await;
             connection_events
                 .observe(Channel::Bounded(20).into())
                 .await
@@ -120,7 +122,7 @@ impl WebhookPool {
         issue_credential_events: Arc<Mutex<IssueCredentialEvents>>,
     ) {
         let mut events = {
-            let mut issue_credential_events = issue_credential_events.try_lock().unwrap();
+            let mut issue_credential_events = issue_credential_events.lock().await;
             issue_credential_events
                 .observe(Channel::Bounded(20).into())
                 .await
@@ -148,7 +150,7 @@ impl WebhookPool {
 
     pub async fn spawn_ping_events(&mut self, ping_events: Arc<Mutex<PingEvents>>) {
         let mut events = {
-            let mut ping_events = ping_events.try_lock().unwrap();
+            let mut ping_events = ping_events.lock().await;
             ping_events
                 .observe(Channel::Unbounded.into())
                 .await
@@ -176,7 +178,7 @@ impl WebhookPool {
 
     pub async fn spawn_message_events(&mut self, message_events: Arc<Mutex<MessageEvents>>) {
         let mut events = {
-            let mut message_events = message_events.try_lock().unwrap();
+            let mut message_events = message_events.lock().await;
             message_events
                 .observe(Channel::Unbounded.into())
                 .await
@@ -207,7 +209,7 @@ impl WebhookPool {
         present_proof_events: Arc<Mutex<PresentProofEvents>>,
     ) {
         let mut events = {
-            let mut present_proof_events = present_proof_events.try_lock().unwrap();
+            let mut present_proof_events = present_proof_events.lock().await;
             present_proof_events
                 .observe(Channel::Unbounded.into())
                 .await
@@ -237,10 +239,13 @@ impl WebhookPool {
 /// # List registered webhooks
 #[openapi(tag = "webhook")]
 #[get("/webhooks")]
-pub async fn get_all_webhooks(webhook_pool: &State<WebhookPool>) -> Json<Vec<WebhookEndpoint>> {
+pub async fn get_all_webhooks(
+    _auth: AdminAuth,
+    webhook_pool: &TenantState<WebhookPool>,
+) -> Json<Vec<WebhookEndpoint>> {
     let webhooks: Vec<WebhookEndpoint> = {
         let mut webhooks = Vec::new();
-        let map = webhook_pool.webhooks.try_lock().unwrap();
+        let map = webhook_pool.webhooks.lock().await;
         for value in (*map).values() {
             let (webhook_endpoint, _webhook) = value;
             webhooks.push(webhook_endpoint.clone());
@@ -254,37 +259,48 @@ pub async fn get_all_webhooks(webhook_pool: &State<WebhookPool>) -> Json<Vec<Web
 #[openapi(tag = "webhook")]
 #[post("/webhook", data = "<request>")]
 pub async fn post_webhook(
-    webhook_pool: &State<WebhookPool>,
+    _auth: AdminAuth,
+    webhook_pool: &TenantState<WebhookPool>,
     request: Json<WebhookEndpoint>,
-) -> Json<WebhookEndpoint> {
+) -> Result<Json<WebhookEndpoint>, Error> {
     let webhook_endpoint = WebhookEndpoint::new_from(&request.into_inner());
+    let webhook_id = webhook_endpoint
+        .id
+        .clone()
+        .ok_or_else(|| Error::BadRequest("webhook without id".to_string()))?;
     let webhook_client =
         Box::new(Client::new(webhook_endpoint.url.to_string())) as Box<dyn Webhook>;
-    webhook_pool.webhooks.try_lock().unwrap().insert(
-        webhook_endpoint.id.as_ref().unwrap().to_string(),
+    webhook_pool.webhooks.lock().await.insert(
+        webhook_id,
         (
             webhook_endpoint.clone(),
             Arc::new(Mutex::new(webhook_client)),
         ),
     );
     webhook_pool
-        .post("webhook", &serde_json::to_value(&webhook_endpoint).unwrap())
-        .await
-        .unwrap();
-    Json(webhook_endpoint)
+        .post(
+            "webhook",
+            &serde_json::to_value(&webhook_endpoint).map_err(Error::internal)?,
+        )
+        .await?;
+    Ok(Json(webhook_endpoint))
 }
 
 /// # Delete a registered webhook
 #[openapi(tag = "webhook")]
 #[delete("/webhook/<webhook_id>")]
-pub async fn delete_webhook(webhook_pool: &State<WebhookPool>, webhook_id: String) -> Status {
+pub async fn delete_webhook(
+    _auth: AdminAuth,
+    webhook_pool: &TenantState<WebhookPool>,
+    webhook_id: String,
+) -> Result<Status, Error> {
     webhook_pool
         .webhooks
-        .try_lock()
-        .unwrap()
+        .lock()
+        .await
         .remove(&webhook_id)
-        .unwrap();
-    Status::Ok
+        .ok_or_else(|| Error::NotFound(format!("webhook {}", webhook_id)))?;
+    Ok(Status::Ok)
 }
 
 #[cfg(test)]